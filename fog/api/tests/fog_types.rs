@@ -306,6 +306,14 @@ fn test_key_image_result_code_enum_values() {
         fog_types::ledger::KeyImageResultCode::KeyImageError as u32,
         fog_api::ledger::KeyImageResultCode::KeyImageError as u32
     );
+    assert_eq!(
+        fog_types::ledger::KeyImageResultCode::ProvisionallySpent as u32,
+        fog_api::ledger::KeyImageResultCode::ProvisionallySpent as u32
+    );
+    assert_eq!(
+        fog_types::ledger::KeyImageResultCode::NotReady as u32,
+        fog_api::ledger::KeyImageResultCode::NotReady as u32
+    );
 }
 
 // Test that KexRngPubkey is a subset of its proto
@@ -147,6 +147,12 @@ pub enum KeyImageResultCode {
     NotSpent,
     /// Error occurred when getting key image
     KeyImageError,
+    /// The key image was spent in the block indicated by spent_at, but that
+    /// block is not yet final.
+    ProvisionallySpent,
+    /// The server has not ingested anything yet, so it cannot say whether
+    /// the key image was spent.
+    NotReady,
 }
 
 impl TryFrom<u32> for KeyImageResultCode {
@@ -158,6 +164,10 @@ impl TryFrom<u32> for KeyImageResultCode {
             Ok(KeyImageResultCode::NotSpent)
         } else if src == KeyImageResultCode::KeyImageError as u32 {
             Ok(KeyImageResultCode::KeyImageError)
+        } else if src == KeyImageResultCode::ProvisionallySpent as u32 {
+            Ok(KeyImageResultCode::ProvisionallySpent)
+        } else if src == KeyImageResultCode::NotReady as u32 {
+            Ok(KeyImageResultCode::NotReady)
         } else {
             Err(())
         }
@@ -63,7 +63,8 @@ impl FogKeyImageGrpcClient {
 pub trait KeyImageResultExtension {
     /// Check the status of a key image query. A `None` value indicates the key
     /// image has not been found. Some(spent_at) indicates the key image
-    /// appeared at block index `spent_at`.
+    /// appeared at block index `spent_at`, even if that block is not yet
+    /// final.
     fn status(&self) -> Result<Option<BlockIndex>, KeyImageQueryError>;
 }
 
@@ -71,12 +72,16 @@ impl KeyImageResultExtension for fog_types::ledger::KeyImageResult {
     /// Map the protobuf KeyImageResult type to a more idiomatic rust Result
     /// type
     fn status(&self) -> Result<Option<BlockIndex>, KeyImageQueryError> {
-        if self.key_image_result_code == KeyImageResultCode::Spent as u32 {
+        if self.key_image_result_code == KeyImageResultCode::Spent as u32
+            || self.key_image_result_code == KeyImageResultCode::ProvisionallySpent as u32
+        {
             Ok(Some(self.spent_at))
         } else if self.key_image_result_code == KeyImageResultCode::NotSpent as u32 {
             Ok(None)
         } else if self.key_image_result_code == KeyImageResultCode::KeyImageError as u32 {
             Err(KeyImageQueryError::KeyImageError)
+        } else if self.key_image_result_code == KeyImageResultCode::NotReady as u32 {
+            Err(KeyImageQueryError::NotReady)
         } else {
             Err(KeyImageQueryError::UnknownStatus(
                 self.key_image_result_code,
@@ -92,6 +97,8 @@ pub enum KeyImageQueryError {
     // FIXME: The server should at least seperate "invalid key image", "rate
     // limited", "database", from other error types
     KeyImageError,
+    /// The server has not ingested anything yet
+    NotReady,
     /// Unknown status code: {0}
     UnknownStatus(u32),
 }
@@ -28,6 +28,36 @@ pub enum Error {
 
     /// An panic occurred on another thread holding a lock
     Poison,
+
+    /// Add Records error: {0}
+    AddRecords(AddRecordsError),
+}
+
+/// An error when something goes wrong with adding a record
+#[derive(Clone, Debug, Deserialize, Display, PartialEq, PartialOrd, Serialize)]
+pub enum AddRecordsError {
+    /// Key was rejected
+    KeyRejected,
+    /// Map Overflowed: len = {0}, capacity = {1}
+    MapOverflow(u64, u64),
+    /// The store is frozen and read-only, unfreeze it to resume ingest
+    ReadOnly,
+    /// The store is degraded, writes are refused until it is cleared
+    Degraded,
+    /// The store reached its soft limit: len = {0}, limit = {1}
+    SoftLimitReached(u64, u64),
+    /// A key image appeared more than once in a batch, with different data
+    DuplicateInBatch,
+    /// Ingest is paused, resume it to accept records again
+    IngestPaused,
+    /// The store was built without the maintenance index this needs
+    MaintenanceIndexDisabled,
+}
+
+impl From<AddRecordsError> for Error {
+    fn from(src: AddRecordsError) -> Self {
+        Error::AddRecords(src)
+    }
 }
 
 impl<T> From<PoisonError<T>> for Error {
@@ -9,7 +9,10 @@ extern crate alloc;
 mod error;
 mod messages;
 
-pub use crate::{error::Error, messages::EnclaveCall};
+pub use crate::{
+    error::{AddRecordsError, Error},
+    messages::EnclaveCall,
+};
 
 use alloc::vec::Vec;
 use core::{hash::Hash, result::Result as StdResult};
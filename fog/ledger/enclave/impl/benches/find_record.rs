@@ -0,0 +1,51 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+use core::time::Duration;
+use criterion::{criterion_group, criterion_main, Criterion};
+use fog_ledger_enclave_impl::{KeyImageData, KeyImageStore};
+use mc_oblivious_traits::HeapORAMStorageCreator;
+use mc_transaction_core::ring_signature::KeyImage;
+
+fn make_store(desired_capacity: u64) -> KeyImageStore<HeapORAMStorageCreator> {
+    let logger = mc_common::logger::create_test_logger("find record bench".into());
+    let mut store = KeyImageStore::new(desired_capacity, logger);
+    for idx in 1..=1024u64 {
+        store
+            .add_record(
+                &KeyImage::from(idx),
+                &KeyImageData {
+                    block_index: idx,
+                    timestamp: idx,
+                },
+            )
+            .unwrap();
+    }
+    store
+}
+
+// Benchmark a single lookup through the find fast path
+pub fn find_one_record(criterion: &mut Criterion) {
+    let mut store = make_store(64 * 1024);
+    let key_image = KeyImage::from(17);
+
+    criterion.bench_function("key image store find 1 record", |b| {
+        b.iter(|| store.find(&key_image))
+    });
+}
+
+// Benchmark the same lookup routed through find_records as a batch of one
+pub fn find_one_record_as_batch(criterion: &mut Criterion) {
+    let mut store = make_store(64 * 1024);
+    let key_images = [KeyImage::from(17)];
+
+    criterion.bench_function("key image store find 1 record as batch", |b| {
+        b.iter(|| store.find_records(&key_images, 1))
+    });
+}
+
+criterion_group! {
+    name = find_record;
+    config = Criterion::default().measurement_time(Duration::new(10, 0));
+    targets = find_one_record, find_one_record_as_batch
+}
+criterion_main!(find_record);
@@ -0,0 +1,53 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Audit records which a KeyImageStore can emit for each operation, for
+//! operators who must log that queries occurred.
+//!
+//! An audit record never contains a key image, or anything derived from one.
+//! For lookups the outcome is always `Answered`, whether or not the key image
+//! was found, since a hit or a miss is exactly what a lookup must not reveal.
+
+use alloc::boxed::Box;
+use core::time::Duration;
+
+/// The kind of operation an audit record describes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AuditOp {
+    /// A single lookup
+    Lookup,
+    /// A batch of lookups
+    BatchLookup,
+    /// A lookup which also removed the record
+    Take,
+    /// A record added by ingest
+    Add,
+    /// A record removed by ingest
+    Remove,
+    /// A record corrected by ingest
+    Update,
+}
+
+/// The category of result of an operation
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AuditOutcome {
+    /// A lookup was answered
+    Answered,
+    /// A write was made
+    Written,
+    /// A write was refused
+    Refused,
+}
+
+/// Non-identifying metadata about one operation on a KeyImageStore
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AuditRecord {
+    /// When the operation completed, from the store's time source
+    pub timestamp: Duration,
+    /// The kind of operation
+    pub op: AuditOp,
+    /// The category of result
+    pub outcome: AuditOutcome,
+}
+
+/// A callback which receives an audit record for every operation
+pub type AuditSink = Box<dyn Fn(&AuditRecord) + Send + Sync>;
@@ -0,0 +1,92 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Clocks which supply wall-clock time to a KeyImageStore.
+//!
+//! The enclave has no clock of its own, so time must come from the host, and
+//! is untrusted. It is only used for operational reporting, like uptime and
+//! staleness, and never on the oblivious path. Abstracting it behind a trait
+//! lets tests drive time-dependent behavior deterministically.
+
+use alloc::sync::Arc;
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// A source of wall-clock time, as a duration since the Unix epoch
+pub trait Clock: Send + Sync {
+    /// The current time
+    fn now(&self) -> Duration;
+}
+
+/// A plain function can be used as a clock
+impl Clock for fn() -> Duration {
+    fn now(&self) -> Duration {
+        self()
+    }
+}
+
+/// The clock used when none is supplied: time stands still at the epoch
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct NoClock;
+
+impl Clock for NoClock {
+    fn now(&self) -> Duration {
+        Duration::default()
+    }
+}
+
+/// A clock which only moves when told to, for tests.
+///
+/// Clones share the same time, so a test can keep one clone and hand
+/// another to the store.
+#[derive(Clone, Debug, Default)]
+pub struct MockClock {
+    /// The current time, in nanoseconds since the epoch
+    nanos: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    /// Make a clock which starts at the given time
+    pub fn new(now: Duration) -> Self {
+        let clock = Self::default();
+        clock.set(now);
+        clock
+    }
+
+    /// Set the current time
+    pub fn set(&self, now: Duration) {
+        self.nanos.store(now.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    /// Move the current time forward
+    pub fn advance(&self, by: Duration) {
+        self.nanos.fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that clones of a mock clock share its time
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(Duration::from_secs(100));
+        let shared = clock.clone();
+        assert_eq!(shared.now(), Duration::from_secs(100));
+
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(shared.now(), Duration::from_millis(101_500));
+
+        shared.set(Duration::from_secs(5));
+        assert_eq!(clock.now(), Duration::from_secs(5));
+        assert_eq!(NoClock.now(), Duration::default());
+    }
+}
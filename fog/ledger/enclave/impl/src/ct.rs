@@ -0,0 +1,75 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Constant-time selection helpers.
+//!
+//! These wrap the `subtle` primitives used on the oblivious paths of the
+//! KeyImageStore, so that other enclave code can share them instead of
+//! reimplementing them. None of them branch on the condition, or on the
+//! values being selected.
+
+use aligned_cmov::subtle::{Choice, ConditionallySelectable};
+
+/// Select if_true if the condition is set, and if_false otherwise
+pub fn ct_select_u64(condition: Choice, if_true: u64, if_false: u64) -> u64 {
+    u64::conditional_select(&if_false, &if_true, condition)
+}
+
+/// Select if_true if the condition is set, and if_false otherwise
+pub fn ct_select_u32(condition: Choice, if_true: u32, if_false: u32) -> u32 {
+    u32::conditional_select(&if_false, &if_true, condition)
+}
+
+/// Copy src over dst if the condition is set, and leave dst alone otherwise.
+///
+/// Panics if the slices have different lengths. Every byte of dst is written
+/// either way.
+pub fn ct_copy_bytes(condition: Choice, dst: &mut [u8], src: &[u8]) {
+    assert_eq!(dst.len(), src.len(), "ct_copy_bytes length mismatch");
+    for (dst_byte, src_byte) in dst.iter_mut().zip(src.iter()) {
+        dst_byte.conditional_assign(src_byte, condition);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that the u64 and u32 selections pick the right value for both
+    // choice values, including the extremes
+    #[test]
+    fn test_ct_select() {
+        let pairs = [(0u64, u64::MAX), (u64::MAX, 0), (5, 7), (9, 9)];
+        for (a, b) in pairs.iter() {
+            assert_eq!(ct_select_u64(Choice::from(1), *a, *b), *a);
+            assert_eq!(ct_select_u64(Choice::from(0), *a, *b), *b);
+
+            let (a, b) = (*a as u32, *b as u32);
+            assert_eq!(ct_select_u32(Choice::from(1), a, b), a);
+            assert_eq!(ct_select_u32(Choice::from(0), a, b), b);
+        }
+    }
+
+    // Test that bytes are copied for a set choice and kept for an unset one
+    #[test]
+    fn test_ct_copy_bytes() {
+        let src = [0xffu8, 0x00, 0x5a, 0xa5];
+
+        let mut dst = [1u8, 2, 3, 4];
+        ct_copy_bytes(Choice::from(0), &mut dst, &src);
+        assert_eq!(dst, [1, 2, 3, 4]);
+
+        ct_copy_bytes(Choice::from(1), &mut dst, &src);
+        assert_eq!(dst, src);
+
+        let mut empty: [u8; 0] = [];
+        ct_copy_bytes(Choice::from(1), &mut empty, &[]);
+    }
+
+    // Test that mismatched lengths are refused
+    #[test]
+    #[should_panic(expected = "ct_copy_bytes length mismatch")]
+    fn test_ct_copy_bytes_length_mismatch() {
+        let mut dst = [0u8; 3];
+        ct_copy_bytes(Choice::from(1), &mut dst, &[1, 2]);
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Errors which can occur when operating a KeyImageStore
+
+use displaydoc::Display;
+use fog_ledger_enclave_api::AddRecordsError;
+use mc_sgx_compat::sync::PoisonError;
+use mc_util_serial::{decode::Error as DecodeError, encode::Error as EncodeError};
+
+/// The reason a KeyImageStore is currently refusing writes
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq)]
+pub enum WriteBlockReason {
+    /// The store is frozen
+    Frozen,
+    /// The store is marked degraded
+    Degraded,
+    /// Ingest is paused
+    IngestPaused,
+    /// The store holds {0} records, reaching its soft limit of {1}
+    SoftLimitReached(u64, u64),
+}
+
+impl From<WriteBlockReason> for AddRecordsError {
+    fn from(src: WriteBlockReason) -> Self {
+        match src {
+            WriteBlockReason::Frozen => AddRecordsError::ReadOnly,
+            WriteBlockReason::Degraded => AddRecordsError::Degraded,
+            WriteBlockReason::IngestPaused => AddRecordsError::IngestPaused,
+            WriteBlockReason::SoftLimitReached(len, limit) => {
+                AddRecordsError::SoftLimitReached(len, limit)
+            }
+        }
+    }
+}
+
+/// An error returned by KeyImageStore maintenance operations
+#[derive(Clone, Debug, Display, PartialEq)]
+pub enum KeyImageStoreError {
+    /// There was an error serializing or deserializing a snapshot
+    Serialization,
+    /// Snapshot delta base generation {0} does not match store generation {1}
+    SnapshotBaseMismatch(u64, u64),
+    /// Snapshot delta base generation {0} predates the tombstones kept, from
+    /// {1}
+    SnapshotBaseCompacted(u64, u64),
+    /// Expected a full snapshot but found a delta, or vice versa
+    WrongSnapshotKind,
+    /// The store was rebuilt or had records taken while a grow was pending
+    GrowInvalidated,
+    /// Desired capacity {0} is below the configured floor of {1}
+    CapacityBelowFloor(u64, u64),
+    /// The grow strategy's parameters are out of range
+    InvalidGrowStrategy,
+    /// The grow strategy picks no capacity above the current capacity of {0}
+    StrategyWouldNotGrow(u64),
+    /// The store was built without the maintenance index this needs
+    MaintenanceIndexDisabled,
+    /// A panic occurred on another thread while it held the store
+    Poison,
+    /// Add Records error: {0}
+    AddRecords(AddRecordsError),
+}
+
+impl From<AddRecordsError> for KeyImageStoreError {
+    fn from(src: AddRecordsError) -> Self {
+        KeyImageStoreError::AddRecords(src)
+    }
+}
+
+impl<T> From<PoisonError<T>> for KeyImageStoreError {
+    fn from(_src: PoisonError<T>) -> Self {
+        KeyImageStoreError::Poison
+    }
+}
+
+impl From<EncodeError> for KeyImageStoreError {
+    fn from(_src: EncodeError) -> Self {
+        KeyImageStoreError::Serialization
+    }
+}
+
+impl From<DecodeError> for KeyImageStoreError {
+    fn from(_src: DecodeError) -> Self {
+        KeyImageStoreError::Serialization
+    }
+}
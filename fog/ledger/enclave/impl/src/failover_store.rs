@@ -0,0 +1,163 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! A wrapper which serves reads from a primary store, failing over to a
+//! read replica, e.g. one restored from a snapshot of the primary, when the
+//! primary reports an error.
+
+use crate::{
+    ct::{ct_select_u32, ct_select_u64},
+    key_image_store::KeyImageData,
+    lookup::KeyImageLookup,
+};
+use aligned_cmov::subtle::ConstantTimeEq;
+use fog_ledger_enclave_api::KeyImageResultCode;
+use mc_transaction_core::ring_signature::KeyImage;
+
+/// Counters describing how often reads failed over to the replica
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FailoverMetrics {
+    /// The number of reads served
+    pub reads: u64,
+    /// The number of reads where the primary reported an error, and the
+    /// replica's answer was served instead
+    pub failovers: u64,
+}
+
+/// Routes reads to a primary store, falling back to a replica on error.
+///
+/// Every read is sent to both stores, and the answer is selected
+/// branchlessly, so the access pattern is the same whether or not the
+/// primary failed.
+pub struct FailoverStore<P: KeyImageLookup, R: KeyImageLookup> {
+    /// The store whose answers are served while it is healthy
+    primary: P,
+    /// The store whose answers are served when the primary errors
+    replica: R,
+    /// Failover counters
+    metrics: FailoverMetrics,
+}
+
+impl<P: KeyImageLookup, R: KeyImageLookup> FailoverStore<P, R> {
+    /// Pair a primary store with its read replica
+    pub fn new(primary: P, replica: R) -> Self {
+        Self {
+            primary,
+            replica,
+            metrics: Default::default(),
+        }
+    }
+
+    /// Get the failover counters
+    pub fn metrics(&self) -> &FailoverMetrics {
+        &self.metrics
+    }
+
+    /// Get the primary store
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    /// Get the replica store
+    pub fn replica(&self) -> &R {
+        &self.replica
+    }
+
+    /// Stop failing over, returning the primary and replica stores
+    pub fn into_inner(self) -> (P, R) {
+        (self.primary, self.replica)
+    }
+
+    /// Look up a key image in both stores, returning the primary's answer,
+    /// or the replica's if the primary's was KeyImageError.
+    ///
+    /// If both stores report an error, the result is KeyImageError.
+    pub fn find_record(&mut self, key_image: &KeyImage) -> (KeyImageData, u32) {
+        let (mut data, mut code) = self.primary.find_record(key_image);
+        let (replica_data, replica_code) = self.replica.find_record(key_image);
+
+        let failover = code.ct_eq(&(KeyImageResultCode::KeyImageError as u32));
+        data.block_index = ct_select_u64(failover, replica_data.block_index, data.block_index);
+        data.timestamp = ct_select_u64(failover, replica_data.timestamp, data.timestamp);
+        code = ct_select_u32(failover, replica_code, code);
+
+        self.metrics.reads += 1;
+        self.metrics.failovers += failover.unwrap_u8() as u64;
+        (data, code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_image_store::{KeyImageStore, KeyImageStoreBuilder};
+    use mc_common::logger::{test_with_logger, Logger};
+    use mc_oblivious_traits::HeapORAMStorageCreator;
+
+    type TestStore = KeyImageStore<HeapORAMStorageCreator>;
+
+    fn data(block_index: u64) -> KeyImageData {
+        KeyImageData {
+            block_index,
+            timestamp: block_index * 10,
+        }
+    }
+
+    /// A store which fails every lookup, once its fault is injected
+    struct FaultyStore {
+        store: TestStore,
+        failing: bool,
+    }
+
+    impl KeyImageLookup for FaultyStore {
+        fn find_record(&mut self, key_image: &KeyImage) -> (KeyImageData, u32) {
+            let result = self.store.find_record_unchecked(key_image);
+            if self.failing {
+                (Default::default(), KeyImageResultCode::KeyImageError as u32)
+            } else {
+                result
+            }
+        }
+    }
+
+    // Test that the replica serves the correct result when the primary
+    // errors, and that both stores are queried either way
+    #[test_with_logger]
+    fn test_failover(logger: Logger) {
+        let mut primary = KeyImageStoreBuilder::new(512, logger.clone())
+            .maintenance_index()
+            .build::<HeapORAMStorageCreator>();
+        for idx in 1..=10u64 {
+            primary
+                .add_record(&KeyImage::from(idx), &data(idx))
+                .unwrap();
+        }
+        let (snapshot, _) = primary.snapshot().unwrap();
+        let mut replica = KeyImageStoreBuilder::new(512, logger)
+            .maintenance_index()
+            .build::<HeapORAMStorageCreator>();
+        replica.restore(&snapshot).unwrap();
+
+        let mut store = FailoverStore::new(
+            FaultyStore {
+                store: primary,
+                failing: false,
+            },
+            replica,
+        );
+        let spent = KeyImageResultCode::Spent as u32;
+        let not_spent = KeyImageResultCode::NotSpent as u32;
+        assert_eq!(store.find_record(&KeyImage::from(3)), (data(3), spent));
+        assert_eq!(store.metrics().failovers, 0);
+
+        store.primary.failing = true;
+        assert_eq!(store.find_record(&KeyImage::from(3)), (data(3), spent));
+        assert_eq!(store.find_record(&KeyImage::from(11)).1, not_spent);
+        assert_eq!(store.metrics().failovers, 2);
+        assert_eq!(store.metrics().reads, 3);
+
+        // Every read went to both stores
+        let (primary, replica) = store.into_inner();
+        assert_eq!(primary.store.metrics().queries, 3);
+        assert_eq!(replica.metrics().queries, 3);
+    }
+}
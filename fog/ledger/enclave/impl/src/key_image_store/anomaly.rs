@@ -0,0 +1,142 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Detection of suspicious timestamps among ingested records.
+//!
+//! Block timestamps should never decrease as block indices increase, and
+//! should never be ahead of the current time. Records which break either rule
+//! point at ingest from a source with a skewed clock.
+
+use super::{KeyImageStore, StorageDataSize, StorageMetaSize};
+use crate::error::KeyImageStoreError;
+use alloc::vec::Vec;
+use core::ops::RangeBounds;
+use mc_oblivious_traits::ORAMStorageCreator;
+use mc_transaction_core::{ring_signature::KeyImage, BlockIndex};
+
+/// What is wrong with a record's timestamp
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimestampAnomalyKind {
+    /// The timestamp is earlier than that of a record in an earlier block,
+    /// which had the given timestamp
+    OutOfOrder(u64),
+    /// The timestamp is later than the store's clock, which read the given
+    /// time in seconds
+    Future(u64),
+}
+
+/// A record whose timestamp looks wrong
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimestampAnomaly {
+    /// The key image of the record
+    pub key_image: KeyImage,
+    /// The block index of the record
+    pub block_index: BlockIndex,
+    /// The timestamp of the record
+    pub timestamp: u64,
+    /// What is wrong with it
+    pub kind: TimestampAnomalyKind,
+}
+
+impl<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> KeyImageStore<OSC> {
+    /// Scan the records of a range of blocks, in block order, for timestamps
+    /// which are out of order, or in the future. Zero timestamps are skipped.
+    pub fn detect_timestamp_anomalies<R: RangeBounds<BlockIndex>>(
+        &mut self,
+        block_range: R,
+    ) -> Result<Vec<TimestampAnomaly>, KeyImageStoreError> {
+        self.require_maintenance_index()?;
+        let blocks = self.tracked_blocks(block_range);
+        let now = self.clock.now().as_secs();
+
+        let mut anomalies = Vec::new();
+        let mut latest_earlier: Option<u64> = None;
+        for (block_index, key_images) in blocks.iter() {
+            let mut latest_in_block = latest_earlier;
+            for key_image in key_images.iter() {
+                let timestamp = match self.read_tracked(key_image) {
+                    Some(value) => self.codec.decode(&value).timestamp,
+                    None => continue,
+                };
+                if timestamp == 0 {
+                    continue;
+                }
+
+                let mut report = |kind| {
+                    anomalies.push(TimestampAnomaly {
+                        key_image: *key_image,
+                        block_index: *block_index,
+                        timestamp,
+                        kind,
+                    })
+                };
+                if let Some(latest) = latest_earlier.filter(|latest| timestamp < *latest) {
+                    report(TimestampAnomalyKind::OutOfOrder(latest));
+                }
+                if now != 0 && timestamp > now {
+                    report(TimestampAnomalyKind::Future(now));
+                }
+                latest_in_block = latest_in_block.max(Some(timestamp));
+            }
+            latest_earlier = latest_in_block;
+        }
+        Ok(anomalies)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::MockClock,
+        key_image_store::{test_utils::data, KeyImageStoreBuilder},
+    };
+    use alloc::{boxed::Box, vec};
+    use core::time::Duration;
+    use mc_common::logger::{test_with_logger, Logger};
+    use mc_oblivious_traits::HeapORAMStorageCreator;
+
+    // Test that seeded out-of-order and future timestamps are detected, and
+    // that well-behaved records, and blocks outside the range, are not
+    #[test_with_logger]
+    fn test_detect_timestamp_anomalies(logger: Logger) {
+        let clock = MockClock::new(Duration::from_secs(1_000));
+        let mut store = KeyImageStoreBuilder::new(512, logger)
+            .maintenance_index()
+            .clock(Box::new(clock))
+            .build::<HeapORAMStorageCreator>();
+        store.add_record(&KeyImage::from(1), &data(1, 100)).unwrap();
+        store.add_record(&KeyImage::from(2), &data(2, 200)).unwrap();
+        // Skewed behind block 2
+        store.add_record(&KeyImage::from(3), &data(3, 150)).unwrap();
+        store.add_record(&KeyImage::from(4), &data(4, 300)).unwrap();
+        // Not known yet
+        store
+            .add_record_without_timestamp(&KeyImage::from(5), 5)
+            .unwrap();
+        // Skewed ahead of the clock
+        store
+            .add_record(&KeyImage::from(6), &data(6, 5_000))
+            .unwrap();
+
+        assert_eq!(
+            store.detect_timestamp_anomalies(..).unwrap(),
+            vec![
+                TimestampAnomaly {
+                    key_image: KeyImage::from(3),
+                    block_index: 3,
+                    timestamp: 150,
+                    kind: TimestampAnomalyKind::OutOfOrder(200),
+                },
+                TimestampAnomaly {
+                    key_image: KeyImage::from(6),
+                    block_index: 6,
+                    timestamp: 5_000,
+                    kind: TimestampAnomalyKind::Future(1_000),
+                },
+            ]
+        );
+
+        // Block 3 is only out of order relative to block 2
+        assert!(store.detect_timestamp_anomalies(3..=5).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,456 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Batches of operations.
+//!
+//! `add_records` adds a batch of records at once. A batch may name the same
+//! key image more than once. Copies with identical data are harmless and
+//! collapse to one record, but copies with different data are resolved
+//! explicitly, as chosen by the caller, rather than by whichever happens to
+//! be written last.
+//!
+//! `add_records_partial` is a variant for when the store may fill up partway
+//! through a batch: it writes what fits and reports where it stopped.
+//!
+//! `execute_batch` runs a mixed sequence of adds, lookups and removals in one
+//! call, for workloads which both ingest and serve.
+
+use super::{KeyImageData, KeyImageStore, StorageDataSize, StorageMetaSize};
+use alloc::{collections::BTreeMap, vec::Vec};
+use fog_ledger_enclave_api::AddRecordsError;
+use mc_oblivious_traits::ORAMStorageCreator;
+use mc_transaction_core::ring_signature::KeyImage;
+
+/// How `add_records` resolves a key image which appears more than once in a
+/// batch with different data
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DuplicateResolution {
+    /// Keep the data of the first copy in the batch
+    FirstWins,
+    /// Keep the data of the last copy in the batch
+    LastWins,
+    /// Refuse the whole batch with DuplicateInBatch
+    Reject,
+}
+
+/// One operation in a batch passed to `execute_batch`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StoreOp {
+    /// Record that a key image was spent, as `add_record` does
+    Add(KeyImage, KeyImageData),
+    /// Look up a key image, as `find_record` does
+    Find(KeyImage),
+    /// Remove the record for a key image, as `remove_record` does
+    Remove(KeyImage),
+}
+
+/// The result of one operation in a batch, of the same kind as the operation
+#[derive(Clone, Debug, PartialEq)]
+pub enum StoreOpResult {
+    /// The result of an Add
+    Added(Result<(), AddRecordsError>),
+    /// The data and KeyImageResultCode found by a Find
+    Found(KeyImageData, u32),
+    /// Whether a Remove found the key image present
+    Removed(Result<bool, AddRecordsError>),
+}
+
+/// How much of a batch `add_records_partial` admitted
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BatchAdmission {
+    /// The whole batch was written, this many records after duplicates
+    Complete(usize),
+    /// The store filled up before the batch was written, and must be grown
+    /// for the rest of it
+    NeedsGrow {
+        /// The number of records written
+        written: usize,
+        /// The indices in the batch of the records which survived duplicate
+        /// resolution but were not written, in batch order
+        remaining: Vec<usize>,
+    },
+}
+
+impl<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> KeyImageStore<OSC> {
+    /// Execute a sequence of operations in order, returning one result per
+    /// operation.
+    ///
+    /// Each operation sees the effects of those before it. A failed Add or
+    /// Remove does not stop the batch, its error is reported in its result.
+    pub fn execute_batch(&mut self, ops: &[StoreOp]) -> Vec<StoreOpResult> {
+        ops.iter()
+            .map(|op| match op {
+                StoreOp::Add(key_image, data) => {
+                    StoreOpResult::Added(self.add_record(key_image, data))
+                }
+                StoreOp::Find(key_image) => {
+                    let (data, result_code) = self.find_record_unchecked(key_image);
+                    StoreOpResult::Found(data, result_code)
+                }
+                StoreOp::Remove(key_image) => StoreOpResult::Removed(self.remove_record(key_image)),
+            })
+            .collect()
+    }
+
+    /// Record a batch of spent key images, returning the number of records
+    /// written. A rejected batch leaves the store unchanged, but if a write
+    /// fails, the records before it remain written.
+    pub fn add_records(
+        &mut self,
+        records: &[(KeyImage, KeyImageData)],
+        resolution: DuplicateResolution,
+    ) -> Result<usize, AddRecordsError> {
+        let indices = self.resolve_duplicates(records, resolution)?;
+        for idx in indices.iter() {
+            let (key_image, data) = &records[*idx];
+            self.add_record(key_image, data)?;
+        }
+        Ok(indices.len())
+    }
+
+    /// Record a batch of spent key images as `add_records` does, but if the
+    /// store fills up partway, stop there and report the records left to grow
+    /// the store and resubmit.
+    pub fn add_records_partial(
+        &mut self,
+        records: &[(KeyImage, KeyImageData)],
+        resolution: DuplicateResolution,
+    ) -> Result<BatchAdmission, AddRecordsError> {
+        let indices = self.resolve_duplicates(records, resolution)?;
+        for (written, idx) in indices.iter().enumerate() {
+            let (key_image, data) = &records[*idx];
+            match self.add_record(key_image, data) {
+                Ok(()) => {}
+                Err(AddRecordsError::MapOverflow(_, _))
+                | Err(AddRecordsError::SoftLimitReached(_, _)) => {
+                    return Ok(BatchAdmission::NeedsGrow {
+                        written,
+                        remaining: indices[written..].to_vec(),
+                    });
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(BatchAdmission::Complete(indices.len()))
+    }
+
+    /// The indices of the records of a batch which survive duplicate
+    /// resolution, in batch order
+    fn resolve_duplicates(
+        &self,
+        records: &[(KeyImage, KeyImageData)],
+        resolution: DuplicateResolution,
+    ) -> Result<Vec<usize>, AddRecordsError> {
+        let mut chosen: BTreeMap<[u8; 32], usize> = BTreeMap::new();
+        for (idx, (key_image, data)) in records.iter().enumerate() {
+            let canonical_key_image = (self.canonicalizer)(key_image);
+            let previous = match chosen.get(&canonical_key_image) {
+                Some(previous) => *previous,
+                None => {
+                    chosen.insert(canonical_key_image, idx);
+                    continue;
+                }
+            };
+            if records[previous].1 == *data {
+                continue;
+            }
+            match resolution {
+                DuplicateResolution::FirstWins => {}
+                DuplicateResolution::LastWins => {
+                    chosen.insert(canonical_key_image, idx);
+                }
+                DuplicateResolution::Reject => return Err(AddRecordsError::DuplicateInBatch),
+            }
+        }
+
+        let mut indices: Vec<usize> = chosen.values().cloned().collect();
+        indices.sort_unstable();
+        Ok(indices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_image_store::{
+        test_utils::{data, indexed_test_store, test_store, TestStore},
+        KeyImageStoreBuilder,
+    };
+    use alloc::vec;
+    use fog_ledger_enclave_api::KeyImageResultCode;
+    use mc_common::logger::{test_with_logger, Logger};
+
+    // A batch where key image 1 appears with conflicting data, and key image
+    // 2 appears twice with the same data
+    fn batch() -> Vec<(KeyImage, KeyImageData)> {
+        [(1, 10), (2, 20), (3, 30), (1, 11), (2, 20)]
+            .iter()
+            .map(|(idx, block_index)| (KeyImage::from(*idx), data(*block_index, *block_index * 10)))
+            .collect()
+    }
+
+    fn assert_block(store: &mut TestStore, idx: u64, block_index: u64) {
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(idx)),
+            (
+                data(block_index, block_index * 10),
+                KeyImageResultCode::Spent as u32
+            )
+        );
+    }
+
+    // Test that the first copy of a conflicting duplicate wins
+    #[test_with_logger]
+    fn test_first_wins(logger: Logger) {
+        let mut store = test_store(512, logger);
+        assert_eq!(
+            store
+                .add_records(&batch(), DuplicateResolution::FirstWins)
+                .unwrap(),
+            3
+        );
+        assert_eq!(store.len(), 3);
+        assert_block(&mut store, 1, 10);
+        assert_block(&mut store, 2, 20);
+        assert_block(&mut store, 3, 30);
+    }
+
+    // Test that the last copy of a conflicting duplicate wins
+    #[test_with_logger]
+    fn test_last_wins(logger: Logger) {
+        let mut store = test_store(512, logger);
+        assert_eq!(
+            store
+                .add_records(&batch(), DuplicateResolution::LastWins)
+                .unwrap(),
+            3
+        );
+        assert_eq!(store.len(), 3);
+        assert_block(&mut store, 1, 11);
+        assert_block(&mut store, 2, 20);
+        assert_block(&mut store, 3, 30);
+    }
+
+    // Test that a conflicting duplicate rejects the whole batch, while an
+    // identical one does not
+    #[test_with_logger]
+    fn test_reject(logger: Logger) {
+        let mut store = test_store(512, logger);
+        assert_eq!(
+            store.add_records(&batch(), DuplicateResolution::Reject),
+            Err(AddRecordsError::DuplicateInBatch)
+        );
+        assert!(store.is_empty());
+
+        let batch = batch();
+        let identical = &batch[1..];
+        assert_eq!(
+            store
+                .add_records(&identical[..2], DuplicateResolution::Reject)
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            store
+                .add_records(&[identical[0], identical[3]], DuplicateResolution::Reject)
+                .unwrap(),
+            1
+        );
+        assert_eq!(store.len(), 2);
+    }
+
+    // Test that a batch larger than the room left fills up to the soft
+    // limit, reports the records left, and completes once the store has room
+    #[test_with_logger]
+    fn test_add_records_partial(logger: Logger) {
+        let mut store: TestStore = KeyImageStoreBuilder::new(512, logger)
+            .soft_limit(10)
+            .build();
+        for idx in 1..=6u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+
+        // A duplicate of record 20 is dropped, so it is not among those left
+        let mut records: Vec<(KeyImage, KeyImageData)> = (20..30u64)
+            .map(|idx| (KeyImage::from(idx), data(idx, idx * 10)))
+            .collect();
+        records.insert(1, records[0]);
+        assert_eq!(
+            store
+                .add_records_partial(&records, DuplicateResolution::Reject)
+                .unwrap(),
+            BatchAdmission::NeedsGrow {
+                written: 4,
+                remaining: (5..11).collect(),
+            }
+        );
+        assert_eq!(store.len(), 10);
+        assert_block(&mut store, 23, 23);
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(24)).1,
+            KeyImageResultCode::NotSpent as u32
+        );
+
+        // Once there is room again, the rest of the batch goes in
+        for idx in 1..=6u64 {
+            store.remove_record(&KeyImage::from(idx)).unwrap();
+        }
+        assert_eq!(
+            store
+                .add_records_partial(&records[5..], DuplicateResolution::Reject)
+                .unwrap(),
+            BatchAdmission::Complete(6)
+        );
+        assert_eq!(store.len(), 10);
+        assert_block(&mut store, 29, 29);
+    }
+
+    // Test that the records left by a partial first-wins batch leave out the
+    // duplicates it dropped, on either side of where it stopped, so that
+    // resubmitting them cannot overwrite the first copies
+    #[test_with_logger]
+    fn test_add_records_partial_first_wins(logger: Logger) {
+        let mut store: TestStore = KeyImageStoreBuilder::new(512, logger).soft_limit(6).build();
+        for idx in 100..102u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+
+        // Key image 1 is written before the store fills up and repeated
+        // after, and key image 5 is first seen and repeated after
+        let records: Vec<(KeyImage, KeyImageData)> = [
+            (1, 1),
+            (2, 2),
+            (3, 3),
+            (4, 4),
+            (5, 5),
+            (1, 100),
+            (6, 6),
+            (5, 500),
+        ]
+        .iter()
+        .map(|(idx, block_index)| (KeyImage::from(*idx), data(*block_index, *block_index * 10)))
+        .collect();
+        let remaining = match store
+            .add_records_partial(&records, DuplicateResolution::FirstWins)
+            .unwrap()
+        {
+            BatchAdmission::NeedsGrow { written, remaining } => {
+                assert_eq!(written, 4);
+                remaining
+            }
+            admission => panic!("the batch should fill the store, got {:?}", admission),
+        };
+        assert_eq!(remaining, vec![4, 6]);
+
+        for idx in 100..102u64 {
+            store.remove_record(&KeyImage::from(idx)).unwrap();
+        }
+        let rest: Vec<(KeyImage, KeyImageData)> =
+            remaining.iter().map(|idx| records[*idx]).collect();
+        assert_eq!(
+            store
+                .add_records_partial(&rest, DuplicateResolution::FirstWins)
+                .unwrap(),
+            BatchAdmission::Complete(2)
+        );
+        assert_eq!(store.len(), 6);
+        assert_block(&mut store, 1, 1);
+        assert_block(&mut store, 5, 5);
+        assert_block(&mut store, 6, 6);
+    }
+
+    // Test that a batch which overflows the oblivious map stops at the
+    // overflowing record, reports the records from it on as left, and
+    // completes once the store has grown
+    #[test_with_logger]
+    fn test_add_records_partial_overflow(logger: Logger) {
+        let mut store = indexed_test_store(64, logger);
+        let records: Vec<(KeyImage, KeyImageData)> = (1..=store.capacity() * 4)
+            .map(|idx| (KeyImage::from(idx), data(idx, idx * 10)))
+            .collect();
+
+        let written = match store
+            .add_records_partial(&records, DuplicateResolution::Reject)
+            .unwrap()
+        {
+            BatchAdmission::NeedsGrow { written, remaining } => {
+                assert_eq!(remaining, (written..records.len()).collect::<Vec<_>>());
+                written
+            }
+            admission => panic!("the batch should overflow, got {:?}", admission),
+        };
+        assert!(written > 0 && written < records.len());
+        assert_eq!(store.len(), written as u64);
+        for (key_image, data) in records[..written].iter() {
+            assert_eq!(
+                store.find_record_unchecked(key_image),
+                (*data, KeyImageResultCode::Spent as u32)
+            );
+        }
+        for (key_image, _) in records[written..].iter() {
+            assert_eq!(
+                store.find_record_unchecked(key_image).1,
+                KeyImageResultCode::NotSpent as u32
+            );
+        }
+
+        store.grow(store.capacity() * 8).unwrap();
+        assert_eq!(
+            store
+                .add_records_partial(&records[written..], DuplicateResolution::Reject)
+                .unwrap(),
+            BatchAdmission::Complete(records.len() - written)
+        );
+        assert_eq!(store.len(), records.len() as u64);
+    }
+
+    // Test that an interleaved batch runs in order, with each operation
+    // seeing the effects of those before it
+    #[test_with_logger]
+    fn test_execute_batch(logger: Logger) {
+        let mut store = test_store(512, logger);
+        let one = KeyImage::from(1);
+        let two = KeyImage::from(2);
+        let results = store.execute_batch(&[
+            StoreOp::Find(one),
+            StoreOp::Add(one, data(5, 50)),
+            StoreOp::Find(one),
+            StoreOp::Add(two, data(6, 60)),
+            StoreOp::Remove(one),
+            StoreOp::Find(one),
+            StoreOp::Remove(one),
+            StoreOp::Find(two),
+        ]);
+
+        let not_spent = store.find_record_unchecked(&one);
+        assert_eq!(not_spent.1, KeyImageResultCode::NotSpent as u32);
+        assert_eq!(
+            results,
+            vec![
+                StoreOpResult::Found(not_spent.0, not_spent.1),
+                StoreOpResult::Added(Ok(())),
+                StoreOpResult::Found(data(5, 50), KeyImageResultCode::Spent as u32),
+                StoreOpResult::Added(Ok(())),
+                StoreOpResult::Removed(Ok(true)),
+                StoreOpResult::Found(not_spent.0, not_spent.1),
+                StoreOpResult::Removed(Ok(false)),
+                StoreOpResult::Found(data(6, 60), KeyImageResultCode::Spent as u32),
+            ]
+        );
+
+        store.freeze();
+        assert_eq!(
+            store.execute_batch(&[
+                StoreOp::Add(KeyImage::from(3), data(7, 70)),
+                StoreOp::Remove(two)
+            ]),
+            vec![
+                StoreOpResult::Added(Err(AddRecordsError::ReadOnly)),
+                StoreOpResult::Removed(Err(AddRecordsError::ReadOnly)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! A canary record, to detect a store being reset unexpectedly.
+//!
+//! The canary is stored under an omap key derived with its own domain tag, so
+//! no key image can look it up or overwrite it. It is written whenever the
+//! store builds a new map and survives everything else, so if it goes missing
+//! the map was replaced or emptied behind the store's back.
+
+use super::{KeyImageStore, KeySize, OMap, StorageDataSize, StorageMetaSize};
+use crate::value_codec::ValueSize;
+use aligned_cmov::A8Bytes;
+use alloc::vec::Vec;
+use digest::Digest;
+use fog_ledger_enclave_api::AddRecordsError;
+use mc_crypto_hashes::Blake2b256;
+use mc_oblivious_traits::{ORAMStorageCreator, ObliviousHashMap, OMAP_FOUND};
+
+// Domain separation tag used when deriving the canary's omap key
+const CANARY_DOMAIN_TAG: &[u8] = b"mc_fog_ledger_key_image_store_canary";
+
+// Every byte of the canary's value
+const CANARY_VALUE_BYTE: u8 = 0xc5;
+
+/// The outcome of checking a store's canary
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CanaryStatus {
+    /// The canary is present and intact
+    Intact,
+    /// The canary is missing or damaged, so the store was reset
+    Missing,
+    /// The store was built without a canary, so there is nothing to check
+    NotConfigured,
+}
+
+impl<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> KeyImageStore<OSC> {
+    /// Check that the canary record is still present and intact.
+    pub fn check_canary(&mut self) -> CanaryStatus {
+        if !self.canary {
+            return CanaryStatus::NotConfigured;
+        }
+        let mut value = A8Bytes::<ValueSize>::default();
+        let found = self
+            .omap
+            .read(&Self::canary_key(&self.hash_seed), &mut value)
+            == OMAP_FOUND;
+        if found && value.iter().all(|byte| *byte == CANARY_VALUE_BYTE) {
+            CanaryStatus::Intact
+        } else {
+            CanaryStatus::Missing
+        }
+    }
+
+    /// Remove every record from the store, including the canary, refused as
+    /// `remove_record` is. The canary is not written again until the map is
+    /// next rebuilt, so `check_canary` reports the reset.
+    pub fn clear(&mut self) -> Result<(), AddRecordsError> {
+        if let Some(reason) = self.why_removal_blocked() {
+            return Err(reason.into());
+        }
+        let key_images = self.tracked_key_images();
+        if !self.is_empty() || !key_images.is_empty() {
+            self.generation += 1;
+        }
+        self.omap = Self::create_omap(self.desired_capacity);
+        self.canary_in_map = false;
+        self.omap_epoch += 1;
+        for key_image in key_images.iter() {
+            self.untrack_key(key_image, self.generation);
+        }
+        self.provisional_keys.clear();
+        Ok(())
+    }
+
+    /// Write the canary record into a map keyed with the given hash seed
+    pub(super) fn write_canary(
+        omap: &mut OMap<OSC>,
+        hash_seed: &[u8; 32],
+    ) -> Result<(), AddRecordsError> {
+        let mut value = A8Bytes::<ValueSize>::default();
+        value.iter_mut().for_each(|byte| *byte = CANARY_VALUE_BYTE);
+        Self::write_value(omap, &Self::canary_key(hash_seed), &value)
+    }
+
+    /// The canary's omap key under a hash seed
+    fn canary_key(hash_seed: &[u8; 32]) -> A8Bytes<KeySize> {
+        let mut hasher = Blake2b256::new();
+        hasher.update(CANARY_DOMAIN_TAG);
+        hasher.update(hash_seed);
+        let mut key = A8Bytes::<KeySize>::default();
+        key.copy_from_slice(hasher.finalize().as_slice());
+        key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_image_store::{
+        test_utils::{data, test_store, TestStore},
+        KeyImageStoreBuilder,
+    };
+    use mc_common::logger::{test_with_logger, Logger};
+    use mc_transaction_core::ring_signature::KeyImage;
+
+    // Test that the canary survives normal operation, is not counted as a
+    // record, and is missing after the store is cleared
+    #[test_with_logger]
+    fn test_canary(logger: Logger) {
+        let mut store: TestStore = KeyImageStoreBuilder::new(512, logger.clone())
+            .maintenance_index()
+            .canary()
+            .build();
+        assert_eq!(store.check_canary(), CanaryStatus::Intact);
+        assert!(store.is_empty());
+
+        for idx in 1..=20u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        store.remove_record(&KeyImage::from(3)).unwrap();
+        store.take_record(&KeyImage::from(4)).unwrap();
+        store.find_records(&[KeyImage::from(5)], 4);
+        store.rehash([7u8; 32]).unwrap();
+        store.grow(1024).unwrap();
+        let (snapshot, _) = store.snapshot().unwrap();
+        store.restore(&snapshot).unwrap();
+        assert_eq!(store.check_canary(), CanaryStatus::Intact);
+        assert_eq!(store.len(), 18);
+
+        store.clear().unwrap();
+        assert_eq!(store.check_canary(), CanaryStatus::Missing);
+        assert!(store.is_empty());
+
+        // Restoring writes the canary again
+        store.restore(&snapshot).unwrap();
+        assert_eq!(store.check_canary(), CanaryStatus::Intact);
+        assert_eq!(store.len(), 18);
+
+        // A store without a canary says so, rather than reporting a reset
+        let mut plain = test_store(512, logger);
+        assert_eq!(plain.check_canary(), CanaryStatus::NotConfigured);
+    }
+}
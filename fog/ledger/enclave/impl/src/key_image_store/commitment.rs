@@ -0,0 +1,476 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Commitments to the contents of a KeyImageStore, and proofs against them.
+//!
+//! A commitment is the root of a Merkle tree over every record in the store,
+//! in key image order, so two stores holding the same records have the same
+//! commitment whatever their hash seeds, capacities or value codecs.
+//! Operators can publish one alongside a snapshot, e.g. in a signed manifest,
+//! and check a restored store against it.
+//!
+//! Because the leaves are sorted, a BatchProof can show a light client both
+//! that a key image is in the store, by revealing its leaf, and that it is
+//! not, by revealing the two adjacent leaves which bracket it. The leaves
+//! needed by a whole batch share one set of interior hashes, so the proof
+//! grows much more slowly than the batch.
+
+use super::{KeyImageData, KeyImageStore, StorageDataSize, StorageMetaSize};
+use crate::error::KeyImageStoreError;
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use digest::Digest;
+use fog_ledger_enclave_api::KeyImageResultCode;
+use mc_crypto_hashes::Blake2b256;
+use mc_oblivious_traits::ORAMStorageCreator;
+use mc_transaction_core::ring_signature::KeyImage;
+use serde::{Deserialize, Serialize};
+
+// Domain separation tags for the hashes making up a commitment
+const LEAF_DOMAIN_TAG: &[u8] = b"mc_fog_ledger_key_image_store_commitment_leaf";
+const NODE_DOMAIN_TAG: &[u8] = b"mc_fog_ledger_key_image_store_commitment_node";
+const ROOT_DOMAIN_TAG: &[u8] = b"mc_fog_ledger_key_image_store_commitment";
+
+/// One record of the store, as committed to
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CommittedRecord {
+    /// The key image bytes
+    pub key_image: [u8; 32],
+    /// The block index and timestamp of the record
+    pub data: KeyImageData,
+    /// Whether the record is provisional
+    pub provisional: bool,
+}
+
+impl CommittedRecord {
+    /// The Merkle leaf hash of this record
+    fn leaf_hash(&self) -> [u8; 32] {
+        let mut hasher = Blake2b256::new();
+        hasher.update(LEAF_DOMAIN_TAG);
+        hasher.update(&self.key_image);
+        hasher.update(&self.data.block_index.to_le_bytes());
+        hasher.update(&self.data.timestamp.to_le_bytes());
+        hasher.update(&[self.provisional as u8]);
+        finalize(hasher)
+    }
+
+    /// The result code a lookup of this record returns
+    fn result_code(&self) -> u32 {
+        if self.provisional {
+            KeyImageResultCode::ProvisionallySpent as u32
+        } else {
+            KeyImageResultCode::Spent as u32
+        }
+    }
+}
+
+/// A proof that the results of a batch of lookups agree with a store
+/// commitment.
+///
+/// It reveals the leaf of each key image which was found, and the adjacent
+/// leaves on either side of each key image which was not, together with the
+/// sibling hashes needed to recompute the commitment from those leaves.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct BatchProof {
+    /// The number of records in the store
+    num_records: u64,
+    /// The revealed leaves, with their positions, in increasing order
+    leaves: Vec<(u64, CommittedRecord)>,
+    /// The sibling hashes, in the order the verifier consumes them
+    siblings: Vec<[u8; 32]>,
+}
+
+impl BatchProof {
+    /// Check that `results` are the correct answers, under `commitment`, to
+    /// lookups of `key_images`.
+    ///
+    /// Results are checked as `find_records` returns them. The data of a key
+    /// image which was not found is not checked, only its result code.
+    pub fn verify(
+        &self,
+        commitment: &[u8; 32],
+        key_images: &[KeyImage],
+        results: &[(KeyImageData, u32)],
+    ) -> bool {
+        if key_images.len() != results.len() || !self.verify_leaves(commitment) {
+            return false;
+        }
+
+        key_images
+            .iter()
+            .zip(results.iter())
+            .all(|(key_image, result)| self.verify_result(key_image.as_ref(), result))
+    }
+
+    /// Check that the revealed leaves are well-formed and, with the sibling
+    /// hashes, recompute the commitment
+    fn verify_leaves(&self, commitment: &[u8; 32]) -> bool {
+        let is_ordered = self
+            .leaves
+            .windows(2)
+            .all(|pair| pair[0].0 < pair[1].0 && pair[0].1.key_image < pair[1].1.key_image);
+        if !is_ordered {
+            return false;
+        }
+
+        let mut known = BTreeMap::new();
+        for (position, record) in self.leaves.iter() {
+            if *position >= self.num_records {
+                return false;
+            }
+            known.insert(*position as usize, record.leaf_hash());
+        }
+
+        let mut siblings = self.siblings.iter();
+        let tree_root = fold_merkle_tree(self.num_records as usize, known, |_, _| {
+            siblings.next().copied()
+        });
+        match tree_root {
+            Some(tree_root) if siblings.next().is_none() => {
+                root_hash(self.num_records, &tree_root) == *commitment
+            }
+            _ => false,
+        }
+    }
+
+    /// Check one result against the revealed leaves
+    fn verify_result(&self, key_image: &[u8], result: &(KeyImageData, u32)) -> bool {
+        let split = match self
+            .leaves
+            .binary_search_by(|(_, record)| record.key_image[..].cmp(key_image))
+        {
+            Ok(idx) => {
+                let record = &self.leaves[idx].1;
+                return result.0 == record.data && result.1 == record.result_code();
+            }
+            Err(split) => split,
+        };
+
+        // Not found, so the nearest revealed leaves must be adjacent in the
+        // tree, with no leaf hidden between them
+        if result.1 != KeyImageResultCode::NotSpent as u32 {
+            return false;
+        }
+        let predecessor = split.checked_sub(1).map(|idx| self.leaves[idx].0);
+        let successor = self.leaves.get(split).map(|(position, _)| *position);
+        match (predecessor, successor) {
+            (Some(predecessor), Some(successor)) => successor == predecessor + 1,
+            (Some(predecessor), None) => predecessor + 1 == self.num_records,
+            (None, Some(successor)) => successor == 0,
+            (None, None) => self.num_records == 0,
+        }
+    }
+}
+
+impl<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> KeyImageStore<OSC> {
+    /// Compute a commitment to every record in the store: its key image, block
+    /// index, timestamp and whether it is provisional.
+    pub fn commitment(&mut self) -> Result<[u8; 32], KeyImageStoreError> {
+        let records = self.committed_records()?;
+        let leaves = records
+            .iter()
+            .map(CommittedRecord::leaf_hash)
+            .enumerate()
+            .collect();
+        let tree_root =
+            fold_merkle_tree(records.len(), leaves, |_, _| None).expect("every leaf is known");
+        Ok(root_hash(records.len() as u64, &tree_root))
+    }
+
+    /// Check that the store holds exactly the records committed to by
+    /// `expected`, e.g. after a restore. A mismatch means the store is
+    /// corrupt, or was restored from the wrong snapshot.
+    pub fn verify_against_commitment(
+        &mut self,
+        expected: [u8; 32],
+    ) -> Result<bool, KeyImageStoreError> {
+        Ok(self.commitment()? == expected)
+    }
+
+    /// Look up a batch of key images, returning their results with one proof,
+    /// against `commitment()`, covering the whole batch. Building the proof is
+    /// not oblivious: which leaves it reveals depends on the key images
+    /// queried.
+    pub fn find_batch_with_proof(
+        &mut self,
+        key_images: &[KeyImage],
+    ) -> Result<(Vec<(KeyImageData, u32)>, BatchProof), KeyImageStoreError> {
+        self.require_maintenance_index()?;
+        let results = self.find_records(key_images, key_images.len());
+
+        let records = self.committed_records()?;
+        let mut positions = Vec::new();
+        for key_image in key_images {
+            match records.binary_search_by(|record| record.key_image[..].cmp(key_image.as_ref())) {
+                Ok(position) => positions.push(position),
+                Err(split) => {
+                    if split > 0 {
+                        positions.push(split - 1);
+                    }
+                    if split < records.len() {
+                        positions.push(split);
+                    }
+                }
+            }
+        }
+        positions.sort_unstable();
+        positions.dedup();
+
+        let levels = merkle_levels(records.iter().map(CommittedRecord::leaf_hash).collect());
+        let known = positions
+            .iter()
+            .map(|position| (*position, levels[0][*position]))
+            .collect();
+        let mut siblings = Vec::new();
+        fold_merkle_tree(records.len(), known, |level, idx| {
+            siblings.push(levels[level][idx]);
+            Some(levels[level][idx])
+        })
+        .expect("every sibling is known");
+
+        let proof = BatchProof {
+            num_records: records.len() as u64,
+            leaves: positions
+                .into_iter()
+                .map(|position| (position as u64, records[position]))
+                .collect(),
+            siblings,
+        };
+        Ok((results, proof))
+    }
+
+    /// Read every record in the store, in key image order
+    fn committed_records(&mut self) -> Result<Vec<CommittedRecord>, KeyImageStoreError> {
+        self.require_maintenance_index()?;
+        let key_images = self.tracked_key_images();
+        let mut records: Vec<CommittedRecord> = key_images
+            .iter()
+            .filter_map(|key_image| {
+                self.read_tracked(key_image).map(|value| {
+                    let mut key_image_bytes = [0u8; 32];
+                    key_image_bytes.copy_from_slice(key_image.as_ref());
+                    CommittedRecord {
+                        key_image: key_image_bytes,
+                        data: self.codec.decode(&value),
+                        provisional: bool::from(self.codec.is_provisional(&value)),
+                    }
+                })
+            })
+            .collect();
+        records.sort_unstable_by(|a, b| a.key_image.cmp(&b.key_image));
+        Ok(records)
+    }
+}
+
+/// Hash the root of the tree over num_records leaves into a commitment
+fn root_hash(num_records: u64, tree_root: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Blake2b256::new();
+    hasher.update(ROOT_DOMAIN_TAG);
+    hasher.update(&num_records.to_le_bytes());
+    hasher.update(tree_root);
+    finalize(hasher)
+}
+
+/// Hash two sibling nodes into their parent
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Blake2b256::new();
+    hasher.update(NODE_DOMAIN_TAG);
+    hasher.update(left);
+    hasher.update(right);
+    finalize(hasher)
+}
+
+fn finalize(hasher: Blake2b256) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(hasher.finalize().as_slice());
+    hash
+}
+
+/// Every level of the tree over some leaves, from the leaves up to the root
+fn merkle_levels(leaves: Vec<[u8; 32]>) -> Vec<Vec<[u8; 32]>> {
+    let mut levels = vec![leaves];
+    while levels[levels.len() - 1].len() > 1 {
+        let level = &levels[levels.len() - 1];
+        let parents = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node_hash(left, right),
+                [only] => *only,
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(parents);
+    }
+    levels
+}
+
+/// Compute the root of the tree over num_leaves leaves, from the hashes of
+/// some of its nodes.
+///
+/// Starting from the known leaves, each level is folded into the next, an
+/// unpaired last node being promoted unchanged. A sibling which is not known
+/// is obtained from `sibling(level, index)`, which is called in the same
+/// order by the prover and the verifier. Returns None if a sibling is
+/// missing or a known node is out of range. The tree over no leaves has an
+/// all-zero root.
+fn fold_merkle_tree<F: FnMut(usize, usize) -> Option<[u8; 32]>>(
+    num_leaves: usize,
+    mut known: BTreeMap<usize, [u8; 32]>,
+    mut sibling: F,
+) -> Option<[u8; 32]> {
+    if num_leaves == 0 {
+        return if known.is_empty() {
+            Some([0u8; 32])
+        } else {
+            None
+        };
+    }
+
+    let mut level = 0;
+    let mut level_len = num_leaves;
+    while level_len > 1 {
+        let mut parents = BTreeMap::new();
+        for (idx, hash) in known.iter() {
+            let (idx, hash) = (*idx, *hash);
+            if idx >= level_len {
+                return None;
+            }
+            // The left child of a pair has already produced its parent
+            if parents.contains_key(&(idx / 2)) {
+                continue;
+            }
+            let sibling_idx = idx ^ 1;
+            let parent = if sibling_idx >= level_len {
+                hash
+            } else {
+                let sibling_hash = match known.get(&sibling_idx) {
+                    Some(sibling_hash) => *sibling_hash,
+                    None => sibling(level, sibling_idx)?,
+                };
+                if idx % 2 == 0 {
+                    node_hash(&hash, &sibling_hash)
+                } else {
+                    node_hash(&sibling_hash, &hash)
+                }
+            };
+            parents.insert(idx / 2, parent);
+        }
+        known = parents;
+        level_len = (level_len + 1) / 2;
+        level += 1;
+    }
+
+    match known.get(&0) {
+        Some(root) if known.len() == 1 => Some(*root),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        key_image_store::{
+            test_utils::{data, indexed_test_store, TestStore},
+            KeyImageStoreBuilder,
+        },
+        value_codec::{BlockIndexWidth, ValueCodec},
+    };
+    use mc_common::logger::{test_with_logger, Logger};
+
+    // Test that a restored store verifies against the commitment of the
+    // store it was snapshotted from, and not against another
+    #[test_with_logger]
+    fn test_verify_against_commitment(logger: Logger) {
+        let mut store = indexed_test_store(512, logger.clone());
+        for idx in 1..=20u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        store
+            .add_provisional_record(&KeyImage::from(21), &data(21, 210))
+            .unwrap();
+        let expected = store.commitment().unwrap();
+        let (snapshot, _) = store.snapshot().unwrap();
+
+        // A different seed, capacity and codec do not change the commitment
+        let mut restored: TestStore = KeyImageStoreBuilder::new(1024, logger)
+            .maintenance_index()
+            .value_codec(ValueCodec::new(BlockIndexWidth::U128))
+            .build();
+        restored.restore(&snapshot).unwrap();
+        assert!(restored.verify_against_commitment(expected).unwrap());
+
+        // Changing any record changes the commitment
+        let mut wrong = expected;
+        wrong[0] ^= 1;
+        assert!(!restored.verify_against_commitment(wrong).unwrap());
+
+        restored.finalize_block(21).unwrap();
+        assert!(!restored.verify_against_commitment(expected).unwrap());
+
+        store.finalize_block(21).unwrap();
+        assert!(restored
+            .verify_against_commitment(store.commitment().unwrap())
+            .unwrap());
+
+        store.remove_record(&KeyImage::from(5)).unwrap();
+        assert!(!restored
+            .verify_against_commitment(store.commitment().unwrap())
+            .unwrap());
+    }
+
+    // Test that a batch proof verifies every result of the batch, found and
+    // not found, against the commitment, and nothing else
+    #[test_with_logger]
+    fn test_find_batch_with_proof(logger: Logger) {
+        let mut store = indexed_test_store(512, logger.clone());
+        for idx in (2..=40u64).step_by(2) {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        store
+            .add_provisional_record(&KeyImage::from(41), &data(41, 410))
+            .unwrap();
+        let commitment = store.commitment().unwrap();
+
+        let key_images: Vec<KeyImage> = [1u64, 2, 3, 17, 18, 40, 41, 99]
+            .iter()
+            .map(|idx| KeyImage::from(*idx))
+            .collect();
+        let (results, proof) = store.find_batch_with_proof(&key_images).unwrap();
+        assert_eq!(results, store.find_records(&key_images, 0));
+        assert!(proof.verify(&commitment, &key_images, &results));
+
+        // The proof shares interior hashes across the batch
+        let (_, single) = store.find_batch_with_proof(&key_images[0..1]).unwrap();
+        assert!(proof.siblings.len() < single.siblings.len() * key_images.len());
+
+        // Claiming any result incorrectly fails verification
+        for idx in 0..results.len() {
+            let mut wrong = results.clone();
+            wrong[idx].1 = if wrong[idx].1 == KeyImageResultCode::NotSpent as u32 {
+                KeyImageResultCode::Spent as u32
+            } else {
+                KeyImageResultCode::NotSpent as u32
+            };
+            assert!(!proof.verify(&commitment, &key_images, &wrong));
+        }
+        let mut wrong = results.clone();
+        wrong[1].0.block_index += 1;
+        assert!(!proof.verify(&commitment, &key_images, &wrong));
+
+        // As does a different commitment, or a hidden leaf
+        store.add_record(&KeyImage::from(5), &data(5, 50)).unwrap();
+        assert!(!proof.verify(&store.commitment().unwrap(), &key_images, &results));
+        let mut hidden = proof.clone();
+        hidden.leaves.remove(0);
+        assert!(!hidden.verify(&commitment, &key_images, &results));
+
+        // An empty store proves that nothing is spent
+        let mut empty = indexed_test_store(512, logger);
+        let commitment = empty.commitment().unwrap();
+        let (results, proof) = empty.find_batch_with_proof(&key_images).unwrap();
+        assert!(proof.verify(&commitment, &key_images, &results));
+    }
+}
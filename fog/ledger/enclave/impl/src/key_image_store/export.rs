@@ -0,0 +1,112 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Exporting the records of a KeyImageStore in an interchange format, and
+//! importing them into another store.
+//!
+//! Unlike snapshots, exports carry only (KeyImage, KeyImageData) pairs, in an
+//! encoding chosen by the caller through RecordSerde. Provisional status is
+//! not carried, and imported records are final.
+
+use super::{KeyImageStore, StorageDataSize, StorageMetaSize};
+use crate::{error::KeyImageStoreError, record_serde::RecordSerde};
+use alloc::vec::Vec;
+use mc_oblivious_traits::ORAMStorageCreator;
+
+impl<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> KeyImageStore<OSC> {
+    /// Encode every record in the store, one buffer per record.
+    ///
+    /// The records are found through the maintenance index, so this fails
+    /// with MaintenanceIndexDisabled if the store was built without it.
+    pub fn export_records<S: RecordSerde>(
+        &mut self,
+        serde: &S,
+    ) -> Result<Vec<Vec<u8>>, KeyImageStoreError> {
+        self.require_maintenance_index()?;
+        let key_images = self.tracked_key_images();
+        let mut records = Vec::with_capacity(key_images.len());
+        for key_image in key_images.iter() {
+            if let Some(value) = self.read_tracked(key_image) {
+                records.push(serde.serialize(key_image, &self.codec.decode(&value))?);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Decode records exported by `export_records` and add them to the
+    /// store, returning the number added.
+    ///
+    /// Every record is decoded before any is added, so a malformed export
+    /// leaves the store unchanged.
+    pub fn import_records<S: RecordSerde>(
+        &mut self,
+        serde: &S,
+        records: &[Vec<u8>],
+    ) -> Result<usize, KeyImageStoreError> {
+        let decoded = records
+            .iter()
+            .map(|bytes| serde.deserialize(bytes))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (key_image, data) in decoded.iter() {
+            self.add_record(key_image, data)?;
+        }
+        Ok(decoded.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        key_image_store::test_utils::{data, indexed_test_store, test_store},
+        record_serde::{FixedRecordSerde, SerialRecordSerde},
+    };
+    use alloc::vec;
+    use fog_ledger_enclave_api::KeyImageResultCode;
+    use mc_common::logger::{test_with_logger, Logger};
+    use mc_transaction_core::ring_signature::KeyImage;
+
+    fn export_import<S: RecordSerde>(serde: &S, logger: Logger) {
+        let mut store = indexed_test_store(512, logger.clone());
+        for idx in 1..=20u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+
+        let records = store.export_records(serde).unwrap();
+        assert_eq!(records.len(), 20);
+
+        let mut imported = test_store(512, logger);
+        assert_eq!(imported.import_records(serde, &records).unwrap(), 20);
+        assert_eq!(imported.len(), 20);
+        for idx in 1..=20u64 {
+            assert_eq!(
+                imported.find_record_unchecked(&KeyImage::from(idx)),
+                (data(idx, idx * 10), KeyImageResultCode::Spent as u32)
+            );
+        }
+    }
+
+    // Test that records exported in each encoding import into a fresh store
+    #[test_with_logger]
+    fn test_export_import(logger: Logger) {
+        export_import(&SerialRecordSerde, logger.clone());
+        export_import(&FixedRecordSerde, logger);
+    }
+
+    // Test that a malformed export is rejected without changing the store
+    #[test_with_logger]
+    fn test_import_malformed(logger: Logger) {
+        let mut store = indexed_test_store(512, logger.clone());
+        store.add_record(&KeyImage::from(1), &data(1, 10)).unwrap();
+        let mut records = store.export_records(&FixedRecordSerde).unwrap();
+        records.push(vec![0u8; 3]);
+
+        let mut imported = test_store(512, logger);
+        assert_eq!(
+            imported.import_records(&FixedRecordSerde, &records),
+            Err(KeyImageStoreError::Serialization)
+        );
+        assert!(imported.is_empty());
+    }
+}
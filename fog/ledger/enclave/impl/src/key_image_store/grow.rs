@@ -0,0 +1,482 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Growing a KeyImageStore into a larger oblivious map.
+//!
+//! Growing is split in two, so that the store can keep serving lookups and
+//! writes while the larger map is built: `prepare_grow` copies every record
+//! into a new map, and `commit_grow` brings the new map up to date with any
+//! writes made since, then swaps it in.
+//!
+//! The swap is the only point at which lookups move from the old map to the
+//! new one. Every lookup before it is answered entirely by the old map, and
+//! every lookup after it entirely by the new map, which by then holds exactly
+//! the same records, so a lookup never observes a partially built store.
+//! `SharedStore` relies on this to serve lookups from other threads while it
+//! fills the new map.
+
+use super::{KeyImageStore, KeySize, OMap, StorageDataSize, StorageMetaSize};
+use crate::{error::KeyImageStoreError, overflow::OverflowInfo, value_codec::ValueSize};
+use aligned_cmov::A8Bytes;
+use alloc::{boxed::Box, vec::Vec};
+use mc_oblivious_traits::{ORAMStorageCreator, ObliviousHashMap};
+use mc_transaction_core::ring_signature::KeyImage;
+
+/// How a store picks its new desired capacity when it grows without being
+/// told one
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GrowStrategy {
+    /// Add a fixed number of records to the desired capacity
+    Increment(u64),
+    /// Double the desired capacity
+    Double,
+    /// Pick the desired capacity which the current records fill to the given
+    /// percentage, from 1 to 100, e.g. 50 for half full
+    TargetLoadFactor(u64),
+}
+
+impl GrowStrategy {
+    /// The new desired capacity for a store in the given state, or None if it
+    /// would not be larger than the current capacity.
+    pub fn next_capacity(&self, info: &OverflowInfo) -> Option<u64> {
+        let next_capacity = match *self {
+            GrowStrategy::Increment(increment) => info.desired_capacity.saturating_add(increment),
+            GrowStrategy::Double => info.desired_capacity.saturating_mul(2),
+            GrowStrategy::TargetLoadFactor(percent) => {
+                if !self.is_valid() {
+                    return None;
+                }
+                (info.len.saturating_mul(100) + percent - 1) / percent
+            }
+        };
+        if next_capacity > info.desired_capacity.max(info.capacity) {
+            Some(next_capacity)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the strategy's parameters are in range: a target load factor
+    /// must be from 1 to 100 percent
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            GrowStrategy::TargetLoadFactor(percent) => (1..=100).contains(&percent),
+            _ => true,
+        }
+    }
+}
+
+impl Default for GrowStrategy {
+    fn default() -> Self {
+        GrowStrategy::Double
+    }
+}
+
+/// A larger oblivious map which has been built for a store, but not yet
+/// swapped in
+pub struct PendingGrow<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> {
+    /// The new oblivious map
+    omap: Box<OMap<OSC>>,
+    /// The capacity the new map was created with
+    desired_capacity: u64,
+    /// The store generation the new map was built from
+    generation: u64,
+    /// The store's omap epoch when the new map was built
+    omap_epoch: u64,
+    /// Records read from the store which are not yet written to the new map
+    unfilled: Vec<(A8Bytes<KeySize>, A8Bytes<ValueSize>)>,
+}
+
+impl<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> PendingGrow<OSC> {
+    /// The capacity the new map was created with
+    pub fn desired_capacity(&self) -> u64 {
+        self.desired_capacity
+    }
+
+    /// Write the records read by `start_grow` into the new map. This does
+    /// not touch the store, so it can run without holding it.
+    pub(crate) fn fill(&mut self) -> Result<(), KeyImageStoreError> {
+        for (key, value) in self.unfilled.iter() {
+            KeyImageStore::<OSC>::write_value(&mut self.omap, key, value)?;
+        }
+        self.unfilled.clear();
+        Ok(())
+    }
+}
+
+impl<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> KeyImageStore<OSC> {
+    /// Move the store into a new oblivious map with the given capacity.
+    ///
+    /// Equivalent to `prepare_grow` followed immediately by `commit_grow`.
+    pub fn grow(&mut self, desired_capacity: u64) -> Result<(), KeyImageStoreError> {
+        let pending = self.prepare_grow(desired_capacity)?;
+        self.commit_grow(pending)
+    }
+
+    /// Grow the store to the desired capacity chosen by its grow strategy,
+    /// returning that capacity.
+    ///
+    /// Fails with StrategyWouldNotGrow, leaving the store unchanged, if the
+    /// strategy picks no capacity larger than the current one.
+    pub fn grow_by_strategy(&mut self) -> Result<u64, KeyImageStoreError> {
+        let desired_capacity = self
+            .next_grow_capacity()
+            .ok_or_else(|| KeyImageStoreError::StrategyWouldNotGrow(self.capacity()))?;
+        self.grow(desired_capacity)?;
+        Ok(desired_capacity)
+    }
+
+    /// The desired capacity the store's grow strategy would grow it to now,
+    /// or None if it would not grow it
+    pub fn next_grow_capacity(&self) -> Option<u64> {
+        let info = OverflowInfo {
+            len: self.len(),
+            capacity: self.capacity(),
+            desired_capacity: self.desired_capacity,
+        };
+        self.grow_strategy.next_capacity(&info)
+    }
+
+    /// Build a new oblivious map with the given capacity, holding every
+    /// record in the store. The store itself is not changed.
+    ///
+    /// The records are found through the maintenance index, so this fails
+    /// with MaintenanceIndexDisabled if the store was built without it.
+    pub fn prepare_grow(
+        &mut self,
+        desired_capacity: u64,
+    ) -> Result<PendingGrow<OSC>, KeyImageStoreError> {
+        let mut pending = self.start_grow(desired_capacity)?;
+        if let Err(err) = pending.fill() {
+            self.abandon_grow(pending);
+            return Err(err);
+        }
+        Ok(pending)
+    }
+
+    /// Create the new map for a grow and read out the records to fill it
+    /// with, leaving them to be written by `PendingGrow::fill`
+    pub(crate) fn start_grow(
+        &mut self,
+        desired_capacity: u64,
+    ) -> Result<PendingGrow<OSC>, KeyImageStoreError> {
+        self.require_maintenance_index()?;
+        let key_images = self.tracked_key_images();
+        let mut unfilled = Vec::with_capacity(key_images.len());
+        for key_image in key_images.iter() {
+            if let Some(value) = self.read_tracked(key_image) {
+                unfilled.push((self.omap_key(key_image), value));
+            }
+        }
+
+        self.pin_generation(self.generation);
+        Ok(PendingGrow {
+            omap: Self::create_omap(desired_capacity),
+            desired_capacity,
+            generation: self.generation,
+            omap_epoch: self.omap_epoch,
+            unfilled,
+        })
+    }
+
+    /// Drop a pending grow without committing it, so that the tombstones kept
+    /// for it can be dropped
+    pub fn abandon_grow(&mut self, pending: PendingGrow<OSC>) {
+        self.unpin_generation(pending.generation);
+    }
+
+    /// Replay the writes and removals made since `prepare_grow` into the new
+    /// map, and swap it in. Fails with GrowInvalidated, leaving the store
+    /// unchanged, if the store was rehashed, restored or had records taken.
+    pub fn commit_grow(&mut self, mut pending: PendingGrow<OSC>) -> Result<(), KeyImageStoreError> {
+        self.unpin_generation(pending.generation);
+        if pending.omap_epoch != self.omap_epoch {
+            return Err(KeyImageStoreError::GrowInvalidated);
+        }
+
+        pending.fill()?;
+        let mut omap = pending.omap;
+        let written: Vec<KeyImage> = self
+            .tracked_keys
+            .values()
+            .filter(|tracked| tracked.generation > pending.generation)
+            .map(|tracked| tracked.key_image)
+            .collect();
+        self.copy_records(&mut omap, &written)?;
+
+        let removed: Vec<KeyImage> = self
+            .removed_keys
+            .values()
+            .filter(|(_, generation)| *generation > pending.generation)
+            .map(|(key_image, _)| *key_image)
+            .collect();
+        for key_image in removed.iter() {
+            omap.remove(&self.omap_key(key_image));
+        }
+
+        if self.canary {
+            Self::write_canary(&mut omap, &self.hash_seed)?;
+        }
+        self.omap = omap;
+        self.canary_in_map = self.canary;
+        self.desired_capacity = pending.desired_capacity;
+        self.omap_epoch += 1;
+        Ok(())
+    }
+
+    /// Copy the current records of some tracked key images into another map
+    fn copy_records(
+        &mut self,
+        omap: &mut OMap<OSC>,
+        key_images: &[KeyImage],
+    ) -> Result<(), KeyImageStoreError> {
+        for key_image in key_images {
+            if let Some(value) = self.read_tracked(key_image) {
+                Self::write_value(omap, &self.omap_key(key_image), &value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        key_image_store::{
+            test_utils::{data, indexed_test_store, TestStore},
+            KeyImageStoreBuilder,
+        },
+        overflow::OverflowAction,
+        value_codec::{BlockIndexWidth, ValueCodec},
+    };
+    use fog_ledger_enclave_api::KeyImageResultCode;
+    use mc_common::logger::{test_with_logger, Logger};
+    use mc_oblivious_traits::HeapORAMStorageCreator;
+
+    fn assert_spent(store: &mut TestStore, idx: u64) {
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(idx)),
+            (data(idx, idx * 10), KeyImageResultCode::Spent as u32)
+        );
+    }
+
+    fn assert_not_spent(store: &mut TestStore, idx: u64) {
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(idx)).1,
+            KeyImageResultCode::NotSpent as u32
+        );
+    }
+
+    // Test that growing keeps every record and increases the capacity
+    #[test_with_logger]
+    fn test_grow(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+        for idx in 1..=100u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        let old_capacity = store.capacity();
+
+        store.grow(4096).unwrap();
+        assert!(store.capacity() > old_capacity);
+        assert_eq!(store.len(), 100);
+        for idx in 1..=100u64 {
+            assert_spent(&mut store, idx);
+        }
+    }
+
+    // Test that reads issued while a grow is pending always see a consistent
+    // store, before and after the swap, including writes made meanwhile
+    #[test_with_logger]
+    fn test_reads_during_grow(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+        for idx in 1..=50u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+
+        let pending = store.prepare_grow(4096).unwrap();
+        assert_eq!(pending.desired_capacity(), 4096);
+        for idx in 1..=50u64 {
+            assert_spent(&mut store, idx);
+        }
+
+        // Write and remove while the grow is pending
+        for idx in 51..=60u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        assert!(store.remove_record(&KeyImage::from(7)).unwrap());
+        assert!(store.remove_record(&KeyImage::from(55)).unwrap());
+        assert_spent(&mut store, 52);
+        assert_not_spent(&mut store, 7);
+
+        store.commit_grow(pending).unwrap();
+        assert_eq!(store.len(), 58);
+        for idx in (1..=60u64).filter(|idx| *idx != 7 && *idx != 55) {
+            assert_spent(&mut store, idx);
+        }
+        assert_not_spent(&mut store, 7);
+        assert_not_spent(&mut store, 55);
+        assert_not_spent(&mut store, 61);
+    }
+
+    // Test that a grow prepared before a codec migration, and committed after
+    // it finished, keeps the records the finish rewrote in the new encoding
+    #[test_with_logger]
+    fn test_grow_across_codec_migration(logger: Logger) {
+        let mut store = KeyImageStoreBuilder::new(512, logger)
+            .maintenance_index()
+            .value_codec(ValueCodec::new(BlockIndexWidth::U64))
+            .build::<HeapORAMStorageCreator>();
+        for idx in 1..=20u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+
+        let pending = store.prepare_grow(4096).unwrap();
+        store.begin_codec_migration(ValueCodec::new(BlockIndexWidth::U128), false);
+        assert_eq!(store.finish_codec_migration().unwrap(), 20);
+
+        store.commit_grow(pending).unwrap();
+        assert!(!store.is_migrating_codec());
+        assert_eq!(store.len(), 20);
+        for idx in 1..=20u64 {
+            assert_spent(&mut store, idx);
+        }
+    }
+
+    // Test that changes which cannot be replayed invalidate a pending grow,
+    // leaving the store unchanged
+    #[test_with_logger]
+    fn test_grow_invalidated(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+        for idx in 1..=10u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+
+        let pending = store.prepare_grow(4096).unwrap();
+        store.take_record(&KeyImage::from(3)).unwrap();
+        assert_eq!(
+            store.commit_grow(pending).err(),
+            Some(KeyImageStoreError::GrowInvalidated)
+        );
+
+        let pending = store.prepare_grow(4096).unwrap();
+        store.rehash([9u8; 32]).unwrap();
+        assert_eq!(
+            store.commit_grow(pending).err(),
+            Some(KeyImageStoreError::GrowInvalidated)
+        );
+
+        assert_eq!(store.len(), 9);
+        assert_not_spent(&mut store, 3);
+        assert_spent(&mut store, 4);
+    }
+
+    // Test that each grow strategy computes the expected desired capacity
+    #[test]
+    fn test_grow_strategy_next_capacity() {
+        let info = OverflowInfo {
+            len: 900,
+            capacity: 1024,
+            desired_capacity: 1000,
+        };
+        assert_eq!(
+            GrowStrategy::Increment(500).next_capacity(&info),
+            Some(1500)
+        );
+        assert_eq!(GrowStrategy::Double.next_capacity(&info), Some(2000));
+        assert_eq!(
+            GrowStrategy::TargetLoadFactor(50).next_capacity(&info),
+            Some(1800)
+        );
+        // Rounds up, so the load stays at or below the target
+        assert_eq!(
+            GrowStrategy::TargetLoadFactor(70).next_capacity(&info),
+            Some(1286)
+        );
+        // A target the records already meet would not grow the store, and
+        // neither would one within the real capacity
+        assert_eq!(
+            GrowStrategy::TargetLoadFactor(95).next_capacity(&info),
+            None
+        );
+        assert_eq!(GrowStrategy::Increment(10).next_capacity(&info), None);
+        // Out of range targets never grow it
+        assert_eq!(GrowStrategy::TargetLoadFactor(0).next_capacity(&info), None);
+        assert_eq!(
+            GrowStrategy::TargetLoadFactor(101).next_capacity(&info),
+            None
+        );
+        assert!(!GrowStrategy::TargetLoadFactor(0).is_valid());
+        assert!(!GrowStrategy::TargetLoadFactor(101).is_valid());
+        assert!(GrowStrategy::TargetLoadFactor(100).is_valid());
+        assert_eq!(GrowStrategy::default(), GrowStrategy::Double);
+    }
+
+    // Test that a store cannot be built with an out of range target load
+    // factor
+    #[test_with_logger]
+    fn test_invalid_grow_strategy(logger: Logger) {
+        for percent in [0u64, 101].iter() {
+            assert_eq!(
+                KeyImageStoreBuilder::new(512, logger.clone())
+                    .grow_strategy(GrowStrategy::TargetLoadFactor(*percent))
+                    .try_build::<HeapORAMStorageCreator>()
+                    .err(),
+                Some(KeyImageStoreError::InvalidGrowStrategy)
+            );
+        }
+    }
+
+    // Test that the store grows by its strategy, both when asked directly and
+    // when the overflow handler asks it to
+    #[test_with_logger]
+    fn test_grow_by_strategy(logger: Logger) {
+        let mut store = KeyImageStoreBuilder::new(512, logger.clone())
+            .maintenance_index()
+            .grow_strategy(GrowStrategy::Increment(512))
+            .build::<HeapORAMStorageCreator>();
+        for idx in 1..=10u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        assert_eq!(store.next_grow_capacity(), Some(1024));
+        assert_eq!(store.grow_by_strategy().unwrap(), 1024);
+        assert_eq!(store.next_grow_capacity(), Some(1536));
+        assert_eq!(store.len(), 10);
+        assert_spent(&mut store, 7);
+
+        let mut store = KeyImageStoreBuilder::new(64, logger)
+            .maintenance_index()
+            .grow_strategy(GrowStrategy::TargetLoadFactor(25))
+            .on_overflow(Box::new(|_: &OverflowInfo| OverflowAction::GrowByStrategy))
+            .build::<HeapORAMStorageCreator>();
+        // Already below the target load, so growing would not make room
+        assert_eq!(store.next_grow_capacity(), None);
+        assert_eq!(
+            store.grow_by_strategy().err(),
+            Some(KeyImageStoreError::StrategyWouldNotGrow(store.capacity()))
+        );
+        let initial_capacity = store.capacity();
+        let num_records = initial_capacity * 2;
+        for idx in 1..=num_records {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        assert!(store.capacity() > initial_capacity);
+        assert_eq!(store.len(), num_records);
+        for idx in 1..=num_records {
+            assert_spent(&mut store, idx);
+        }
+    }
+}
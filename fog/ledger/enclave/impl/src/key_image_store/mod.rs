@@ -0,0 +1,3842 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Object representing trusted storage for key image records.
+//! Mediates between the bytes used in ORAM and the key image / block index
+//! types, the various ORAM vs. fog api error codes, etc.
+
+mod anomaly;
+pub use anomaly::{TimestampAnomaly, TimestampAnomalyKind};
+
+mod batch;
+pub use batch::{BatchAdmission, DuplicateResolution, StoreOp, StoreOpResult};
+
+mod canary;
+pub use canary::CanaryStatus;
+
+mod commitment;
+pub use commitment::{BatchProof, CommittedRecord};
+
+mod export;
+
+mod grow;
+pub use grow::{GrowStrategy, PendingGrow};
+
+mod response;
+pub use response::{ResponseFormat, COMPACT_RESULT_SIZE};
+
+mod simulation;
+pub use simulation::{InsertPattern, SimReport};
+
+mod snapshot;
+pub use snapshot::SnapshotInfo;
+
+mod wallet;
+pub use wallet::WalletSpendSummary;
+
+#[cfg(test)]
+mod test_utils;
+
+use crate::{
+    audit::{AuditOp, AuditOutcome, AuditRecord, AuditSink},
+    clock::{Clock, NoClock},
+    ct::{ct_copy_bytes, ct_select_u32, ct_select_u64},
+    error::{KeyImageStoreError, WriteBlockReason},
+    metrics::{
+        AccessStats, BlockBucketMetrics, KeyImageStoreMetrics, LatencyByOutcome,
+        DEFAULT_BLOCK_BUCKET_SIZE,
+    },
+    overflow::{OverflowAction, OverflowHandler, OverflowInfo},
+    value_codec::{ValueCodec, ValueSize},
+};
+use aligned_cmov::{
+    subtle::{Choice, ConstantTimeEq, ConstantTimeGreater, CtOption},
+    typenum::{Unsigned, U1024, U32, U4096, U64},
+    A8Bytes,
+};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    vec,
+    vec::Vec,
+};
+use core::{ops::RangeBounds, time::Duration};
+use digest::Digest;
+use fog_ledger_enclave_api::{AddRecordsError, KeyImageResultCode};
+use mc_common::logger::{log, Logger};
+use mc_crypto_hashes::Blake2b256;
+use mc_crypto_rand::McRng;
+use mc_oblivious_map::CuckooHashTableCreator;
+use mc_oblivious_ram::PathORAM4096Z4Creator;
+use mc_oblivious_traits::{
+    OMapCreator, ORAMStorageCreator, ObliviousHashMap, OMAP_FOUND, OMAP_INVALID_KEY,
+    OMAP_NOT_FOUND, OMAP_OVERFLOW,
+};
+use mc_transaction_core::{ring_signature::KeyImage, BlockIndex};
+use rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+
+// internal constants
+// KeySize and ValueSize reflect the needs of key_image_store
+// We must choose an oblivious map algorithm that can support that
+// ValueSize is determined by the value codec
+type KeySize = U32;
+// BlockSize is a tuning parameter for OMap which must become the ValueSize of
+// the selected ORAM
+type BlockSize = U1024;
+
+// This selects an oblivious ram algorithm which can support queries of size
+// BlockSize The ORAMStorageCreator type is a generic parameter to KeyImageStore
+type ObliviousRAMAlgo<OSC> = PathORAM4096Z4Creator<McRng, OSC>;
+
+// These are the requirements on the storage, this is imposed by the choice of
+// oram algorithm
+
+/// The storage data size which OSC must be able to support
+pub type StorageDataSize = U4096;
+/// The storage meta size which OSC must be able to support
+pub type StorageMetaSize = U64;
+
+// This selects the stash size we will construct the oram with
+const STASH_SIZE: usize = 32;
+
+// This selects the oblivious map algorithm
+type ObliviousMapCreator<OSC> = CuckooHashTableCreator<BlockSize, McRng, ObliviousRAMAlgo<OSC>>;
+
+// The oblivious map type produced by the selected algorithm
+type OMap<OSC> = <ObliviousMapCreator<OSC> as OMapCreator<KeySize, ValueSize, McRng>>::Output;
+
+// The cuckoo hash table keeps one ORAM per hash function, and every read or
+// write accesses one bucket in each of them
+const ORAM_ACCESSES_PER_OMAP_OP: usize = 2;
+
+// The size of the timestamp in each record
+const TIMESTAMP_SIZE: usize = 8;
+
+// The most records which auto-prune removes after any one write, so that a
+// single write never stalls ingest for long
+const AUTO_PRUNE_BATCH: usize = 4;
+
+// However little time has passed, an overflow warning is logged again once
+// this many have been suppressed since the last one
+const OVERFLOW_LOG_EVERY: u64 = 1000;
+
+/// The default minimum interval between overflow warnings
+pub const DEFAULT_OVERFLOW_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+// Domain separation tag for configuration fingerprints
+const CONFIG_FINGERPRINT_DOMAIN_TAG: &[u8] = b"mc_fog_ledger_key_image_store_config";
+
+// Domain separation tag used when deriving omap keys from key images
+const KEY_DERIVATION_DOMAIN_TAG: &[u8] = b"mc_fog_ledger_key_image_store_omap_key";
+
+/// A source of wall-clock time as a plain function, which can be used as a
+/// Clock
+pub type TimeSource = fn() -> Duration;
+
+/// Maps a key image to the canonical bytes from which its omap key is
+/// derived.
+///
+/// Deployments which represent key images differently, e.g. with a version
+/// prefix, can supply one which strips the difference. Key images with the
+/// same canonical bytes are treated as the same key image.
+pub type Canonicalizer = fn(&KeyImage) -> [u8; 32];
+
+/// The canonical bytes of a key image, by which the maintenance index is
+/// keyed, so that key images which share an omap key are tracked once
+type CanonicalKeyImage = [u8; 32];
+
+/// The canonicalizer used when none is supplied: the compressed point bytes
+fn compressed_bytes(key_image: &KeyImage) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(key_image.as_ref());
+    bytes
+}
+
+/// The data recorded in the oblivious map for a spent key image
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct KeyImageData {
+    /// The index of the block in which the key image was spent
+    pub block_index: BlockIndex,
+    /// The timestamp of that block, in seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl ConstantTimeEq for KeyImageData {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        self.block_index.ct_eq(&other.block_index) & self.timestamp.ct_eq(&other.timestamp)
+    }
+}
+
+/// How the timestamp is chosen when a record overwrites an existing one
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimestampOverwritePolicy {
+    /// Use the timestamp of the new record
+    Replace,
+    /// Keep the timestamp of the existing record
+    Preserve,
+    /// Keep whichever timestamp is earlier
+    KeepEarliest,
+}
+
+impl Default for TimestampOverwritePolicy {
+    fn default() -> Self {
+        TimestampOverwritePolicy::Replace
+    }
+}
+
+/// What the store tracks about each key image written by ingest
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct TrackedKey {
+    /// The key image as it was last written, one of those with these
+    /// canonical bytes
+    key_image: KeyImage,
+    /// The generation at which the key image was last written
+    generation: u64,
+    /// The block index it was last written with
+    block_index: BlockIndex,
+}
+
+/// Object which holds ORAM and services key image requests
+///
+/// This object handles translations between key images and block data, and
+/// the aligned chunks of bytes Key and Value used in the oblivious map
+/// interface.
+///
+/// - Lookups return a `KeyImageResultCode` as a u32, so that it can be selected
+///   branchlessly.
+/// - The omap key is a hash of the canonicalized key image under a secret seed,
+///   which `rehash` changes.
+/// - The store is Send and Sync when its storage is. Operations take &mut self,
+///   so share it behind a lock, as SharedStore does.
+pub struct KeyImageStore<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> {
+    /// Oblivious map to hold hashed key image -> KeyImageData mapping
+    omap: Box<OMap<OSC>>,
+
+    /// The capacity the oblivious map was created with
+    desired_capacity: u64,
+
+    /// The seed used to derive omap keys from key images
+    hash_seed: [u8; 32],
+
+    /// Whether the seed was chosen by the operator, and so may be exposed
+    explicit_seed: bool,
+
+    /// Whether the key indexes below are kept. When they are not, each of
+    /// them stays empty.
+    maintenance_index: bool,
+
+    /// The key images which have been written to the oblivious map, by
+    /// canonical bytes, with when and in which block each was last written.
+    ///
+    /// This mirrors the live keys of the oblivious map, except that records
+    /// taken by clients stay here until `compact_key_index` drops them.
+    tracked_keys: BTreeMap<CanonicalKeyImage, TrackedKey>,
+
+    /// The canonical bytes of the tracked key images, by the block index they
+    /// were last written with
+    keys_by_block: BTreeMap<BlockIndex, BTreeSet<CanonicalKeyImage>>,
+
+    /// The key images which have been removed from the oblivious map, by
+    /// canonical bytes, with the generation at which each was removed.
+    ///
+    /// These tombstones are only kept while a snapshot or a pending grow
+    /// which may replay them is outstanding, so that removals by ingest, e.g.
+    /// by auto-pruning, do not grow the index without bound.
+    removed_keys: BTreeMap<CanonicalKeyImage, (KeyImage, u64)>,
+
+    /// The generations of the outstanding snapshots and pending grows, with
+    /// how many of each are outstanding
+    pinned_generations: BTreeMap<u64, usize>,
+
+    /// Deltas and grows based on a generation below this cannot be built,
+    /// since tombstones they would need have been dropped
+    tombstone_floor: u64,
+
+    /// The key images which were added as provisional, by block index. This
+    /// is pruned lazily, so it may contain key images which have since been
+    /// finalized, overwritten or removed.
+    provisional_keys: BTreeMap<BlockIndex, BTreeSet<KeyImage>>,
+
+    /// Counts the writes and removals made by ingest
+    generation: u64,
+
+    /// Whether the store has ingested records, by writing them or restoring
+    /// a snapshot of a store which had
+    ingested: bool,
+
+    /// Counts changes to the oblivious map which are not tracked by
+    /// generation: replacing it, re-keying it, or taking records from it
+    omap_epoch: u64,
+
+    /// The encoding of KeyImageData into omap values
+    codec: ValueCodec,
+
+    /// The value bytes which lookups return for a key image which is not found
+    miss_value: A8Bytes<ValueSize>,
+
+    /// How the timestamp is chosen when a record overwrites an existing one
+    timestamp_overwrite_policy: TimestampOverwritePolicy,
+
+    /// The encoding being migrated from, while a migration is in progress
+    migrate_from: Option<ValueCodec>,
+
+    /// Whether lookups rewrite the records they read in the new encoding,
+    /// while a migration is in progress
+    read_repair: bool,
+
+    /// The source of wall-clock time
+    clock: Box<dyn Clock>,
+
+    /// When a record was last written, or when the store was built if none
+    /// has been
+    last_write_at: Duration,
+
+    /// Maps key images to the bytes their omap keys are derived from
+    canonicalizer: Canonicalizer,
+
+    /// The time at which this store was constructed
+    created_at: Duration,
+
+    /// Operations slower than this are counted and logged
+    slow_operation_threshold: Option<Duration>,
+
+    /// Whether the store is frozen, refusing new records
+    frozen: bool,
+
+    /// Whether ingest is paused for maintenance, refusing new records
+    ingest_paused: bool,
+
+    /// Whether the store is marked degraded, refusing new records
+    degraded: bool,
+
+    /// The number of records after which new records are refused
+    soft_limit: Option<u64>,
+
+    /// The number of blocks in each bucket of the per-block metrics
+    block_bucket_size: u64,
+
+    /// Records more than this many blocks older than the newest are pruned
+    auto_prune_older_than_blocks: Option<u64>,
+
+    /// Whether a canary record is written whenever the map is (re)built
+    canary: bool,
+
+    /// Whether the canary record was written to the current map, and has not
+    /// been cleared. It is not counted as a record.
+    canary_in_map: bool,
+
+    /// Whether lookups answer NotReady until the store has ingested anything
+    strict_readiness: bool,
+
+    /// Operational metrics
+    metrics: KeyImageStoreMetrics,
+
+    /// Whether snapshots carry the metrics, so that restoring keeps them
+    snapshot_metrics: bool,
+
+    /// The number of padding lookups done by the last batch lookup
+    last_batch_padding: usize,
+
+    /// Receives an audit record for every operation
+    audit_sink: Option<AuditSink>,
+
+    /// Audit records accumulated since the log was last drained, if the log
+    /// is enabled
+    audit_log: Option<Vec<AuditRecord>>,
+
+    /// Decides what to do when a write overflows
+    overflow_handler: Option<OverflowHandler>,
+
+    /// Picks the new desired capacity for grows which are not given one
+    grow_strategy: GrowStrategy,
+
+    /// The minimum interval between overflow warnings
+    overflow_log_interval: Duration,
+
+    /// When an overflow warning was last logged, if one has been
+    last_overflow_log_at: Option<Duration>,
+
+    /// The number of overflow warnings suppressed since the last one logged
+    overflow_logs_suppressed: u64,
+
+    /// The logger object
+    logger: Logger,
+}
+
+/// Builder object which configures a KeyImageStore
+pub struct KeyImageStoreBuilder {
+    // Required
+    desired_capacity: u64,
+    logger: Logger,
+
+    // Optional, has sane defaults
+    capacity_floor: Option<u64>,
+    hash_seed: Option<[u8; 32]>,
+    clock: Box<dyn Clock>,
+    canonicalizer: Canonicalizer,
+    codec: ValueCodec,
+    miss_sentinel: KeyImageData,
+    timestamp_overwrite_policy: TimestampOverwritePolicy,
+    slow_operation_threshold: Option<Duration>,
+    soft_limit: Option<u64>,
+    block_bucket_size: u64,
+    auto_prune_older_than_blocks: Option<u64>,
+    maintenance_index: bool,
+    canary: bool,
+    strict_readiness: bool,
+    snapshot_metrics: bool,
+    audit_sink: Option<AuditSink>,
+    audit_log: bool,
+    overflow_handler: Option<OverflowHandler>,
+    grow_strategy: GrowStrategy,
+    overflow_log_interval: Duration,
+}
+
+impl KeyImageStoreBuilder {
+    /// Create a new builder for a store with the given desired capacity
+    pub fn new(desired_capacity: u64, logger: Logger) -> Self {
+        Self {
+            desired_capacity,
+            logger,
+            capacity_floor: None,
+            hash_seed: None,
+            clock: Box::new(NoClock),
+            canonicalizer: compressed_bytes,
+            codec: Default::default(),
+            miss_sentinel: Default::default(),
+            timestamp_overwrite_policy: Default::default(),
+            slow_operation_threshold: None,
+            soft_limit: None,
+            block_bucket_size: DEFAULT_BLOCK_BUCKET_SIZE,
+            auto_prune_older_than_blocks: None,
+            maintenance_index: false,
+            canary: false,
+            strict_readiness: false,
+            snapshot_metrics: false,
+            audit_sink: None,
+            audit_log: false,
+            overflow_handler: None,
+            grow_strategy: Default::default(),
+            overflow_log_interval: DEFAULT_OVERFLOW_LOG_INTERVAL,
+        }
+    }
+
+    /// Sets a minimum desired capacity. Building a store with a smaller one
+    /// fails, which catches a mistyped capacity before the store overflows
+    /// in production. By default there is no floor.
+    pub fn capacity_floor(self, capacity_floor: u64) -> Self {
+        let mut retval = self;
+        retval.capacity_floor = Some(capacity_floor);
+        retval
+    }
+
+    /// Sets the seed used to derive omap keys, instead of a random one, so that
+    /// key derivation can be replayed in a test harness. Never use this by
+    /// default in production: the seed reveals which slots key images map to.
+    pub fn hash_seed(self, hash_seed: [u8; 32]) -> Self {
+        let mut retval = self;
+        retval.hash_seed = Some(hash_seed);
+        retval
+    }
+
+    /// Sets the source of wall-clock time. By default the store has none,
+    /// and reports zero uptime.
+    pub fn clock(self, clock: Box<dyn Clock>) -> Self {
+        let mut retval = self;
+        retval.clock = clock;
+        retval
+    }
+
+    /// Sets a plain function as the source of wall-clock time
+    pub fn time_source(self, time_source: TimeSource) -> Self {
+        self.clock(Box::new(time_source))
+    }
+
+    /// Sets the canonicalization applied to key images before their omap keys
+    /// are derived, in both writes and lookups. By default the compressed
+    /// point bytes are used as they are.
+    pub fn canonicalizer(self, canonicalizer: Canonicalizer) -> Self {
+        let mut retval = self;
+        retval.canonicalizer = canonicalizer;
+        retval
+    }
+
+    /// Sets the encoding of KeyImageData into omap values
+    pub fn value_codec(self, codec: ValueCodec) -> Self {
+        let mut retval = self;
+        retval.codec = codec;
+        retval
+    }
+
+    /// Sets the data which lookups return for a key image which is not
+    /// found, e.g. all-ones for protocols which expect that rather than the
+    /// default all-zero data
+    pub fn miss_sentinel(self, miss_sentinel: KeyImageData) -> Self {
+        let mut retval = self;
+        retval.miss_sentinel = miss_sentinel;
+        retval
+    }
+
+    /// Sets how the timestamp is chosen when a record overwrites an existing
+    /// one. By default the new record's timestamp replaces it. The other
+    /// policies read the existing record before each write, at the cost of
+    /// an extra oblivious map access.
+    pub fn timestamp_overwrite_policy(self, policy: TimestampOverwritePolicy) -> Self {
+        let mut retval = self;
+        retval.timestamp_overwrite_policy = policy;
+        retval
+    }
+
+    /// Sets a latency threshold. Any single operation taking longer than this
+    /// is counted in the metrics and logged as a warning, since it indicates
+    /// ORAM performance degradation, e.g. a pathological stash state.
+    pub fn slow_operation_threshold(self, threshold: Duration) -> Self {
+        let mut retval = self;
+        retval.slow_operation_threshold = Some(threshold);
+        retval
+    }
+
+    /// Sets a soft limit on the number of records. Once the store holds this
+    /// many, new records are refused, leaving headroom below the hard
+    /// capacity of the oblivious map.
+    pub fn soft_limit(self, soft_limit: u64) -> Self {
+        let mut retval = self;
+        retval.soft_limit = Some(soft_limit);
+        retval
+    }
+
+    /// Sets the number of blocks in each bucket of the per-block metrics.
+    /// The default is DEFAULT_BLOCK_BUCKET_SIZE.
+    pub fn block_bucket_size(self, block_bucket_size: u64) -> Self {
+        assert!(block_bucket_size > 0, "block bucket size must be nonzero");
+        let mut retval = self;
+        retval.block_bucket_size = block_bucket_size;
+        retval
+    }
+
+    /// Sets a maximum record age, in blocks, beyond which each write also
+    /// removes a few of the oldest records. Needs the maintenance index.
+    pub fn auto_prune_older_than_blocks(self, max_age: u64) -> Self {
+        let mut retval = self;
+        retval.auto_prune_older_than_blocks = Some(max_age);
+        retval
+    }
+
+    /// Enables the maintenance index, a plaintext index of the key images
+    /// written by ingest, by key image and by block.
+    ///
+    /// Snapshots, grows, rehashing, exports, commitments, anomaly detection,
+    /// timestamp backfills, provisional records, finishing a codec migration
+    /// and auto-pruning all need it, since the oblivious map cannot be
+    /// walked without revealing its keys. These maintenance operations read
+    /// tracked records directly and are not oblivious, which is fine
+    /// because spent key images, block indices and timestamps are public
+    /// ledger data. The index costs enclave memory in proportion to the
+    /// number of records, so by default it is not kept, and those
+    /// operations fail with MaintenanceIndexDisabled.
+    pub fn maintenance_index(self) -> Self {
+        let mut retval = self;
+        retval.maintenance_index = true;
+        retval
+    }
+
+    /// Enables a canary record, written into the map when the store is built
+    /// and whenever its map is rebuilt, e.g. by restore. If `check_canary`
+    /// ever reports it missing, the store was reset unexpectedly.
+    pub fn canary(self) -> Self {
+        let mut retval = self;
+        retval.canary = true;
+        retval
+    }
+
+    /// Enables strict mode, in which every lookup answers NotReady until the
+    /// store has ingested something, whether by writing records or restoring
+    /// a snapshot of a store which had. This is for deployments where an
+    /// empty store is a configuration error, so that clients do not take an
+    /// unready store's misses for an authoritative NotSpent.
+    pub fn strict_readiness(self) -> Self {
+        let mut retval = self;
+        retval.strict_readiness = true;
+        retval
+    }
+
+    /// Includes the operational metrics in snapshots and deltas, so that a
+    /// store restored from them carries on counting where the snapshotted
+    /// store left off, instead of from zero. By default they are left out.
+    pub fn snapshot_metrics(self) -> Self {
+        let mut retval = self;
+        retval.snapshot_metrics = true;
+        retval
+    }
+
+    /// Sets a callback which receives a non-identifying audit record for
+    /// every lookup and write. By default no audit records are made.
+    pub fn audit_sink(self, audit_sink: AuditSink) -> Self {
+        let mut retval = self;
+        retval.audit_sink = Some(audit_sink);
+        retval
+    }
+
+    /// Enables an in-store audit log, which accumulates the same records an
+    /// audit sink receives until `drain_audit_log` is called. The log grows
+    /// without bound, so it must be drained periodically.
+    pub fn audit_log(self) -> Self {
+        let mut retval = self;
+        retval.audit_log = true;
+        retval
+    }
+
+    /// Sets a callback which decides what to do when a write overflows the
+    /// oblivious map: reject it, degrade the store, or grow the store and
+    /// retry. By default overflowing writes are rejected.
+    pub fn on_overflow(self, overflow_handler: OverflowHandler) -> Self {
+        let mut retval = self;
+        retval.overflow_handler = Some(overflow_handler);
+        retval
+    }
+
+    /// Sets how the store picks its new desired capacity in
+    /// `grow_by_strategy`, or when the overflow handler asks it to grow by
+    /// strategy. By default the desired capacity is doubled. Building fails
+    /// if a target load factor is not from 1 to 100 percent.
+    pub fn grow_strategy(self, grow_strategy: GrowStrategy) -> Self {
+        let mut retval = self;
+        retval.grow_strategy = grow_strategy;
+        retval
+    }
+
+    /// Sets the minimum interval, by the store's clock, between warnings
+    /// about overflowing writes, so that a store stuck at capacity does not
+    /// flood the log. Warnings in between are counted in the metrics, and
+    /// one is logged anyway after every 1000 suppressed. Defaults to
+    /// DEFAULT_OVERFLOW_LOG_INTERVAL.
+    pub fn overflow_log_interval(self, interval: Duration) -> Self {
+        let mut retval = self;
+        retval.overflow_log_interval = interval;
+        retval
+    }
+
+    /// Create the store.
+    ///
+    /// Panics if the configuration is invalid, see `try_build`.
+    pub fn build<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>>(
+        self,
+    ) -> KeyImageStore<OSC> {
+        self.try_build()
+            .expect("invalid KeyImageStore configuration")
+    }
+
+    /// Create the store, or fail with CapacityBelowFloor if the desired
+    /// capacity is below the capacity floor, InvalidGrowStrategy if the grow
+    /// strategy's parameters are out of range, or MaintenanceIndexDisabled if
+    /// auto-pruning is enabled without the maintenance index
+    pub fn try_build<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>>(
+        self,
+    ) -> Result<KeyImageStore<OSC>, KeyImageStoreError> {
+        match self.capacity_floor {
+            Some(floor) if self.desired_capacity < floor => {
+                return Err(KeyImageStoreError::CapacityBelowFloor(
+                    self.desired_capacity,
+                    floor,
+                ));
+            }
+            _ => {}
+        }
+        if !self.grow_strategy.is_valid() {
+            return Err(KeyImageStoreError::InvalidGrowStrategy);
+        }
+        if self.auto_prune_older_than_blocks.is_some() && !self.maintenance_index {
+            return Err(KeyImageStoreError::MaintenanceIndexDisabled);
+        }
+
+        let created_at = self.clock.now();
+        let hash_seed = self.hash_seed.unwrap_or_else(|| {
+            let mut hash_seed = [0u8; 32];
+            McRng::default().fill_bytes(&mut hash_seed);
+            hash_seed
+        });
+        let mut omap = KeyImageStore::<OSC>::create_omap(self.desired_capacity);
+        if self.canary {
+            KeyImageStore::<OSC>::write_canary(&mut omap, &hash_seed)
+                .expect("an empty map has room for the canary");
+        }
+        Ok(KeyImageStore {
+            omap,
+            desired_capacity: self.desired_capacity,
+            hash_seed,
+            explicit_seed: self.hash_seed.is_some(),
+            maintenance_index: self.maintenance_index,
+            tracked_keys: Default::default(),
+            keys_by_block: Default::default(),
+            removed_keys: Default::default(),
+            pinned_generations: Default::default(),
+            tombstone_floor: 0,
+            provisional_keys: Default::default(),
+            generation: 0,
+            ingested: false,
+            omap_epoch: 0,
+            codec: self.codec,
+            miss_value: self.codec.encode(&self.miss_sentinel),
+            timestamp_overwrite_policy: self.timestamp_overwrite_policy,
+            migrate_from: None,
+            read_repair: false,
+            clock: self.clock,
+            last_write_at: created_at,
+            canonicalizer: self.canonicalizer,
+            created_at,
+            slow_operation_threshold: self.slow_operation_threshold,
+            frozen: false,
+            ingest_paused: false,
+            degraded: false,
+            soft_limit: self.soft_limit,
+            block_bucket_size: self.block_bucket_size,
+            auto_prune_older_than_blocks: self.auto_prune_older_than_blocks,
+            canary: self.canary,
+            canary_in_map: self.canary,
+            strict_readiness: self.strict_readiness,
+            metrics: Default::default(),
+            snapshot_metrics: self.snapshot_metrics,
+            last_batch_padding: 0,
+            audit_sink: self.audit_sink,
+            audit_log: if self.audit_log {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            overflow_handler: self.overflow_handler,
+            grow_strategy: self.grow_strategy,
+            overflow_log_interval: self.overflow_log_interval,
+            last_overflow_log_at: None,
+            overflow_logs_suppressed: 0,
+            logger: self.logger,
+        })
+    }
+}
+
+impl<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> KeyImageStore<OSC> {
+    /// Make a new KeyImageStore with the default configuration
+    pub fn new(desired_capacity: u64, logger: Logger) -> Self {
+        KeyImageStoreBuilder::new(desired_capacity, logger).build()
+    }
+
+    /// Get the encoding of KeyImageData into omap values
+    pub fn value_codec(&self) -> &ValueCodec {
+        &self.codec
+    }
+
+    /// Get the time at which this store was constructed, as a duration since
+    /// the Unix epoch
+    pub fn created_at(&self) -> Duration {
+        self.created_at
+    }
+
+    /// Get how long this store has been live.
+    ///
+    /// This saturates at zero if the time source goes backwards.
+    pub fn uptime(&self) -> Duration {
+        self.clock
+            .now()
+            .checked_sub(self.created_at)
+            .unwrap_or_default()
+    }
+
+    /// Get when a record was last written, or when the store was built if
+    /// none has been
+    pub fn last_write_at(&self) -> Duration {
+        self.last_write_at
+    }
+
+    /// Whether no record has been written for longer than max_age, which
+    /// suggests that ingest has stalled
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        self.clock
+            .now()
+            .checked_sub(self.last_write_at)
+            .unwrap_or_default()
+            > max_age
+    }
+
+    /// The current hash seed, if the store was built with an explicit one, and
+    /// None otherwise, so that a random seed is never revealed.
+    pub fn seed(&self) -> Option<[u8; 32]> {
+        if self.explicit_seed {
+            Some(self.hash_seed)
+        } else {
+            None
+        }
+    }
+
+    /// A stable identifier for the parameters which determine the layout and
+    /// meaning of the stored data, for checking compatibility across
+    /// deployments. Operational settings, the secret hash seed and the
+    /// canonicalizer are left out.
+    pub fn config_fingerprint(&self) -> [u8; 32] {
+        let mut hasher = Blake2b256::new();
+        hasher.update(CONFIG_FINGERPRINT_DOMAIN_TAG);
+        for param in [
+            KeySize::U64,
+            ValueSize::U64,
+            BlockSize::U64,
+            StorageDataSize::U64,
+            StorageMetaSize::U64,
+            STASH_SIZE as u64,
+            self.desired_capacity,
+            self.codec.block_index_width().num_bytes() as u64,
+            self.timestamp_overwrite_policy as u64,
+            self.canary as u64,
+        ]
+        .iter()
+        {
+            hasher.update(&param.to_le_bytes());
+        }
+        // A presence byte, then the value if any, so that no value collides
+        // with auto-pruning being off
+        match self.auto_prune_older_than_blocks {
+            Some(max_age) => {
+                hasher.update(&[1u8]);
+                hasher.update(&max_age.to_le_bytes());
+            }
+            None => hasher.update(&[0u8]),
+        }
+        hasher.update(&self.miss_value);
+
+        let mut fingerprint = [0u8; 32];
+        fingerprint.copy_from_slice(hasher.finalize().as_slice());
+        fingerprint
+    }
+
+    /// The number of dummy lookups the last batch lookup did to reach its
+    /// pad_to, or zero if it needed none or there has been no batch.
+    pub fn last_batch_padding(&self) -> usize {
+        self.last_batch_padding
+    }
+
+    /// Get the operational metrics
+    pub fn metrics(&self) -> &KeyImageStoreMetrics {
+        &self.metrics
+    }
+
+    /// Get the aggregate access pattern statistics, none of which depend on
+    /// what was queried, so they can be handed to external auditors.
+    pub fn access_stats_report(&self) -> AccessStats {
+        self.metrics.access.clone()
+    }
+
+    /// Return every audit record accumulated since the last drain, and empty
+    /// the log. Returns nothing if the audit log is not enabled.
+    ///
+    /// Taking the records and emptying the log happen in one call, so no
+    /// record can be made in between and lost.
+    pub fn drain_audit_log(&mut self) -> Vec<AuditRecord> {
+        self.audit_log
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Get the counters per range of block indices, keyed by the first block
+    /// index of each range
+    pub fn metrics_by_block_bucket(&self) -> &BTreeMap<BlockIndex, BlockBucketMetrics> {
+        &self.metrics.by_block_bucket
+    }
+
+    /// Get operation latencies split by the category of their outcome. The hit
+    /// and miss counts must not be published outside the operator's control.
+    pub fn latency_by_outcome(&self) -> &LatencyByOutcome {
+        &self.metrics.latency_by_outcome
+    }
+
+    /// Get the largest latency observed for a single operation
+    pub fn max_observed_latency(&self) -> Duration {
+        self.metrics.latency.max()
+    }
+
+    /// Get the number of records in the oblivious map
+    pub fn len(&self) -> u64 {
+        self.omap.len() - self.canary_in_map as u64
+    }
+
+    /// Check if the oblivious map is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the real capacity of the underlying oblivious map
+    pub fn capacity(&self) -> u64 {
+        self.omap.capacity()
+    }
+
+    /// Freeze the store, so that it refuses new records until unfrozen.
+    /// Lookups are unaffected.
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    /// Unfreeze the store
+    pub fn unfreeze(&mut self) {
+        self.frozen = false;
+    }
+
+    /// Whether the store is frozen
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Pause ingest for the length of a maintenance operation, so that new
+    /// records are refused with IngestPaused until it is resumed.
+    pub fn pause_ingest(&mut self) {
+        self.ingest_paused = true;
+    }
+
+    /// Resume ingest after `pause_ingest`
+    pub fn resume_ingest(&mut self) {
+        self.ingest_paused = false;
+    }
+
+    /// Whether ingest is paused
+    pub fn is_ingest_paused(&self) -> bool {
+        self.ingest_paused
+    }
+
+    /// Mark the store degraded, e.g. because ingest found it inconsistent,
+    /// so that it refuses new records until the mark is cleared. Lookups are
+    /// unaffected.
+    pub fn mark_degraded(&mut self) {
+        self.degraded = true;
+    }
+
+    /// Clear the degraded mark
+    pub fn clear_degraded(&mut self) {
+        self.degraded = false;
+    }
+
+    /// Whether the store is marked degraded
+    pub fn is_degraded(&self) -> bool {
+        self.degraded
+    }
+
+    /// The reason the store is refusing new records, if it is.
+    ///
+    /// When several reasons apply, the one which needs operator action
+    /// soonest is reported: frozen, then degraded, then paused ingest, then
+    /// the soft limit.
+    pub fn why_write_blocked(&self) -> Option<WriteBlockReason> {
+        if self.frozen {
+            return Some(WriteBlockReason::Frozen);
+        }
+        if self.degraded {
+            return Some(WriteBlockReason::Degraded);
+        }
+        if self.ingest_paused {
+            return Some(WriteBlockReason::IngestPaused);
+        }
+        match self.soft_limit {
+            Some(limit) if self.len() >= limit => {
+                Some(WriteBlockReason::SoftLimitReached(self.len(), limit))
+            }
+            _ => None,
+        }
+    }
+
+    /// The reason the store is refusing removals, if it is. This is
+    /// `why_write_blocked` without the soft limit, since removals make room.
+    fn why_removal_blocked(&self) -> Option<WriteBlockReason> {
+        match self.why_write_blocked() {
+            Some(WriteBlockReason::SoftLimitReached(_, _)) => None,
+            reason => reason,
+        }
+    }
+
+    /// Estimate how many bytes would be saved by storing each block's timestamp
+    /// once, in a per-block table, rather than in every record.
+    pub fn estimate_timestamp_dedup_savings(&self) -> Result<u64, KeyImageStoreError> {
+        self.require_maintenance_index()?;
+        let num_records = self.tracked_keys.len() as u64;
+        let num_blocks = self
+            .tracked_keys
+            .values()
+            .map(|tracked| tracked.block_index)
+            .collect::<BTreeSet<_>>()
+            .len() as u64;
+        let table_entry_size = (self.codec.block_index_width().num_bytes() + TIMESTAMP_SIZE) as u64;
+        Ok((num_records * TIMESTAMP_SIZE as u64).saturating_sub(num_blocks * table_entry_size))
+    }
+
+    /// The number of ORAM accesses made by a single `find` or `add_record`,
+    /// which is the unit of cost for the store.
+    pub fn oram_accesses_per_op(&self) -> usize {
+        ORAM_ACCESSES_PER_OMAP_OP
+    }
+
+    /// Record that a key image was spent in a given block
+    pub fn add_record(
+        &mut self,
+        key_image: &KeyImage,
+        data: &KeyImageData,
+    ) -> Result<(), AddRecordsError> {
+        self.write_record(key_image, data, false)
+    }
+
+    /// Record that a key image was spent in a block which is not yet final, so
+    /// that it reads as provisionally spent until `finalize_block`.
+    pub fn add_provisional_record(
+        &mut self,
+        key_image: &KeyImage,
+        data: &KeyImageData,
+    ) -> Result<(), AddRecordsError> {
+        if !self.maintenance_index {
+            return Err(AddRecordsError::MaintenanceIndexDisabled);
+        }
+        self.write_record(key_image, data, true)?;
+        self.track_provisional(key_image, data.block_index);
+        Ok(())
+    }
+
+    /// Record that a key image was spent in a given block, with a zero
+    /// timestamp until `backfill_timestamps` is called for the block.
+    pub fn add_record_without_timestamp(
+        &mut self,
+        key_image: &KeyImage,
+        block_index: BlockIndex,
+    ) -> Result<(), AddRecordsError> {
+        let data = KeyImageData {
+            block_index,
+            timestamp: 0,
+        };
+        self.add_record(key_image, &data)
+    }
+
+    /// Set the timestamp of every record in a block, e.g. once the block's
+    /// timestamp arrives after its key images were ingested. Returns the number
+    /// of records updated.
+    pub fn backfill_timestamps(
+        &mut self,
+        block_index: BlockIndex,
+        timestamp: u64,
+    ) -> Result<usize, AddRecordsError> {
+        if !self.maintenance_index {
+            return Err(AddRecordsError::MaintenanceIndexDisabled);
+        }
+        let key_images: Vec<KeyImage> = match self.tracked_blocks(block_index..=block_index).pop() {
+            Some((_, key_images)) => key_images,
+            None => return Ok(0),
+        };
+
+        let mut num_updated = 0;
+        for key_image in key_images.iter() {
+            let mut value = match self.read_tracked(key_image) {
+                Some(value) => value,
+                None => continue,
+            };
+            self.codec.set_timestamp(&mut value, timestamp);
+            let key = self.omap_key(key_image);
+            Self::write_value(&mut self.omap, &key, &value)?;
+            self.generation += 1;
+            self.track_key(key_image, block_index, self.generation);
+            num_updated += 1;
+        }
+        Ok(num_updated)
+    }
+
+    /// Mark every provisional record of a block as final, returning the number
+    /// of records which were finalized.
+    pub fn finalize_block(&mut self, block_index: BlockIndex) -> Result<usize, AddRecordsError> {
+        if !self.maintenance_index {
+            return Err(AddRecordsError::MaintenanceIndexDisabled);
+        }
+        let key_images = match self.provisional_keys.remove(&block_index) {
+            Some(key_images) => key_images,
+            None => return Ok(0),
+        };
+
+        let mut num_finalized = 0;
+        for key_image in key_images.iter() {
+            let mut value = match self.read_tracked(key_image) {
+                Some(value) => value,
+                None => continue,
+            };
+            // Skip records which were finalized or rewritten in another block
+            // since they were added
+            if !bool::from(self.codec.is_provisional(&value))
+                || self.codec.decode(&value).block_index != block_index
+            {
+                continue;
+            }
+
+            self.codec.set_provisional(&mut value, false);
+            let key = self.omap_key(key_image);
+            Self::write_value(&mut self.omap, &key, &value)?;
+            self.generation += 1;
+            self.track_key(key_image, block_index, self.generation);
+            num_finalized += 1;
+        }
+        Ok(num_finalized)
+    }
+
+    /// Remove the record for a key image, e.g. to correct ingest, returning
+    /// true if it was present. Refused as `add_record` is, except at the soft
+    /// limit.
+    pub fn remove_record(&mut self, key_image: &KeyImage) -> Result<bool, AddRecordsError> {
+        if let Some(reason) = self.why_removal_blocked() {
+            self.audit(AuditOp::Remove, AuditOutcome::Refused);
+            return Err(reason.into());
+        }
+        Ok(self.remove_unchecked(key_image))
+    }
+
+    /// Remove the record for a key image without checking whether removals
+    /// are blocked, returning true if it was present
+    fn remove_unchecked(&mut self, key_image: &KeyImage) -> bool {
+        let key = self.omap_key(key_image);
+        let present = self.omap.remove(&key) == OMAP_FOUND;
+
+        if present {
+            self.generation += 1;
+            self.untrack_key(key_image, self.generation);
+        }
+        self.audit(AuditOp::Remove, AuditOutcome::Written);
+        present
+    }
+
+    /// Drop index entries, including provisional entries and tombstones, which
+    /// no longer match the live records, e.g. records taken by `take_record`.
+    /// Returns the number of key images dropped from the index.
+    pub fn compact_key_index(&mut self) -> usize {
+        let key_images = self.tracked_key_images();
+        let mut provisional_keys: BTreeMap<BlockIndex, BTreeSet<KeyImage>> = Default::default();
+        let mut num_dropped = 0;
+        for key_image in key_images.iter() {
+            match self.read_tracked(key_image) {
+                Some(value) => {
+                    if bool::from(self.codec.is_provisional(&value)) {
+                        provisional_keys
+                            .entry(self.codec.decode(&value).block_index)
+                            .or_default()
+                            .insert(*key_image);
+                    }
+                }
+                None => {
+                    let canonical = (self.canonicalizer)(key_image);
+                    if let Some(previous) = self.tracked_keys.remove(&canonical) {
+                        self.remove_from_block(&canonical, previous.block_index);
+                    }
+                    num_dropped += 1;
+                }
+            }
+        }
+        self.provisional_keys = provisional_keys;
+        self.drop_tombstones();
+        num_dropped
+    }
+
+    /// Move the record for a key image to a different block, e.g. when a reorg
+    /// moves its spend. Returns true if the key image was present.
+    pub fn update_block_index(
+        &mut self,
+        key_image: &KeyImage,
+        new_block_index: BlockIndex,
+    ) -> Result<bool, AddRecordsError> {
+        if let Some(reason) = self.why_write_blocked() {
+            self.audit(AuditOp::Update, AuditOutcome::Refused);
+            return Err(reason.into());
+        }
+
+        let key = self.omap_key(key_image);
+        let (codec, migrate_from) = (self.codec, self.migrate_from);
+        let mut provisional = Choice::from(0);
+        let oram_result_code = self.omap.access(&key, |code, buffer| {
+            let found = code.ct_eq(&OMAP_FOUND);
+            let mut updated = buffer.clone();
+            if let Some(from) = migrate_from.as_ref() {
+                codec.migrate(from, &mut updated);
+            }
+            codec.set_block_index(&mut updated, new_block_index);
+            ct_copy_bytes(found, buffer, &updated);
+            provisional = found & codec.is_provisional(&updated);
+        });
+        let present = oram_result_code == OMAP_FOUND;
+
+        // Tracking is ingest-side, and only follows the records which exist
+        if present {
+            self.generation += 1;
+            self.track_key(key_image, new_block_index, self.generation);
+            if bool::from(provisional) {
+                self.track_provisional(key_image, new_block_index);
+            }
+        }
+        self.audit(AuditOp::Update, AuditOutcome::Written);
+        Ok(present)
+    }
+
+    /// Start migrating the store's records, in place, to a new value encoding,
+    /// which every write uses from now on. With read_repair, lookups also
+    /// rewrite the records they read. Panics if a migration is in progress.
+    pub fn begin_codec_migration(&mut self, codec: ValueCodec, read_repair: bool) {
+        assert!(
+            self.migrate_from.is_none(),
+            "a value encoding migration is already in progress"
+        );
+        self.miss_value = codec.encode(&self.codec.decode(&self.miss_value));
+        self.migrate_from = Some(self.codec);
+        self.codec = codec;
+        self.read_repair = read_repair;
+    }
+
+    /// Rewrite every record which has not migrated yet in the new encoding, and
+    /// end the migration, returning the number of records rewritten. If it
+    /// fails partway through, restore the store from a snapshot.
+    pub fn finish_codec_migration(&mut self) -> Result<usize, AddRecordsError> {
+        if self.migrate_from.is_none() {
+            return Ok(0);
+        }
+        if !self.maintenance_index {
+            return Err(AddRecordsError::MaintenanceIndexDisabled);
+        }
+
+        let key_images = self.tracked_key_images();
+        let mut num_rewritten = 0;
+        for key_image in key_images.iter() {
+            if let Some(mut value) = self.read_tracked(key_image) {
+                // Clear the mark, so that a later migration can tell these
+                // records apart from the ones it writes
+                self.codec.set_migrated(&mut value, false);
+                let key = self.omap_key(key_image);
+                Self::write_value(&mut self.omap, &key, &value)?;
+                self.generation += 1;
+                let block_index = self.codec.decode(&value).block_index;
+                self.track_key(key_image, block_index, self.generation);
+                num_rewritten += 1;
+            }
+        }
+        self.migrate_from = None;
+        self.read_repair = false;
+        Ok(num_rewritten)
+    }
+
+    /// Whether a value encoding migration is in progress
+    pub fn is_migrating_codec(&self) -> bool {
+        self.migrate_from.is_some()
+    }
+
+    /// Switch the value encoding used for new writes, as
+    /// `begin_codec_migration` does without read-repair. Panics if a
+    /// migration to a different encoding is in progress.
+    pub fn set_write_codec(&mut self, codec: ValueCodec) {
+        if codec != self.codec {
+            self.begin_codec_migration(codec, false);
+        }
+    }
+
+    /// Re-key the store's internal hashing with a fresh seed, moving every
+    /// record to a new slot. The store is left unchanged on failure.
+    pub fn rehash(&mut self, new_seed: [u8; 32]) -> Result<(), AddRecordsError> {
+        if !self.maintenance_index {
+            return Err(AddRecordsError::MaintenanceIndexDisabled);
+        }
+        let mut new_omap = Self::create_omap(self.desired_capacity);
+        for (canonical, tracked) in self.tracked_keys.iter() {
+            let old_key = self.omap_key(&tracked.key_image);
+            let mut value = A8Bytes::<ValueSize>::default();
+            if self.omap.read(&old_key, &mut value) == OMAP_FOUND {
+                self.upgrade_value(&mut value);
+                let new_key = Self::derive_key(&new_seed, canonical);
+                Self::write_value(&mut new_omap, &new_key, &value)?;
+            }
+        }
+        if self.canary {
+            Self::write_canary(&mut new_omap, &new_seed)?;
+        }
+        self.omap = new_omap;
+        self.canary_in_map = self.canary;
+        self.hash_seed = new_seed;
+        self.omap_epoch += 1;
+        Ok(())
+    }
+
+    /// Look up a key image with a single oblivious map read, returning its
+    /// data, if it was found, and a KeyImageResultCode.
+    pub fn find(&mut self, key_image: &KeyImage) -> (CtOption<KeyImageData>, u32) {
+        let (data, result_code) = self.find_record_unchecked(key_image);
+        let found = result_code.ct_eq(&(KeyImageResultCode::Spent as u32))
+            | result_code.ct_eq(&(KeyImageResultCode::ProvisionallySpent as u32));
+        (CtOption::new(data, found), result_code)
+    }
+
+    /// Look up a key image as `find` does, also returning the number of ORAM
+    /// accesses made, which is always `oram_accesses_per_op`.
+    pub fn find_with_cost(&mut self, key_image: &KeyImage) -> (CtOption<KeyImageData>, u32, usize) {
+        let (data, result_code) = self.find(key_image);
+        (data, result_code, self.oram_accesses_per_op())
+    }
+
+    /// Test whether a key image is present, spent or provisionally spent,
+    /// without returning any of its data.
+    pub fn contains(&mut self, key_image: &KeyImage) -> Choice {
+        let started_at = self.clock.now();
+        let (_value, result_code) = self.read_value(key_image);
+        self.record_lookup_latency(started_at, result_code);
+
+        self.metrics.queries += 1;
+        self.audit(AuditOp::Lookup, AuditOutcome::Answered);
+        result_code.ct_eq(&(KeyImageResultCode::Spent as u32))
+            | result_code.ct_eq(&(KeyImageResultCode::ProvisionallySpent as u32))
+    }
+
+    /// Look up a key image, returning its data, or zeroes on a miss, and a
+    /// KeyImageResultCode.
+    #[deprecated(note = "use find, which only yields data on a hit")]
+    pub fn find_record(&mut self, key_image: &KeyImage) -> (KeyImageData, u32) {
+        self.find_record_unchecked(key_image)
+    }
+
+    /// Look up a key image as `find_record` does, for the wrappers and batch
+    /// operations which select on the result code themselves
+    pub(crate) fn find_record_unchecked(&mut self, key_image: &KeyImage) -> (KeyImageData, u32) {
+        let started_at = self.clock.now();
+        let (value, result_code) = self.read_value(key_image);
+        self.record_lookup_latency(started_at, result_code);
+
+        self.metrics.queries += 1;
+        self.audit(AuditOp::Lookup, AuditOutcome::Answered);
+        (self.codec.decode(&value), result_code)
+    }
+
+    /// Look up a key image, returning the exact value bytes stored for it,
+    /// which do not depend on the hash seed, and a KeyImageResultCode.
+    pub fn find_raw(&mut self, key_image: &KeyImage) -> (A8Bytes<ValueSize>, u32) {
+        let started_at = self.clock.now();
+        let (value, result_code) = self.read_value(key_image);
+        self.record_lookup_latency(started_at, result_code);
+
+        self.metrics.queries += 1;
+        self.audit(AuditOp::Lookup, AuditOutcome::Answered);
+        (value, result_code)
+    }
+
+    /// Look up a batch of key images, padded with dummy lookups to pad_to in
+    /// total, returning their data and KeyImageResultCodes in the same order.
+    pub fn find_records(
+        &mut self,
+        key_images: &[KeyImage],
+        pad_to: usize,
+    ) -> Vec<(KeyImageData, u32)> {
+        let mut results = vec![Default::default(); key_images.len()];
+        self.find_records_into(key_images, pad_to, &mut results);
+        results
+    }
+
+    /// Look up a batch of key images as `find_records` does, into a buffer with
+    /// one result per key image. Panics if the lengths differ.
+    pub fn find_records_into(
+        &mut self,
+        key_images: &[KeyImage],
+        pad_to: usize,
+        out: &mut [(KeyImageData, u32)],
+    ) {
+        assert_eq!(
+            out.len(),
+            key_images.len(),
+            "one result is written per key image"
+        );
+        self.find_records_streaming(key_images, pad_to, |idx, result| out[idx] = result);
+    }
+
+    /// Look up a batch of key images as `find_records` does, passing each
+    /// result to a callback, with its index, before the padding lookups are
+    /// done.
+    pub fn find_records_streaming<F: FnMut(usize, (KeyImageData, u32))>(
+        &mut self,
+        key_images: &[KeyImage],
+        pad_to: usize,
+        mut f: F,
+    ) {
+        let started_at = self.clock.now();
+        for (idx, key_image) in key_images.iter().enumerate() {
+            let (value, result_code) = self.read_value(key_image);
+            f(idx, (self.codec.decode(&value), result_code));
+        }
+        let padding_key_image = KeyImage::default();
+        for _ in key_images.len()..pad_to {
+            self.read_value(&padding_key_image);
+        }
+        self.record_latency(started_at, AuditOutcome::Answered);
+        self.last_batch_padding = pad_to.saturating_sub(key_images.len());
+
+        self.metrics.queries += key_images.len() as u64;
+        self.metrics.access.record_batch(key_images.len(), pad_to);
+        self.audit(AuditOp::BatchLookup, AuditOutcome::Answered);
+    }
+
+    /// Look up a batch of key images as `find_records` does, also returning the
+    /// number of ORAM accesses made, padding included.
+    pub fn find_records_with_cost(
+        &mut self,
+        key_images: &[KeyImage],
+        pad_to: usize,
+    ) -> (Vec<(KeyImageData, u32)>, usize) {
+        let results = self.find_records(key_images, pad_to);
+        let num_lookups = key_images.len().max(pad_to);
+        (results, num_lookups * self.oram_accesses_per_op())
+    }
+
+    /// Look up a batch of key images as `find_records` does, returning every
+    /// result tagged with the caller's id for its request.
+    pub fn find_tagged_records<Id: Copy>(
+        &mut self,
+        requests: &[(Id, KeyImage)],
+        pad_to: usize,
+    ) -> Vec<(Id, KeyImageData, u32)> {
+        let key_images: Vec<KeyImage> = requests.iter().map(|(_, key_image)| *key_image).collect();
+        self.find_records(&key_images, pad_to)
+            .into_iter()
+            .zip(requests.iter())
+            .map(|((data, result_code), (id, _))| (*id, data, result_code))
+            .collect()
+    }
+
+    /// Look up a key image and remove its record, in a single oblivious
+    /// operation. Takes are not recorded for snapshot deltas, and are refused
+    /// as `remove_record` is.
+    pub fn take_record(
+        &mut self,
+        key_image: &KeyImage,
+    ) -> Result<(KeyImageData, u32), AddRecordsError> {
+        if let Some(reason) = self.why_removal_blocked() {
+            self.audit(AuditOp::Take, AuditOutcome::Refused);
+            return Err(reason.into());
+        }
+        let started_at = self.clock.now();
+        let key = self.omap_key(key_image);
+        let mut value = self.miss_value.clone();
+        // The buffer is only meaningful if the key was found, and value stays
+        // the miss sentinel otherwise
+        let oram_result_code = self.omap.access_and_remove(&key, |code, buffer| {
+            ct_copy_bytes(code.ct_eq(&OMAP_FOUND), &mut value, buffer);
+            Choice::from(1)
+        });
+        let mut upgraded = value.clone();
+        self.upgrade_value(&mut upgraded);
+        ct_copy_bytes(oram_result_code.ct_eq(&OMAP_FOUND), &mut value, &upgraded);
+        let result_code = self.to_result_code(oram_result_code, &value);
+        self.record_lookup_latency(started_at, result_code);
+
+        self.omap_epoch += 1;
+        self.metrics.queries += 1;
+        self.audit(AuditOp::Take, AuditOutcome::Answered);
+        Ok((self.codec.decode(&value), result_code))
+    }
+
+    /// Find the highest spent-at block index, with its timestamp, among a set
+    /// of key images, selected in constant time.
+    pub fn latest_spend(&mut self, key_images: &[KeyImage]) -> Option<(BlockIndex, u64)> {
+        let mut latest = KeyImageData::default();
+        let mut any_found = Choice::from(0);
+
+        for key_image in key_images {
+            let (data, result_code) = self.find_record_unchecked(key_image);
+            let found = result_code.ct_eq(&(KeyImageResultCode::Spent as u32))
+                | result_code.ct_eq(&(KeyImageResultCode::ProvisionallySpent as u32));
+            // Take this record if it is present and either it is the first
+            // present record, or it is later than the best seen so far.
+            let take = found & (!any_found | data.block_index.ct_gt(&latest.block_index));
+            latest.block_index = ct_select_u64(take, data.block_index, latest.block_index);
+            latest.timestamp = ct_select_u64(take, data.timestamp, latest.timestamp);
+            any_found |= found;
+        }
+
+        if bool::from(any_found) {
+            Some((latest.block_index, latest.timestamp))
+        } else {
+            None
+        }
+    }
+
+    /// Look up a batch of key images, returning whether each is spent, packed
+    /// 64 to a word with bit `i % 64` of word `i / 64` for the i-th key
+    /// image.
+    pub fn spent_bitmask(&mut self, key_images: &[KeyImage]) -> Vec<u64> {
+        let mut words = vec![0u64; (key_images.len() + 63) / 64];
+        self.find_records_streaming(key_images, 0, |idx, (_, result_code)| {
+            let found = result_code.ct_eq(&(KeyImageResultCode::Spent as u32))
+                | result_code.ct_eq(&(KeyImageResultCode::ProvisionallySpent as u32));
+            words[idx / 64] |= (found.unwrap_u8() as u64) << (idx % 64);
+        });
+        words
+    }
+
+    /// Write a record to the oblivious map and track it, auditing the result
+    fn write_record(
+        &mut self,
+        key_image: &KeyImage,
+        data: &KeyImageData,
+        provisional: bool,
+    ) -> Result<(), AddRecordsError> {
+        let result = self.try_write_record(key_image, data, provisional);
+        let outcome = if result.is_ok() {
+            AuditOutcome::Written
+        } else {
+            AuditOutcome::Refused
+        };
+        self.audit(AuditOp::Add, outcome);
+        if result.is_ok() {
+            self.auto_prune();
+        }
+        result
+    }
+
+    /// Remove up to AUTO_PRUNE_BATCH of the oldest records, if they are older
+    /// than the configured maximum age. Only ingest-side data is consulted.
+    fn auto_prune(&mut self) {
+        let max_age = match self.auto_prune_older_than_blocks {
+            Some(max_age) => max_age,
+            None => return,
+        };
+        let newest_block = match self.keys_by_block.keys().next_back() {
+            Some(block_index) => *block_index,
+            None => return,
+        };
+        let cutoff = newest_block.saturating_sub(max_age);
+        let key_images: Vec<KeyImage> = self
+            .keys_by_block
+            .range(..cutoff)
+            .flat_map(|(_, canonical_key_images)| canonical_key_images.iter())
+            .take(AUTO_PRUNE_BATCH)
+            .map(|canonical| self.tracked_keys[canonical].key_image)
+            .collect();
+        // This runs after a write succeeded, so removals are not blocked
+        for key_image in key_images.iter() {
+            self.remove_unchecked(key_image);
+        }
+    }
+
+    /// Write a record to the oblivious map, and track it
+    fn try_write_record(
+        &mut self,
+        key_image: &KeyImage,
+        data: &KeyImageData,
+        provisional: bool,
+    ) -> Result<(), AddRecordsError> {
+        let started_at = self.clock.now();
+        if let Some(reason) = self.why_write_blocked() {
+            self.record_latency(started_at, AuditOutcome::Refused);
+            return Err(reason.into());
+        }
+
+        let key = self.omap_key(key_image);
+        let mut value = self.encode_value(data, provisional);
+        if let Some(timestamp) = self.overwritten_timestamp(key_image, data.timestamp) {
+            self.codec.set_timestamp(&mut value, timestamp);
+        }
+        let result = match Self::write_value(&mut self.omap, &key, &value) {
+            Err(AddRecordsError::MapOverflow(len, capacity)) => {
+                self.handle_overflow(&key, &value, len, capacity)
+            }
+            result => result,
+        };
+        let outcome = if result.is_ok() {
+            AuditOutcome::Written
+        } else {
+            AuditOutcome::Refused
+        };
+        self.record_latency(started_at, outcome);
+        result?;
+
+        self.generation += 1;
+        self.ingested = true;
+        self.track_key(key_image, data.block_index, self.generation);
+        self.metrics
+            .record_added(data.block_index, self.block_bucket_size);
+        self.last_write_at = self.clock.now();
+        Ok(())
+    }
+
+    /// The timestamp a write should keep instead of new_timestamp, under the
+    /// timestamp overwrite policy, if it overwrites an existing record.
+    ///
+    /// This is only called by ingest, so it may branch on whether the record
+    /// exists.
+    fn overwritten_timestamp(&mut self, key_image: &KeyImage, new_timestamp: u64) -> Option<u64> {
+        if self.timestamp_overwrite_policy == TimestampOverwritePolicy::Replace {
+            return None;
+        }
+        let existing = self.codec.decode(&self.read_tracked(key_image)?).timestamp;
+        match self.timestamp_overwrite_policy {
+            TimestampOverwritePolicy::Replace => None,
+            TimestampOverwritePolicy::Preserve => Some(existing),
+            TimestampOverwritePolicy::KeepEarliest => Some(existing.min(new_timestamp)),
+        }
+    }
+
+    /// Consult the overflow handler about a write which overflowed, and act
+    /// on its decision
+    fn handle_overflow(
+        &mut self,
+        key: &A8Bytes<KeySize>,
+        value: &A8Bytes<ValueSize>,
+        len: u64,
+        capacity: u64,
+    ) -> Result<(), AddRecordsError> {
+        let info = OverflowInfo {
+            len,
+            capacity,
+            desired_capacity: self.desired_capacity,
+        };
+        let action = match self.overflow_handler.as_ref() {
+            Some(overflow_handler) => overflow_handler(&info),
+            None => OverflowAction::Reject,
+        };
+        let action = match action {
+            // A strategy which would not grow the store cannot make room
+            OverflowAction::GrowByStrategy => match self.grow_strategy.next_capacity(&info) {
+                Some(desired_capacity) => OverflowAction::Grow(desired_capacity),
+                None => OverflowAction::Reject,
+            },
+            action => action,
+        };
+
+        match action {
+            OverflowAction::Reject => {
+                if let Some(suppressed) = self.overflow_log_due() {
+                    log::warn!(
+                        self.logger,
+                        "KeyImageStore overflowed at len = {}, capacity = {}, rejecting write ({} similar warnings suppressed)",
+                        len,
+                        capacity,
+                        suppressed
+                    );
+                }
+                Err(AddRecordsError::MapOverflow(len, capacity))
+            }
+            OverflowAction::Degrade => {
+                if let Some(suppressed) = self.overflow_log_due() {
+                    log::warn!(
+                        self.logger,
+                        "KeyImageStore overflowed at len = {}, capacity = {}, marking degraded ({} similar warnings suppressed)",
+                        len,
+                        capacity,
+                        suppressed
+                    );
+                }
+                self.mark_degraded();
+                Err(AddRecordsError::MapOverflow(len, capacity))
+            }
+            OverflowAction::Grow(desired_capacity) => {
+                log::info!(
+                    self.logger,
+                    "KeyImageStore overflowed at len = {}, capacity = {}, growing to {}",
+                    len,
+                    capacity,
+                    desired_capacity
+                );
+                if let Err(err) = self.grow(desired_capacity) {
+                    log::error!(self.logger, "KeyImageStore failed to grow: {}", err);
+                    return Err(AddRecordsError::MapOverflow(len, capacity));
+                }
+                Self::write_value(&mut self.omap, key, value)
+            }
+        }
+    }
+
+    /// Decide whether an overflow warning should be logged now, or be
+    /// suppressed by rate limiting.
+    ///
+    /// Returns the number of warnings suppressed since the last one logged
+    /// if this one is due, or None if it should be suppressed.
+    fn overflow_log_due(&mut self) -> Option<u64> {
+        let now = self.clock.now();
+        let due = match self.last_overflow_log_at {
+            Some(last) => {
+                now.checked_sub(last).unwrap_or_default() >= self.overflow_log_interval
+                    || self.overflow_logs_suppressed >= OVERFLOW_LOG_EVERY
+            }
+            None => true,
+        };
+        if !due {
+            self.overflow_logs_suppressed += 1;
+            self.metrics.overflow_warnings_suppressed += 1;
+            return None;
+        }
+
+        self.last_overflow_log_at = Some(now);
+        self.metrics.overflow_warnings_logged += 1;
+        Some(core::mem::replace(&mut self.overflow_logs_suppressed, 0))
+    }
+
+    /// Send an audit record to the audit sink, if there is one.
+    ///
+    /// Like `record_latency`, this must only be called once the oblivious
+    /// part of an operation is complete.
+    fn audit(&mut self, op: AuditOp, outcome: AuditOutcome) {
+        self.metrics.access.record_op(op);
+        if self.audit_sink.is_none() && self.audit_log.is_none() {
+            return;
+        }
+        let record = AuditRecord {
+            timestamp: self.clock.now(),
+            op,
+            outcome,
+        };
+        if let Some(audit_sink) = self.audit_sink.as_ref() {
+            audit_sink(&record);
+        }
+        if let Some(audit_log) = self.audit_log.as_mut() {
+            audit_log.push(record);
+        }
+    }
+
+    /// Encode a record as value bytes, marked provisional or final
+    fn encode_value(&self, data: &KeyImageData, provisional: bool) -> A8Bytes<ValueSize> {
+        let mut value = self.codec.encode(data);
+        self.codec.set_provisional(&mut value, provisional);
+        self.codec
+            .set_migrated(&mut value, self.migrate_from.is_some());
+        value
+    }
+
+    /// Rewrite value bytes read from the map in the current encoding, if a
+    /// migration is in progress and they are still in the old one. This is
+    /// constant-time.
+    fn upgrade_value(&self, value: &mut A8Bytes<ValueSize>) {
+        if let Some(from) = self.migrate_from.as_ref() {
+            self.codec.migrate(from, value);
+        }
+    }
+
+    /// Fail with MaintenanceIndexDisabled unless the store keeps the
+    /// maintenance index
+    fn require_maintenance_index(&self) -> Result<(), KeyImageStoreError> {
+        if self.maintenance_index {
+            Ok(())
+        } else {
+            Err(KeyImageStoreError::MaintenanceIndexDisabled)
+        }
+    }
+
+    /// Track a key image which was written by ingest at a generation
+    fn track_key(&mut self, key_image: &KeyImage, block_index: BlockIndex, generation: u64) {
+        if !self.maintenance_index {
+            return;
+        }
+        let canonical = (self.canonicalizer)(key_image);
+        let tracked = TrackedKey {
+            key_image: *key_image,
+            generation,
+            block_index,
+        };
+        if let Some(previous) = self.tracked_keys.insert(canonical, tracked) {
+            self.remove_from_block(&canonical, previous.block_index);
+        }
+        self.keys_by_block
+            .entry(block_index)
+            .or_default()
+            .insert(canonical);
+        self.removed_keys.remove(&canonical);
+    }
+
+    /// Stop tracking a key image which was removed by ingest at a generation
+    fn untrack_key(&mut self, key_image: &KeyImage, generation: u64) {
+        if !self.maintenance_index {
+            return;
+        }
+        let canonical = (self.canonicalizer)(key_image);
+        if let Some(previous) = self.tracked_keys.remove(&canonical) {
+            self.remove_from_block(&canonical, previous.block_index);
+        }
+        // Only outstanding snapshots and grows replay removals, so with none
+        // there is nothing to keep a tombstone for
+        if self.pinned_generations.is_empty() {
+            self.removed_keys.remove(&canonical);
+            self.tombstone_floor = generation;
+        } else {
+            self.removed_keys
+                .insert(canonical, (*key_image, generation));
+        }
+    }
+
+    /// The tracked key images, as last written, in canonical order
+    fn tracked_key_images(&self) -> Vec<KeyImage> {
+        self.tracked_keys
+            .values()
+            .map(|tracked| tracked.key_image)
+            .collect()
+    }
+
+    /// The tracked key images, as last written, of each block in a range
+    /// which has any, in block order
+    fn tracked_blocks<R: RangeBounds<BlockIndex>>(
+        &self,
+        block_range: R,
+    ) -> Vec<(BlockIndex, Vec<KeyImage>)> {
+        self.keys_by_block
+            .range(block_range)
+            .map(|(block_index, canonical_key_images)| {
+                let key_images = canonical_key_images
+                    .iter()
+                    .map(|canonical| self.tracked_keys[canonical].key_image)
+                    .collect();
+                (*block_index, key_images)
+            })
+            .collect()
+    }
+
+    /// The generation of the oldest outstanding snapshot or pending grow, or
+    /// the current generation if there is none. Tombstones at or below it
+    /// are not needed by anything outstanding.
+    fn tombstone_watermark(&self) -> u64 {
+        self.pinned_generations
+            .keys()
+            .next()
+            .cloned()
+            .unwrap_or(self.generation)
+    }
+
+    /// Drop the tombstones at or below the watermark, raising the tombstone
+    /// floor past them
+    fn drop_tombstones(&mut self) {
+        let watermark = self.tombstone_watermark();
+        let dropped: Vec<(CanonicalKeyImage, u64)> = self
+            .removed_keys
+            .iter()
+            .filter(|(_, (_, generation))| *generation <= watermark)
+            .map(|(canonical, (_, generation))| (*canonical, *generation))
+            .collect();
+        for (canonical, generation) in dropped.iter() {
+            self.removed_keys.remove(canonical);
+            self.tombstone_floor = self.tombstone_floor.max(*generation);
+        }
+    }
+
+    /// Note that a snapshot or pending grow of a generation is outstanding,
+    /// so that the tombstones of later removals are kept for it
+    fn pin_generation(&mut self, generation: u64) {
+        *self.pinned_generations.entry(generation).or_default() += 1;
+    }
+
+    /// Note that a snapshot or pending grow of a generation is no longer
+    /// outstanding
+    fn unpin_generation(&mut self, generation: u64) {
+        if let Some(count) = self.pinned_generations.get_mut(&generation) {
+            *count -= 1;
+            if *count == 0 {
+                self.pinned_generations.remove(&generation);
+            }
+        }
+    }
+
+    /// Remove a key image, by canonical bytes, from the by-block index
+    fn remove_from_block(&mut self, canonical: &CanonicalKeyImage, block_index: BlockIndex) {
+        if let Some(canonical_key_images) = self.keys_by_block.get_mut(&block_index) {
+            canonical_key_images.remove(canonical);
+            if canonical_key_images.is_empty() {
+                self.keys_by_block.remove(&block_index);
+            }
+        }
+    }
+
+    /// Note that a key image was added as provisional in a block
+    fn track_provisional(&mut self, key_image: &KeyImage, block_index: BlockIndex) {
+        if !self.maintenance_index {
+            return;
+        }
+        self.provisional_keys
+            .entry(block_index)
+            .or_default()
+            .insert(*key_image);
+    }
+
+    /// Read the raw value for a key image from the oblivious map, and map
+    /// the omap result code to a KeyImageResultCode branchlessly.
+    fn read_value(&mut self, key_image: &KeyImage) -> (A8Bytes<ValueSize>, u32) {
+        let key = self.omap_key(key_image);
+        let mut value = A8Bytes::<ValueSize>::default();
+        let oram_result_code = match self.migrate_from {
+            // Read-repair writes the upgraded record back. Access always
+            // writes back, so this looks the same whether or not the record
+            // needed upgrading.
+            Some(from) if self.read_repair => {
+                let codec = self.codec;
+                self.omap.access(&key, |code, buffer| {
+                    let mut upgraded = buffer.clone();
+                    codec.migrate(&from, &mut upgraded);
+                    ct_copy_bytes(code.ct_eq(&OMAP_FOUND), buffer, &upgraded);
+                    value.copy_from_slice(buffer);
+                })
+            }
+            _ => {
+                let oram_result_code = self.omap.read(&key, &mut value);
+                self.upgrade_value(&mut value);
+                oram_result_code
+            }
+        };
+        let result_code = self.to_result_code(oram_result_code, &value);
+        ct_copy_bytes(
+            !oram_result_code.ct_eq(&OMAP_FOUND),
+            &mut value,
+            &self.miss_value,
+        );
+        (value, result_code)
+    }
+
+    /// Map an omap result code, and the value read, to a KeyImageResultCode
+    /// branchlessly
+    fn to_result_code(&self, oram_result_code: u32, value: &A8Bytes<ValueSize>) -> u32 {
+        // OMAP_FOUND -> KeyImageResultCode::Spent, or ProvisionallySpent
+        // OMAP_NOT_FOUND -> KeyImageResultCode::NotSpent
+        // Other -> KeyImageResultCode::KeyImageError
+        let found = oram_result_code.ct_eq(&OMAP_FOUND);
+        let mut result_code = KeyImageResultCode::KeyImageError as u32;
+        result_code = ct_select_u32(found, KeyImageResultCode::Spent as u32, result_code);
+        result_code = ct_select_u32(
+            found & self.codec.is_provisional(value),
+            KeyImageResultCode::ProvisionallySpent as u32,
+            result_code,
+        );
+        result_code = ct_select_u32(
+            oram_result_code.ct_eq(&OMAP_NOT_FOUND),
+            KeyImageResultCode::NotSpent as u32,
+            result_code,
+        );
+        // Readiness only depends on ingest, which is public
+        let not_ready = self.strict_readiness && !self.ingested;
+        result_code = ct_select_u32(
+            Choice::from(not_ready as u8),
+            KeyImageResultCode::NotReady as u32,
+            result_code,
+        );
+        // This is debug assert to avoid creating a branch in production
+        debug_assert!(
+            oram_result_code == OMAP_FOUND
+                || oram_result_code == OMAP_NOT_FOUND
+                || oram_result_code == OMAP_INVALID_KEY,
+            "oram_result_code had an unexpected value: {}",
+            oram_result_code
+        );
+
+        result_code
+    }
+
+    /// Read the raw value for a tracked key image, for maintenance
+    /// operations.
+    ///
+    /// This branches on whether the record is present, so it must only be
+    /// used on key images which came from ingest, never for client queries.
+    fn read_tracked(&mut self, key_image: &KeyImage) -> Option<A8Bytes<ValueSize>> {
+        let key = self.omap_key(key_image);
+        let mut value = A8Bytes::<ValueSize>::default();
+        if self.omap.read(&key, &mut value) == OMAP_FOUND {
+            self.upgrade_value(&mut value);
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Record the latency of an operation which started at started_at.
+    ///
+    /// This must only be called once the oblivious part of the operation is
+    /// complete, so that the threshold check never branches on secrets.
+    fn record_latency(&mut self, started_at: Duration, outcome: AuditOutcome) -> Duration {
+        let latency = self.clock.now().checked_sub(started_at).unwrap_or_default();
+        self.metrics.latency.record(latency);
+        self.metrics.latency_by_outcome.record(outcome, latency);
+
+        if let Some(threshold) = self.slow_operation_threshold {
+            if latency > threshold {
+                self.metrics.slow_operations += 1;
+                log::warn!(
+                    self.logger,
+                    "KeyImageStore operation took {:?}, exceeding threshold {:?}",
+                    latency,
+                    threshold
+                );
+            }
+        }
+        latency
+    }
+
+    /// Record the latency of a single key lookup which started at
+    /// started_at, as answered and as a hit or a miss by its result code.
+    ///
+    /// Like `record_latency`, this must only be called once the lookup is
+    /// complete. The hit or miss is selected without branching on it.
+    fn record_lookup_latency(&mut self, started_at: Duration, result_code: u32) {
+        let latency = self.record_latency(started_at, AuditOutcome::Answered);
+        let found = result_code.ct_eq(&(KeyImageResultCode::Spent as u32))
+            | result_code.ct_eq(&(KeyImageResultCode::ProvisionallySpent as u32));
+        self.metrics
+            .latency_by_outcome
+            .record_hit_or_miss(found, latency);
+    }
+
+    /// Write a value to an oblivious map, translating the omap result code
+    fn write_value(
+        omap: &mut OMap<OSC>,
+        key: &A8Bytes<KeySize>,
+        value: &A8Bytes<ValueSize>,
+    ) -> Result<(), AddRecordsError> {
+        // Note: Passing true means we allow overwrite, which seems fine since
+        // a key image can only be spent once
+        let omap_result_code = omap.vartime_write(key, value, Choice::from(1));
+        if omap_result_code == OMAP_INVALID_KEY {
+            return Err(AddRecordsError::KeyRejected);
+        } else if omap_result_code == OMAP_OVERFLOW {
+            return Err(AddRecordsError::MapOverflow(omap.len(), omap.capacity()));
+        } else if omap_result_code != OMAP_FOUND && omap_result_code != OMAP_NOT_FOUND {
+            panic!(
+                "omap_result_code had an unexpected value: {}",
+                omap_result_code
+            );
+        }
+        Ok(())
+    }
+
+    /// Create an empty oblivious map
+    fn create_omap(desired_capacity: u64) -> Box<OMap<OSC>> {
+        Self::create_omap_with_stash(desired_capacity, STASH_SIZE)
+    }
+
+    /// Create an empty oblivious map with a given stash size
+    fn create_omap_with_stash(desired_capacity: u64, stash_size: usize) -> Box<OMap<OSC>> {
+        Box::new(<ObliviousMapCreator<OSC> as OMapCreator<
+            KeySize,
+            ValueSize,
+            McRng,
+        >>::create(
+            desired_capacity, stash_size, McRng::default
+        ))
+    }
+
+    /// The omap key for a key image under the current hash seed
+    fn omap_key(&self, key_image: &KeyImage) -> A8Bytes<KeySize> {
+        Self::derive_key(&self.hash_seed, &(self.canonicalizer)(key_image))
+    }
+
+    /// Derive the omap key corresponding to a canonicalized key image under a
+    /// hash seed
+    fn derive_key(hash_seed: &[u8; 32], canonical_key_image: &[u8; 32]) -> A8Bytes<KeySize> {
+        let mut hasher = Blake2b256::new();
+        hasher.update(KEY_DERIVATION_DOMAIN_TAG);
+        hasher.update(hash_seed);
+        hasher.update(canonical_key_image);
+        let mut key = A8Bytes::<KeySize>::default();
+        key.copy_from_slice(hasher.finalize().as_slice());
+        key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::MockClock,
+        key_image_store::test_utils::{data, indexed_test_store, test_store},
+        value_codec::BlockIndexWidth,
+    };
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use mc_common::logger::test_with_logger;
+    use mc_oblivious_traits::HeapORAMStorageCreator;
+
+    // Test that latest_spend picks out the highest block among present keys
+    #[test_with_logger]
+    fn test_latest_spend(logger: Logger) {
+        let mut store = test_store(512, logger);
+
+        store
+            .add_record(&KeyImage::from(1), &data(10, 1000))
+            .unwrap();
+        store
+            .add_record(&KeyImage::from(2), &data(42, 4200))
+            .unwrap();
+        store.add_record(&KeyImage::from(3), &data(7, 700)).unwrap();
+
+        let keys = [
+            KeyImage::from(1),
+            KeyImage::from(4),
+            KeyImage::from(2),
+            KeyImage::from(3),
+            KeyImage::from(5),
+        ];
+        assert_eq!(store.latest_spend(&keys), Some((42, 4200)));
+        assert_eq!(store.latest_spend(&keys[3..]), Some((7, 700)));
+        assert_eq!(
+            store.latest_spend(&[KeyImage::from(4), KeyImage::from(5)]),
+            None
+        );
+        assert_eq!(store.latest_spend(&[]), None);
+    }
+
+    // Test that a key image spent in block 0 is still reported
+    #[test_with_logger]
+    fn test_latest_spend_block_zero(logger: Logger) {
+        let mut store = test_store(512, logger);
+
+        store.add_record(&KeyImage::from(1), &data(0, 5)).unwrap();
+        assert_eq!(
+            store.latest_spend(&[KeyImage::from(2), KeyImage::from(1)]),
+            Some((0, 5))
+        );
+    }
+
+    // Test that each bit of the spent bitmask maps to the key image at the
+    // same index, across several words
+    #[test_with_logger]
+    fn test_spent_bitmask(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+        let spent = [0usize, 5, 63, 64, 100, 129];
+        for idx in spent.iter() {
+            store
+                .add_record(&KeyImage::from(*idx as u64), &data(1, 10))
+                .unwrap();
+        }
+        store
+            .add_provisional_record(&KeyImage::from(127), &data(2, 20))
+            .unwrap();
+
+        let key_images: Vec<KeyImage> = (0..130u64).map(KeyImage::from).collect();
+        let words = store.spent_bitmask(&key_images);
+        assert_eq!(words.len(), 3);
+        for idx in 0..130 {
+            let bit = (words[idx / 64] >> (idx % 64)) & 1 == 1;
+            assert_eq!(bit, spent.contains(&idx) || idx == 127, "index {}", idx);
+        }
+        assert_eq!(words[2] >> 2, 0);
+
+        assert!(store.spent_bitmask(&[]).is_empty());
+    }
+
+    // Test that the single lookup path and a batch, padded or not, give
+    // identical results
+    #[test_with_logger]
+    fn test_find_records_matches_single_lookups(logger: Logger) {
+        let mut store = test_store(512, logger);
+        store.add_record(&KeyImage::from(1), &data(3, 30)).unwrap();
+        store.add_record(&KeyImage::from(2), &data(4, 40)).unwrap();
+
+        let keys = [KeyImage::from(1), KeyImage::from(5), KeyImage::from(2)];
+        for key in keys.iter() {
+            let single = store.find_record_unchecked(key);
+            assert_eq!(store.find_records(&[*key], 1), vec![single]);
+            assert_eq!(store.find_records(&[*key], 8), vec![single]);
+        }
+
+        let batch = store.find_records(&keys, 2);
+        let expected: Vec<_> = keys
+            .iter()
+            .map(|key| store.find_record_unchecked(key))
+            .collect();
+        assert_eq!(batch, expected);
+        assert_eq!(batch[1].1, KeyImageResultCode::NotSpent as u32);
+
+        // Padding lookups are not counted as queries
+        assert_eq!(store.metrics().queries, 15);
+        assert!(store.find_records(&[], 4).is_empty());
+        assert_eq!(store.metrics().queries, 15);
+    }
+
+    // Test that provisional spends are flagged until their block is
+    // finalized, and only their own block's finalization affects them
+    #[test_with_logger]
+    fn test_provisional_lifecycle(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+        let provisional = KeyImageResultCode::ProvisionallySpent as u32;
+        let spent = KeyImageResultCode::Spent as u32;
+
+        store
+            .add_provisional_record(&KeyImage::from(1), &data(5, 50))
+            .unwrap();
+        store
+            .add_provisional_record(&KeyImage::from(2), &data(5, 50))
+            .unwrap();
+        store
+            .add_provisional_record(&KeyImage::from(3), &data(6, 60))
+            .unwrap();
+        store.add_record(&KeyImage::from(4), &data(5, 50)).unwrap();
+
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(1)),
+            (data(5, 50), provisional)
+        );
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(3)).1,
+            provisional
+        );
+        assert_eq!(store.find_record_unchecked(&KeyImage::from(4)).1, spent);
+
+        // Provisional spends still count as spends
+        assert_eq!(
+            store.latest_spend(&[KeyImage::from(3), KeyImage::from(4)]),
+            Some((6, 60))
+        );
+
+        assert_eq!(store.finalize_block(5).unwrap(), 2);
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(1)),
+            (data(5, 50), spent)
+        );
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(2)),
+            (data(5, 50), spent)
+        );
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(3)).1,
+            provisional
+        );
+        assert_eq!(store.find_record_unchecked(&KeyImage::from(4)).1, spent);
+        assert_eq!(store.finalize_block(5).unwrap(), 0);
+
+        // A provisional record which was rewritten as final is not counted
+        store.add_record(&KeyImage::from(3), &data(6, 60)).unwrap();
+        assert_eq!(store.finalize_block(6).unwrap(), 0);
+        assert_eq!(store.find_record_unchecked(&KeyImage::from(3)).1, spent);
+
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(7)).1,
+            KeyImageResultCode::NotSpent as u32
+        );
+    }
+
+    // Test that results carry the request ids they were asked with, whatever
+    // order the ids come in
+    #[test_with_logger]
+    fn test_find_tagged_records(logger: Logger) {
+        let mut store = test_store(512, logger);
+        for idx in 1..=4u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+
+        let requests = [
+            (93u32, KeyImage::from(3)),
+            (7, KeyImage::from(1)),
+            (512, KeyImage::from(9)),
+            (61, KeyImage::from(4)),
+            (2, KeyImage::from(2)),
+        ];
+        let results = store.find_tagged_records(&requests, 8);
+        assert_eq!(results.len(), requests.len());
+
+        let spent = KeyImageResultCode::Spent as u32;
+        for (id, found, result_code) in results {
+            match id {
+                93 => assert_eq!((found, result_code), (data(3, 30), spent)),
+                7 => assert_eq!((found, result_code), (data(1, 10), spent)),
+                61 => assert_eq!((found, result_code), (data(4, 40), spent)),
+                2 => assert_eq!((found, result_code), (data(2, 20), spent)),
+                512 => assert_eq!(result_code, KeyImageResultCode::NotSpent as u32),
+                _ => panic!("unexpected request id {}", id),
+            }
+        }
+    }
+
+    static FAKE_NOW_SECS: AtomicU64 = AtomicU64::new(1_600_000_000);
+
+    fn fake_now() -> Duration {
+        Duration::from_secs(FAKE_NOW_SECS.load(Ordering::SeqCst))
+    }
+
+    // Test that uptime increases as time passes, and created_at does not move
+    #[test_with_logger]
+    fn test_created_at_and_uptime(logger: Logger) {
+        let mut store: KeyImageStore<HeapORAMStorageCreator> =
+            KeyImageStoreBuilder::new(512, logger)
+                .time_source(fake_now)
+                .build();
+
+        let created_at = store.created_at();
+        assert_eq!(created_at, Duration::from_secs(1_600_000_000));
+        assert_eq!(store.uptime(), Duration::from_secs(0));
+
+        FAKE_NOW_SECS.fetch_add(5, Ordering::SeqCst);
+        let first = store.uptime();
+        assert_eq!(first, Duration::from_secs(5));
+
+        store.add_record(&KeyImage::from(1), &data(1, 1)).unwrap();
+        store.find_record_unchecked(&KeyImage::from(1));
+
+        FAKE_NOW_SECS.fetch_add(60, Ordering::SeqCst);
+        assert!(store.uptime() > first);
+        assert_eq!(store.uptime(), Duration::from_secs(65));
+        assert_eq!(store.created_at(), created_at);
+    }
+
+    // Test that a store without a time source reports zero uptime
+    #[test_with_logger]
+    fn test_no_time_source(logger: Logger) {
+        let store = test_store(512, logger);
+        assert_eq!(store.created_at(), Duration::default());
+        assert_eq!(store.uptime(), Duration::default());
+    }
+
+    // Test that the cost model matches the default configuration, which is a
+    // two-table cuckoo hash over path ORAM
+    #[test_with_logger]
+    fn test_oram_accesses_per_op(logger: Logger) {
+        let store = test_store(512, logger);
+        assert_eq!(store.oram_accesses_per_op(), 2);
+    }
+
+    // Test that stores with identical configurations share a fingerprint,
+    // whatever their seeds and contents, and that changing a parameter
+    // changes it
+    #[test_with_logger]
+    fn test_config_fingerprint(logger: Logger) {
+        let build = |builder: KeyImageStoreBuilder| builder.build::<HeapORAMStorageCreator>();
+        let mut first = build(KeyImageStoreBuilder::new(512, logger.clone()).maintenance_index());
+        let second = build(
+            KeyImageStoreBuilder::new(512, logger.clone())
+                .maintenance_index()
+                .hash_seed([7u8; 32]),
+        );
+        first.add_record(&KeyImage::from(1), &data(1, 10)).unwrap();
+        let fingerprint = first.config_fingerprint();
+        assert_eq!(second.config_fingerprint(), fingerprint);
+
+        let changed = [
+            build(KeyImageStoreBuilder::new(4096, logger.clone()).maintenance_index()),
+            // The configured capacity is covered, even where the omap rounds
+            // it to the same capacity
+            build(KeyImageStoreBuilder::new(500, logger.clone()).maintenance_index()),
+            build(
+                KeyImageStoreBuilder::new(512, logger.clone())
+                    .maintenance_index()
+                    .value_codec(ValueCodec::new(BlockIndexWidth::U128)),
+            ),
+            build(
+                KeyImageStoreBuilder::new(512, logger.clone())
+                    .maintenance_index()
+                    .miss_sentinel(data(1, 1)),
+            ),
+            build(
+                KeyImageStoreBuilder::new(512, logger.clone())
+                    .maintenance_index()
+                    .timestamp_overwrite_policy(TimestampOverwritePolicy::Preserve),
+            ),
+            build(
+                KeyImageStoreBuilder::new(512, logger.clone())
+                    .maintenance_index()
+                    .auto_prune_older_than_blocks(0),
+            ),
+            build(
+                KeyImageStoreBuilder::new(512, logger.clone())
+                    .maintenance_index()
+                    .auto_prune_older_than_blocks(u64::MAX),
+            ),
+            build(
+                KeyImageStoreBuilder::new(512, logger)
+                    .maintenance_index()
+                    .canary(),
+            ),
+        ];
+        for store in changed.iter() {
+            assert_ne!(store.config_fingerprint(), fingerprint);
+        }
+    }
+
+    // Test that the reported cost of a lookup matches the cost model, and
+    // scales with the padded size of a batch
+    #[test_with_logger]
+    fn test_find_with_cost(logger: Logger) {
+        let mut store = test_store(512, logger);
+        store.add_record(&KeyImage::from(1), &data(3, 30)).unwrap();
+        let per_op = store.oram_accesses_per_op();
+
+        let (found, result_code, cost) = store.find_with_cost(&KeyImage::from(1));
+        assert_eq!(Option::from(found), Some(data(3, 30)));
+        assert_eq!(result_code, KeyImageResultCode::Spent as u32);
+        assert_eq!(cost, per_op);
+        assert_eq!(store.find_with_cost(&KeyImage::from(2)).2, per_op);
+
+        let keys = [KeyImage::from(1), KeyImage::from(2), KeyImage::from(3)];
+        let (results, cost) = store.find_records_with_cost(&keys, 0);
+        assert_eq!(results, store.find_records(&keys, 0));
+        assert_eq!(cost, 3 * per_op);
+        assert_eq!(store.find_records_with_cost(&keys, 8).1, 8 * per_op);
+        assert_eq!(store.find_records_with_cost(&[], 0).1, 0);
+    }
+
+    // Test that a taken record returns its data and is then a miss, and that
+    // taking a missing key is a clean miss
+    #[test_with_logger]
+    fn test_take_record(logger: Logger) {
+        let mut store = test_store(512, logger);
+        store.add_record(&KeyImage::from(1), &data(3, 30)).unwrap();
+        store.add_record(&KeyImage::from(2), &data(4, 40)).unwrap();
+
+        assert_eq!(
+            store.take_record(&KeyImage::from(1)),
+            Ok((data(3, 30), KeyImageResultCode::Spent as u32))
+        );
+        assert_eq!(store.len(), 1);
+        let miss = (KeyImageData::default(), KeyImageResultCode::NotSpent as u32);
+        assert_eq!(store.find_record_unchecked(&KeyImage::from(1)), miss);
+        assert_eq!(store.take_record(&KeyImage::from(1)), Ok(miss));
+        assert_eq!(store.take_record(&KeyImage::from(5)), Ok(miss));
+
+        assert_eq!(store.len(), 1);
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(2)).0,
+            data(4, 40)
+        );
+    }
+
+    // Test that compacting the key index drops taken and removed records, so
+    // that it matches len() again, and that finalizing still works after
+    #[test_with_logger]
+    fn test_compact_key_index(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+        for idx in 1..=10u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, 0))
+                .unwrap();
+        }
+        store
+            .add_provisional_record(&KeyImage::from(11), &data(11, 0))
+            .unwrap();
+        store
+            .add_provisional_record(&KeyImage::from(12), &data(11, 0))
+            .unwrap();
+        for idx in [2u64, 5, 11].iter() {
+            store.take_record(&KeyImage::from(*idx)).unwrap();
+        }
+        assert!(store.remove_record(&KeyImage::from(7)).unwrap());
+        assert_eq!(store.len(), 8);
+        assert_eq!(store.tracked_keys.len(), 11);
+
+        assert_eq!(store.compact_key_index(), 3);
+        assert_eq!(store.tracked_keys.len(), store.len() as usize);
+        assert_eq!(
+            store
+                .keys_by_block
+                .values()
+                .map(BTreeSet::len)
+                .sum::<usize>(),
+            store.len() as usize
+        );
+        assert_eq!(store.provisional_keys[&11].len(), 1);
+        assert_eq!(store.compact_key_index(), 0);
+
+        assert_eq!(store.finalize_block(11).unwrap(), 1);
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(12)).1,
+            KeyImageResultCode::Spent as u32
+        );
+    }
+
+    // Test that compacting drops exactly the tombstones which no outstanding
+    // snapshot can replay, and that deltas from released snapshots are then
+    // refused
+    #[test_with_logger]
+    fn test_compact_tombstones(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+        for idx in 1..=10u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, 0))
+                .unwrap();
+        }
+        let (_, first) = store.snapshot().unwrap();
+        assert!(store.remove_record(&KeyImage::from(1)).unwrap());
+        assert!(store.remove_record(&KeyImage::from(2)).unwrap());
+        let (_, second) = store.snapshot_delta(&first).unwrap();
+        assert!(store.remove_record(&KeyImage::from(3)).unwrap());
+        assert_eq!(store.removed_keys.len(), 3);
+
+        // Both snapshots are outstanding, so every tombstone is needed
+        store.compact_key_index();
+        assert_eq!(store.removed_keys.len(), 3);
+
+        store.release_snapshot(&first);
+        store.compact_key_index();
+        assert_eq!(store.removed_keys.len(), 1);
+        assert_eq!(
+            store.snapshot_delta(&first).err(),
+            Some(KeyImageStoreError::SnapshotBaseCompacted(10, 12))
+        );
+        let (_, third) = store.snapshot_delta(&second).unwrap();
+
+        store.release_snapshot(&second);
+        store.release_snapshot(&third);
+        store.compact_key_index();
+        assert!(store.removed_keys.is_empty());
+        assert!(store.pinned_generations.is_empty());
+    }
+
+    // Test that a store built without the maintenance index keeps no index,
+    // still ingests and answers lookups, and refuses the operations which
+    // need the index
+    #[test_with_logger]
+    fn test_without_maintenance_index(logger: Logger) {
+        let mut store = test_store(512, logger.clone());
+        for idx in 1..=10u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        assert!(store.remove_record(&KeyImage::from(3)).unwrap());
+        assert_eq!(store.len(), 9);
+        assert!(store.tracked_keys.is_empty());
+        assert!(store.keys_by_block.is_empty());
+        assert!(store.removed_keys.is_empty());
+        assert!(store.pinned_generations.is_empty());
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(4)).0,
+            data(4, 40)
+        );
+        assert!(store.update_block_index(&KeyImage::from(4), 6).unwrap());
+
+        let disabled = Some(AddRecordsError::MaintenanceIndexDisabled);
+        assert_eq!(
+            store
+                .add_provisional_record(&KeyImage::from(11), &data(11, 0))
+                .err(),
+            disabled
+        );
+        assert_eq!(store.finalize_block(11).err(), disabled);
+        assert_eq!(store.backfill_timestamps(4, 50).err(), disabled);
+        assert_eq!(store.rehash([7u8; 32]).err(), disabled);
+        assert_eq!(store.compact_key_index(), 0);
+        assert_eq!(
+            store.snapshot().err(),
+            Some(KeyImageStoreError::MaintenanceIndexDisabled)
+        );
+        assert_eq!(
+            store.grow(1024).err(),
+            Some(KeyImageStoreError::MaintenanceIndexDisabled)
+        );
+        let disabled = Some(KeyImageStoreError::MaintenanceIndexDisabled);
+        assert_eq!(store.commitment().err(), disabled);
+        assert_eq!(store.verify_against_commitment([0u8; 32]).err(), disabled);
+        assert_eq!(
+            store.find_batch_with_proof(&[KeyImage::from(4)]).err(),
+            disabled
+        );
+        assert_eq!(store.estimate_timestamp_dedup_savings().err(), disabled);
+        assert_eq!(store.detect_timestamp_anomalies(..).err(), disabled);
+        assert_eq!(store.len(), 9);
+
+        // Auto-pruning finds old records through the index
+        assert_eq!(
+            KeyImageStoreBuilder::new(512, logger)
+                .auto_prune_older_than_blocks(10)
+                .try_build::<HeapORAMStorageCreator>()
+                .err(),
+            Some(KeyImageStoreError::MaintenanceIndexDisabled)
+        );
+    }
+
+    // Test that records are counted in the bucket of their block index
+    #[test_with_logger]
+    fn test_metrics_by_block_bucket(logger: Logger) {
+        let mut store = KeyImageStoreBuilder::new(512, logger)
+            .maintenance_index()
+            .block_bucket_size(100)
+            .build::<HeapORAMStorageCreator>();
+
+        for (idx, block_index) in [0u64, 99, 100, 150, 199, 1234].iter().enumerate() {
+            store
+                .add_record(&KeyImage::from(idx as u64), &data(*block_index, 0))
+                .unwrap();
+        }
+        store
+            .add_provisional_record(&KeyImage::from(10), &data(1250, 0))
+            .unwrap();
+        store.find_record_unchecked(&KeyImage::from(1));
+
+        let counts: Vec<(BlockIndex, u64)> = store
+            .metrics_by_block_bucket()
+            .iter()
+            .map(|(bucket, metrics)| (*bucket, metrics.records_added))
+            .collect();
+        assert_eq!(counts, vec![(0, 2), (100, 3), (1200, 2)]);
+        assert_eq!(store.metrics().records_added, 7);
+    }
+
+    // Test that filling a caller buffer gives the same results as the
+    // allocating batch lookup
+    #[test_with_logger]
+    fn test_find_records_into(logger: Logger) {
+        let mut store = test_store(512, logger);
+        for idx in 1..=10u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+
+        let key_images: Vec<KeyImage> = (5..=15u64).map(KeyImage::from).collect();
+        let expected = store.find_records(&key_images, 16);
+        let mut out = [(KeyImageData::default(), 0u32); 11];
+        store.find_records_into(&key_images, 16, &mut out);
+        assert_eq!(&out[..], &expected[..]);
+    }
+
+    // Test that the reported padding of the last batch is pad_to less the
+    // batch size, or zero if the batch was large enough
+    #[test_with_logger]
+    fn test_last_batch_padding(logger: Logger) {
+        let mut store = test_store(512, logger);
+        store.add_record(&KeyImage::from(1), &data(3, 30)).unwrap();
+        assert_eq!(store.last_batch_padding(), 0);
+
+        let keys = [KeyImage::from(1), KeyImage::from(2), KeyImage::from(3)];
+        store.find_records(&keys, 8);
+        assert_eq!(store.last_batch_padding(), 8 - keys.len());
+
+        // A batch which already reaches pad_to needs no padding
+        store.find_records(&keys, 2);
+        assert_eq!(store.last_batch_padding(), 0);
+
+        store.find_records(&keys[0..1], 4);
+        assert_eq!(store.last_batch_padding(), 3);
+    }
+
+    // Test that the streaming lookup calls back once per key image, in
+    // order, with the same results as the allocating batch lookup
+    #[test_with_logger]
+    fn test_find_records_streaming(logger: Logger) {
+        let mut store = test_store(512, logger);
+        for idx in 1..=10u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+
+        let key_images: Vec<KeyImage> = (5..=15u64).map(KeyImage::from).collect();
+        let expected = store.find_records(&key_images, 16);
+        let mut streamed = Vec::new();
+        store.find_records_streaming(&key_images, 16, |idx, result| streamed.push((idx, result)));
+        assert_eq!(streamed.len(), key_images.len());
+        for (position, (idx, result)) in streamed.iter().enumerate() {
+            assert_eq!(*idx, position);
+            assert_eq!(*result, expected[position]);
+        }
+    }
+
+    // Test that two replicas holding the same records return identical raw
+    // bytes, despite their different hash seeds
+    #[test_with_logger]
+    fn test_find_raw(logger: Logger) {
+        let mut first = indexed_test_store(512, logger.clone());
+        let mut second = indexed_test_store(512, logger);
+        for store in [&mut first, &mut second].iter_mut() {
+            store
+                .add_record(&KeyImage::from(1), &data(100, 1_600_000_000))
+                .unwrap();
+            store
+                .add_provisional_record(&KeyImage::from(2), &data(101, 1_600_000_010))
+                .unwrap();
+        }
+
+        for idx in 1..=3u64 {
+            let (first_value, first_code) = first.find_raw(&KeyImage::from(idx));
+            let (second_value, second_code) = second.find_raw(&KeyImage::from(idx));
+            assert_eq!(first_value[..], second_value[..]);
+            assert_eq!(first_code, second_code);
+        }
+
+        let (value, result_code) = first.find_raw(&KeyImage::from(1));
+        assert_eq!(
+            value[..],
+            first.value_codec().encode(&data(100, 1_600_000_000))[..]
+        );
+        assert_eq!(result_code, KeyImageResultCode::Spent as u32);
+        assert_eq!(
+            first.find_raw(&KeyImage::from(2)).1,
+            KeyImageResultCode::ProvisionallySpent as u32
+        );
+        assert_eq!(
+            first.find_raw(&KeyImage::from(3)).1,
+            KeyImageResultCode::NotSpent as u32
+        );
+    }
+
+    // Test that under continuous ingest, auto-prune keeps the store at a
+    // stable size holding only the most recent blocks
+    #[test_with_logger]
+    fn test_auto_prune(logger: Logger) {
+        let mut store = KeyImageStoreBuilder::new(1024, logger)
+            .maintenance_index()
+            .auto_prune_older_than_blocks(10)
+            .build::<HeapORAMStorageCreator>();
+
+        for block_index in 1..=200u64 {
+            for idx in 0..2u64 {
+                store
+                    .add_record(
+                        &KeyImage::from(block_index * 2 + idx),
+                        &data(block_index, 0),
+                    )
+                    .unwrap();
+            }
+            if block_index > 10 {
+                assert_eq!(store.len(), 22);
+                assert_eq!(store.tracked_keys.len(), 22);
+                assert_eq!(store.keys_by_block.len(), 11);
+            }
+            // With no snapshot outstanding, no tombstones are kept
+            assert!(store.removed_keys.is_empty());
+        }
+
+        // An outstanding snapshot keeps the tombstones its delta needs, and
+        // once it is released no more are added
+        let (_, info) = store.snapshot().unwrap();
+        for block_index in 201..=205u64 {
+            for idx in 0..2u64 {
+                store
+                    .add_record(
+                        &KeyImage::from(block_index * 2 + idx),
+                        &data(block_index, 0),
+                    )
+                    .unwrap();
+            }
+        }
+        assert_eq!(store.removed_keys.len(), 10);
+        store.release_snapshot(&info);
+        for block_index in 206..=210u64 {
+            for idx in 0..2u64 {
+                store
+                    .add_record(
+                        &KeyImage::from(block_index * 2 + idx),
+                        &data(block_index, 0),
+                    )
+                    .unwrap();
+            }
+        }
+        assert_eq!(store.removed_keys.len(), 10);
+        assert_eq!(store.tracked_keys.len(), 22);
+
+        for block_index in 1..190u64 {
+            assert_eq!(
+                store
+                    .find_record_unchecked(&KeyImage::from(block_index * 2))
+                    .1,
+                KeyImageResultCode::NotSpent as u32
+            );
+        }
+        for block_index in 190..=200u64 {
+            assert_eq!(
+                store.find_record_unchecked(&KeyImage::from(block_index * 2 + 1)),
+                (data(block_index, 0), KeyImageResultCode::Spent as u32)
+            );
+        }
+    }
+
+    static AUDITED_LOOKUPS: AtomicU64 = AtomicU64::new(0);
+    static AUDITED_WRITES: AtomicU64 = AtomicU64::new(0);
+    static AUDITED_REFUSALS: AtomicU64 = AtomicU64::new(0);
+    static AUDITED_OTHER: AtomicU64 = AtomicU64::new(0);
+
+    fn count_audit_record(record: &AuditRecord) {
+        assert_eq!(record.timestamp, Duration::default());
+        let counter = match (record.op, record.outcome) {
+            (AuditOp::Lookup, AuditOutcome::Answered) => &AUDITED_LOOKUPS,
+            (AuditOp::Add, AuditOutcome::Written) => &AUDITED_WRITES,
+            (AuditOp::Add, AuditOutcome::Refused) => &AUDITED_REFUSALS,
+            _ => &AUDITED_OTHER,
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Test that the audit sink receives one record of the expected type per
+    // operation, and that lookups are audited the same for hits and misses
+    #[test_with_logger]
+    fn test_audit_sink(logger: Logger) {
+        let mut store = KeyImageStoreBuilder::new(512, logger)
+            .audit_sink(Box::new(count_audit_record))
+            .build::<HeapORAMStorageCreator>();
+
+        store.add_record(&KeyImage::from(1), &data(1, 10)).unwrap();
+        store.add_record(&KeyImage::from(2), &data(2, 20)).unwrap();
+        store.find_record_unchecked(&KeyImage::from(1));
+        store.find_record_unchecked(&KeyImage::from(3));
+        store.find_record_unchecked(&KeyImage::from(4));
+        store.freeze();
+        assert!(store.add_record(&KeyImage::from(3), &data(3, 30)).is_err());
+        store.find_records(&[KeyImage::from(1), KeyImage::from(2)], 4);
+        store.remove_record(&KeyImage::from(2)).unwrap();
+
+        assert_eq!(AUDITED_LOOKUPS.load(Ordering::SeqCst), 3);
+        assert_eq!(AUDITED_WRITES.load(Ordering::SeqCst), 2);
+        assert_eq!(AUDITED_REFUSALS.load(Ordering::SeqCst), 1);
+        assert_eq!(AUDITED_OTHER.load(Ordering::SeqCst), 2);
+    }
+
+    // Test that draining the audit log returns every record made since the
+    // previous drain, in order, and leaves the log empty
+    #[test_with_logger]
+    fn test_drain_audit_log(logger: Logger) {
+        let mut store = KeyImageStoreBuilder::new(512, logger.clone())
+            .audit_log()
+            .build::<HeapORAMStorageCreator>();
+        assert!(store.drain_audit_log().is_empty());
+
+        store.add_record(&KeyImage::from(1), &data(1, 10)).unwrap();
+        store.find_record_unchecked(&KeyImage::from(1));
+        store.remove_record(&KeyImage::from(1)).unwrap();
+        let ops: Vec<(AuditOp, AuditOutcome)> = store
+            .drain_audit_log()
+            .iter()
+            .map(|record| (record.op, record.outcome))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                (AuditOp::Add, AuditOutcome::Written),
+                (AuditOp::Lookup, AuditOutcome::Answered),
+                (AuditOp::Remove, AuditOutcome::Written),
+            ]
+        );
+        assert!(store.drain_audit_log().is_empty());
+
+        store.find_records(&[KeyImage::from(2)], 4);
+        assert_eq!(store.drain_audit_log().len(), 1);
+        assert!(store.drain_audit_log().is_empty());
+
+        // Without the log enabled, nothing accumulates
+        let mut store = test_store(512, logger);
+        store.find_record_unchecked(&KeyImage::from(1));
+        assert!(store.drain_audit_log().is_empty());
+    }
+
+    // Test that an overflow handler which grows the store lets ingest continue
+    // past the original capacity
+    #[test_with_logger]
+    fn test_overflow_grow(logger: Logger) {
+        let mut store = KeyImageStoreBuilder::new(64, logger)
+            .maintenance_index()
+            .on_overflow(Box::new(|info: &OverflowInfo| {
+                OverflowAction::Grow(info.desired_capacity * 2)
+            }))
+            .build::<HeapORAMStorageCreator>();
+        let initial_capacity = store.capacity();
+
+        let num_records = initial_capacity * 2;
+        for idx in 1..=num_records {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx))
+                .unwrap();
+        }
+        assert!(store.capacity() > initial_capacity);
+        assert_eq!(store.len(), num_records);
+        for idx in 1..=num_records {
+            assert_eq!(
+                store.find_record_unchecked(&KeyImage::from(idx)).0,
+                data(idx, idx)
+            );
+        }
+    }
+
+    // Test that an overflow handler can degrade the store instead
+    #[test_with_logger]
+    fn test_overflow_degrade(logger: Logger) {
+        let mut store = KeyImageStoreBuilder::new(64, logger)
+            .on_overflow(Box::new(|_: &OverflowInfo| OverflowAction::Degrade))
+            .build::<HeapORAMStorageCreator>();
+
+        let limit = store.capacity() * 4;
+        let mut idx = 1;
+        let err = loop {
+            assert!(idx <= limit, "store never overflowed");
+            if let Err(err) = store.add_record(&KeyImage::from(idx), &data(idx, idx)) {
+                break err;
+            }
+            idx += 1;
+        };
+        assert!(matches!(err, AddRecordsError::MapOverflow(_, _)));
+        assert!(store.is_degraded());
+        assert_eq!(
+            store.add_record(&KeyImage::from(idx + 1), &data(1, 1)),
+            Err(AddRecordsError::Degraded)
+        );
+    }
+
+    // Test that repeated overflows within the log interval produce a single
+    // warning, and that another is logged once the interval has passed
+    #[test_with_logger]
+    fn test_overflow_log_rate_limit(logger: Logger) {
+        let clock = MockClock::new(Duration::from_secs(1_000));
+        let mut store = KeyImageStoreBuilder::new(64, logger)
+            .clock(Box::new(clock.clone()))
+            .overflow_log_interval(Duration::from_secs(10))
+            .build::<HeapORAMStorageCreator>();
+
+        let limit = store.capacity() * 4;
+        let mut num_overflows = 0;
+        for idx in 1..=limit {
+            if store
+                .add_record(&KeyImage::from(idx), &data(idx, idx))
+                .is_err()
+            {
+                num_overflows += 1;
+            }
+            if num_overflows == 5 {
+                break;
+            }
+        }
+        assert_eq!(num_overflows, 5, "store overflowed too rarely");
+        assert_eq!(store.metrics().overflow_warnings_logged, 1);
+        assert_eq!(store.metrics().overflow_warnings_suppressed, 4);
+
+        clock.advance(Duration::from_secs(10));
+        let mut idx = limit;
+        while store.metrics().overflow_warnings_logged == 1 {
+            idx += 1;
+            assert!(idx <= limit * 2, "store stopped overflowing");
+            store.add_record(&KeyImage::from(idx), &data(idx, idx)).ok();
+        }
+        assert_eq!(store.metrics().overflow_warnings_logged, 2);
+        assert_eq!(store.metrics().overflow_warnings_suppressed, 4);
+    }
+
+    // Test the timestamp dedup estimate against a known distribution of
+    // records over blocks
+    #[test_with_logger]
+    fn test_estimate_timestamp_dedup_savings(logger: Logger) {
+        let mut store = indexed_test_store(512, logger.clone());
+        assert_eq!(store.estimate_timestamp_dedup_savings().unwrap(), 0);
+
+        // 10 records in block 1, 5 in block 2 and 1 in block 3
+        let mut idx = 0u64;
+        for (block_index, count) in [(1u64, 10), (2, 5), (3, 1)].iter() {
+            for _ in 0..*count {
+                idx += 1;
+                store
+                    .add_record(&KeyImage::from(idx), &data(*block_index, 100))
+                    .unwrap();
+            }
+        }
+        // 16 timestamps saved, 3 table entries of 8 + 8 bytes added
+        assert_eq!(
+            store.estimate_timestamp_dedup_savings().unwrap(),
+            16 * 8 - 3 * 16
+        );
+
+        // Removed records no longer count
+        store.remove_record(&KeyImage::from(1)).unwrap();
+        assert_eq!(
+            store.estimate_timestamp_dedup_savings().unwrap(),
+            15 * 8 - 3 * 16
+        );
+
+        // With one record per block, a table would only cost space
+        let mut store = KeyImageStoreBuilder::new(512, logger)
+            .maintenance_index()
+            .value_codec(ValueCodec::new(BlockIndexWidth::U128))
+            .build::<HeapORAMStorageCreator>();
+        for idx in 1..=4u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx))
+                .unwrap();
+        }
+        assert_eq!(store.estimate_timestamp_dedup_savings().unwrap(), 0);
+    }
+
+    // Test that misses return the configured sentinel, on every lookup path,
+    // while hits are unaffected
+    #[test_with_logger]
+    fn test_miss_sentinel(logger: Logger) {
+        let ones = KeyImageData {
+            block_index: u64::MAX,
+            timestamp: u64::MAX,
+        };
+        for (sentinel, fill) in [(KeyImageData::default(), 0u8), (ones, 0xff)].iter() {
+            let mut store = KeyImageStoreBuilder::new(512, logger.clone())
+                .miss_sentinel(*sentinel)
+                .build::<HeapORAMStorageCreator>();
+            store.add_record(&KeyImage::from(1), &data(3, 30)).unwrap();
+
+            let (value, result_code) = store.find_raw(&KeyImage::from(2));
+            assert_eq!(result_code, KeyImageResultCode::NotSpent as u32);
+            assert!(value[0..16].iter().all(|byte| byte == fill));
+            assert_eq!(
+                store.find_record_unchecked(&KeyImage::from(2)),
+                (*sentinel, KeyImageResultCode::NotSpent as u32)
+            );
+            assert_eq!(
+                store.find_records(&[KeyImage::from(2), KeyImage::from(1)], 4),
+                vec![
+                    (*sentinel, KeyImageResultCode::NotSpent as u32),
+                    (data(3, 30), KeyImageResultCode::Spent as u32)
+                ]
+            );
+            assert_eq!(store.take_record(&KeyImage::from(2)).unwrap().0, *sentinel);
+            assert_eq!(
+                store.take_record(&KeyImage::from(1)).unwrap().0,
+                data(3, 30)
+            );
+            assert_eq!(store.find_record_unchecked(&KeyImage::from(1)).0, *sentinel);
+        }
+    }
+
+    // Test that find only yields data on a hit, whatever the miss sentinel,
+    // and yields the record's data, provisional or final
+    #[test_with_logger]
+    fn test_find_data_only_on_hit(logger: Logger) {
+        let mut store = KeyImageStoreBuilder::new(512, logger)
+            .maintenance_index()
+            .miss_sentinel(data(1, 10))
+            .build::<HeapORAMStorageCreator>();
+        store.add_record(&KeyImage::from(1), &data(3, 30)).unwrap();
+        store
+            .add_provisional_record(&KeyImage::from(2), &data(4, 40))
+            .unwrap();
+
+        let (found, result_code) = store.find(&KeyImage::from(1));
+        assert_eq!(Option::from(found), Some(data(3, 30)));
+        assert_eq!(result_code, KeyImageResultCode::Spent as u32);
+
+        let (found, result_code) = store.find(&KeyImage::from(2));
+        assert_eq!(Option::from(found), Some(data(4, 40)));
+        assert_eq!(result_code, KeyImageResultCode::ProvisionallySpent as u32);
+
+        let (found, result_code) = store.find(&KeyImage::from(3));
+        assert_eq!(Option::<KeyImageData>::from(found), None);
+        assert_eq!(result_code, KeyImageResultCode::NotSpent as u32);
+
+        store.take_record(&KeyImage::from(1)).unwrap();
+        assert!(bool::from(store.find(&KeyImage::from(1)).0.is_none()));
+    }
+
+    // Test that contains reports presence of final and provisional records,
+    // and absence of missing and taken ones
+    #[test_with_logger]
+    fn test_contains(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+        store.add_record(&KeyImage::from(1), &data(3, 30)).unwrap();
+        store
+            .add_provisional_record(&KeyImage::from(2), &data(4, 40))
+            .unwrap();
+
+        assert!(bool::from(store.contains(&KeyImage::from(1))));
+        assert!(bool::from(store.contains(&KeyImage::from(2))));
+        assert!(!bool::from(store.contains(&KeyImage::from(3))));
+
+        store.take_record(&KeyImage::from(1)).unwrap();
+        assert!(!bool::from(store.contains(&KeyImage::from(1))));
+        assert_eq!(store.metrics().queries, 5);
+    }
+
+    // Test that the access stats report aggregates a run of mixed operations,
+    // and does not depend on whether lookups hit
+    #[test_with_logger]
+    fn test_access_stats_report(logger: Logger) {
+        let mut store = test_store(512, logger.clone());
+        for idx in 1..=3u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        store.find(&KeyImage::from(1));
+        store.find(&KeyImage::from(9));
+        store.contains(&KeyImage::from(2));
+        store.find_records(&[KeyImage::from(1), KeyImage::from(9)], 8);
+        store.find_records(
+            &[KeyImage::from(2), KeyImage::from(3), KeyImage::from(4)],
+            0,
+        );
+        store.take_record(&KeyImage::from(3)).unwrap();
+        assert!(store.remove_record(&KeyImage::from(2)).unwrap());
+
+        let report = store.access_stats_report();
+        assert_eq!(report.lookups, 3);
+        assert_eq!(report.batch_lookups, 2);
+        assert_eq!(report.takes, 1);
+        assert_eq!(report.adds, 3);
+        assert_eq!(report.removes, 1);
+        assert_eq!(report.updates, 0);
+        assert_eq!(report.keys_looked_up, 9);
+        assert_eq!(report.padding_lookups, 6);
+        assert_eq!(report.total_lookups(), 15);
+        assert!((report.padding_ratio() - 0.4).abs() < 1e-9);
+        // One batch of 8 lookups, and one of 3
+        assert_eq!(report.batch_size_buckets[4], 1);
+        assert_eq!(report.batch_size_buckets[2], 1);
+        assert_eq!(report.batch_size_buckets.iter().sum::<u64>(), 2);
+
+        // The same operations with lookups which all miss give the same
+        // report
+        let mut other = test_store(512, logger);
+        for idx in 1..=3u64 {
+            other
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        other.find(&KeyImage::from(21));
+        other.find(&KeyImage::from(29));
+        other.contains(&KeyImage::from(22));
+        other.find_records(&[KeyImage::from(21), KeyImage::from(29)], 8);
+        other.find_records(
+            &[KeyImage::from(22), KeyImage::from(23), KeyImage::from(24)],
+            0,
+        );
+        other.take_record(&KeyImage::from(23)).unwrap();
+        other.remove_record(&KeyImage::from(2)).unwrap();
+        assert_eq!(other.access_stats_report(), report);
+    }
+
+    // Test that lookups answer NotReady before any ingest in strict mode, and
+    // ordinary misses otherwise, and that strict mode lets go once records
+    // are written or a snapshot which had some is restored
+    #[test_with_logger]
+    fn test_strict_readiness(logger: Logger) {
+        let mut store = KeyImageStoreBuilder::new(512, logger.clone())
+            .maintenance_index()
+            .strict_readiness()
+            .build::<HeapORAMStorageCreator>();
+        let (found, result_code) = store.find(&KeyImage::from(1));
+        assert!(bool::from(found.is_none()));
+        assert_eq!(result_code, KeyImageResultCode::NotReady as u32);
+        assert_eq!(
+            store.find_records(&[KeyImage::from(1)], 4),
+            vec![(KeyImageData::default(), KeyImageResultCode::NotReady as u32)]
+        );
+        assert!(!bool::from(store.contains(&KeyImage::from(1))));
+
+        // Removing what is not there, or clearing an empty store, is not
+        // ingest and changes nothing
+        let info = store.snapshot_info();
+        assert_eq!(store.remove_record(&KeyImage::from(1)), Ok(false));
+        store.clear().unwrap();
+        assert_eq!(store.snapshot_info(), info);
+        assert_eq!(
+            store.find(&KeyImage::from(1)).1,
+            KeyImageResultCode::NotReady as u32
+        );
+
+        store.add_record(&KeyImage::from(1), &data(3, 30)).unwrap();
+        assert_eq!(
+            store.find(&KeyImage::from(2)).1,
+            KeyImageResultCode::NotSpent as u32
+        );
+        assert_eq!(
+            store.find(&KeyImage::from(1)).1,
+            KeyImageResultCode::Spent as u32
+        );
+
+        let (snapshot, _) = store.snapshot().unwrap();
+        let mut restored = KeyImageStoreBuilder::new(512, logger.clone())
+            .maintenance_index()
+            .strict_readiness()
+            .build::<HeapORAMStorageCreator>();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(
+            restored.find(&KeyImage::from(2)).1,
+            KeyImageResultCode::NotSpent as u32
+        );
+
+        // Without strict mode an empty store answers ordinary misses
+        let mut store = indexed_test_store(512, logger);
+        assert_eq!(
+            store.find(&KeyImage::from(1)).1,
+            KeyImageResultCode::NotSpent as u32
+        );
+    }
+
+    // Test that each timestamp overwrite policy picks the expected timestamp
+    // when a record is overwritten, and leaves new records alone
+    #[test_with_logger]
+    fn test_timestamp_overwrite_policy(logger: Logger) {
+        for (policy, later, earlier) in [
+            (TimestampOverwritePolicy::Replace, 50, 20),
+            (TimestampOverwritePolicy::Preserve, 30, 30),
+            (TimestampOverwritePolicy::KeepEarliest, 30, 20),
+        ]
+        .iter()
+        {
+            let mut store = KeyImageStoreBuilder::new(512, logger.clone())
+                .timestamp_overwrite_policy(*policy)
+                .build::<HeapORAMStorageCreator>();
+            store.add_record(&KeyImage::from(1), &data(3, 30)).unwrap();
+            assert_eq!(
+                store.find_record_unchecked(&KeyImage::from(1)).0,
+                data(3, 30)
+            );
+
+            // The block index always comes from the new record
+            store.add_record(&KeyImage::from(1), &data(4, 50)).unwrap();
+            assert_eq!(
+                store.find_record_unchecked(&KeyImage::from(1)).0,
+                data(4, *later)
+            );
+
+            store.add_record(&KeyImage::from(2), &data(3, 30)).unwrap();
+            store.add_record(&KeyImage::from(2), &data(5, 20)).unwrap();
+            assert_eq!(
+                store.find_record_unchecked(&KeyImage::from(2)).0,
+                data(5, *earlier)
+            );
+        }
+    }
+
+    // Test that a desired capacity below the floor is rejected, and one at
+    // or above it is accepted
+    #[test_with_logger]
+    fn test_capacity_floor(logger: Logger) {
+        let result = KeyImageStoreBuilder::new(256, logger.clone())
+            .capacity_floor(1024)
+            .try_build::<HeapORAMStorageCreator>();
+        assert_eq!(
+            result.err(),
+            Some(KeyImageStoreError::CapacityBelowFloor(256, 1024))
+        );
+
+        let store = KeyImageStoreBuilder::new(1024, logger.clone())
+            .capacity_floor(1024)
+            .try_build::<HeapORAMStorageCreator>()
+            .unwrap();
+        assert!(store.capacity() >= 1024);
+
+        // Without a floor, any capacity is accepted
+        assert!(KeyImageStoreBuilder::new(256, logger)
+            .try_build::<HeapORAMStorageCreator>()
+            .is_ok());
+    }
+
+    // Test that a store built with the seed captured from another derives
+    // the same omap key for every key image
+    #[test_with_logger]
+    fn test_deterministic_key_derivation(logger: Logger) {
+        let random = test_store(512, logger.clone());
+        assert_eq!(random.seed(), None);
+
+        let mut store = KeyImageStoreBuilder::new(512, logger.clone())
+            .maintenance_index()
+            .hash_seed([3u8; 32])
+            .build::<HeapORAMStorageCreator>();
+        let seed = store.seed().unwrap();
+        assert_eq!(seed, [3u8; 32]);
+        for idx in 1..=32u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, 0))
+                .unwrap();
+        }
+
+        let mut reproduced = KeyImageStoreBuilder::new(512, logger)
+            .maintenance_index()
+            .hash_seed(seed)
+            .build::<HeapORAMStorageCreator>();
+        for idx in 1..=32u64 {
+            reproduced
+                .add_record(&KeyImage::from(idx), &data(idx, 0))
+                .unwrap();
+            let key_image = KeyImage::from(idx);
+            assert_eq!(
+                reproduced.omap_key(&key_image)[..],
+                store.omap_key(&key_image)[..]
+            );
+            assert_ne!(
+                random.omap_key(&key_image)[..],
+                store.omap_key(&key_image)[..]
+            );
+        }
+
+        // Rehashing moves to a seed the operator chose, which is exposed too
+        store.rehash([4u8; 32]).unwrap();
+        assert_eq!(store.seed(), Some([4u8; 32]));
+    }
+
+    // Test that updating the block index of a present record keeps its
+    // timestamp and provisional status, and that a missing key is a no-op
+    #[test_with_logger]
+    fn test_update_block_index(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+        store
+            .add_record(&KeyImage::from(1), &data(10, 100))
+            .unwrap();
+        store
+            .add_provisional_record(&KeyImage::from(2), &data(11, 110))
+            .unwrap();
+
+        assert_eq!(store.update_block_index(&KeyImage::from(1), 12), Ok(true));
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(1)),
+            (data(12, 100), KeyImageResultCode::Spent as u32)
+        );
+
+        // A provisional record stays provisional, and finalizes with its new
+        // block
+        assert_eq!(store.update_block_index(&KeyImage::from(2), 13), Ok(true));
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(2)),
+            (data(13, 110), KeyImageResultCode::ProvisionallySpent as u32)
+        );
+        assert_eq!(store.finalize_block(11).unwrap(), 0);
+        assert_eq!(store.finalize_block(13).unwrap(), 1);
+
+        // A missing key image is not created
+        let generation = store.snapshot_info().generation;
+        assert_eq!(store.update_block_index(&KeyImage::from(3), 14), Ok(false));
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(3)).1,
+            KeyImageResultCode::NotSpent as u32
+        );
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.snapshot_info().generation, generation);
+
+        store.freeze();
+        assert_eq!(
+            store.update_block_index(&KeyImage::from(1), 15),
+            Err(AddRecordsError::ReadOnly)
+        );
+    }
+
+    // Test that latencies are recorded under the outcome of each operation,
+    // that single key lookups land as hits or misses, and that writes blocked
+    // before reaching the map count as refused
+    #[test_with_logger]
+    fn test_latency_by_outcome(logger: Logger) {
+        let mut store = test_store(512, logger);
+        store.add_record(&KeyImage::from(1), &data(1, 10)).unwrap();
+        store.find_record_unchecked(&KeyImage::from(1));
+        store.find_record_unchecked(&KeyImage::from(2));
+        store.find_records(&[KeyImage::from(1)], 4);
+        assert_eq!(store.contains(&KeyImage::from(1)).unwrap_u8(), 1);
+        store.freeze();
+        assert!(store.add_record(&KeyImage::from(3), &data(3, 30)).is_err());
+
+        let by_outcome = store.latency_by_outcome();
+        assert_eq!(by_outcome.get(AuditOutcome::Answered).count(), 4);
+        assert_eq!(by_outcome.hits().count(), 2);
+        assert_eq!(by_outcome.misses().count(), 1);
+        assert_eq!(by_outcome.get(AuditOutcome::Written).count(), 1);
+        assert_eq!(by_outcome.get(AuditOutcome::Refused).count(), 1);
+        assert_eq!(store.metrics().latency.count(), 6);
+    }
+
+    // Test staleness detection by driving a mock clock, without sleeping
+    #[test_with_logger]
+    fn test_is_stale(logger: Logger) {
+        let clock = MockClock::new(Duration::from_secs(1_000));
+        let mut store = KeyImageStoreBuilder::new(512, logger)
+            .clock(Box::new(clock.clone()))
+            .build::<HeapORAMStorageCreator>();
+        let max_age = Duration::from_secs(60);
+        assert_eq!(store.last_write_at(), Duration::from_secs(1_000));
+        assert!(!store.is_stale(max_age));
+
+        clock.advance(Duration::from_secs(61));
+        assert!(store.is_stale(max_age));
+        assert_eq!(store.uptime(), Duration::from_secs(61));
+
+        store.add_record(&KeyImage::from(1), &data(1, 10)).unwrap();
+        assert_eq!(store.last_write_at(), Duration::from_secs(1_061));
+        assert!(!store.is_stale(max_age));
+
+        // Lookups and refused writes do not count as ingest
+        clock.advance(Duration::from_secs(30));
+        store.find_record_unchecked(&KeyImage::from(1));
+        store.freeze();
+        assert!(store.add_record(&KeyImage::from(2), &data(2, 20)).is_err());
+        clock.advance(Duration::from_secs(31));
+        assert!(store.is_stale(max_age));
+    }
+
+    // Test that backfilling sets the timestamp of every record in a block,
+    // and only that block
+    #[test_with_logger]
+    fn test_backfill_timestamps(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+        for idx in 1..=3u64 {
+            store
+                .add_record_without_timestamp(&KeyImage::from(idx), 5)
+                .unwrap();
+        }
+        store
+            .add_provisional_record(&KeyImage::from(4), &data(5, 0))
+            .unwrap();
+        store
+            .add_record_without_timestamp(&KeyImage::from(10), 6)
+            .unwrap();
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(1)).0,
+            data(5, 0)
+        );
+
+        assert_eq!(store.backfill_timestamps(5, 1_600_000_000).unwrap(), 4);
+        for idx in 1..=3u64 {
+            assert_eq!(
+                store.find_record_unchecked(&KeyImage::from(idx)),
+                (data(5, 1_600_000_000), KeyImageResultCode::Spent as u32)
+            );
+        }
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(4)),
+            (
+                data(5, 1_600_000_000),
+                KeyImageResultCode::ProvisionallySpent as u32
+            )
+        );
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(10)).0,
+            data(6, 0)
+        );
+        assert_eq!(store.backfill_timestamps(7, 1).unwrap(), 0);
+    }
+
+    fn assert_send<T: Send>() {}
+    fn assert_sync<T: Sync>() {}
+
+    // Test, at compile time, that the store and its builder can be moved
+    // between and shared across threads
+    #[test]
+    fn test_send_sync() {
+        assert_send::<KeyImageStore<HeapORAMStorageCreator>>();
+        assert_sync::<KeyImageStore<HeapORAMStorageCreator>>();
+        assert_send::<KeyImageStoreBuilder>();
+        assert_sync::<KeyImageStoreBuilder>();
+    }
+
+    // Test that a frozen store refuses writes as read-only, but still serves
+    // lookups
+    #[test_with_logger]
+    fn test_write_blocked_frozen(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+        store.add_record(&KeyImage::from(1), &data(1, 10)).unwrap();
+        assert_eq!(store.why_write_blocked(), None);
+
+        store.freeze();
+        assert!(store.is_frozen());
+        assert_eq!(store.why_write_blocked(), Some(WriteBlockReason::Frozen));
+        assert_eq!(
+            store.add_record(&KeyImage::from(2), &data(2, 20)),
+            Err(AddRecordsError::ReadOnly)
+        );
+        assert_eq!(
+            store.add_provisional_record(&KeyImage::from(2), &data(2, 20)),
+            Err(AddRecordsError::ReadOnly)
+        );
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(1)).0,
+            data(1, 10)
+        );
+        assert_eq!(store.len(), 1);
+
+        store.unfreeze();
+        assert_eq!(store.why_write_blocked(), None);
+        store.add_record(&KeyImage::from(2), &data(2, 20)).unwrap();
+    }
+
+    // Test that removals and takes are refused by a frozen, degraded or
+    // paused store, leaving its records in place, but not by the soft limit
+    #[test_with_logger]
+    fn test_removal_blocked(logger: Logger) {
+        let mut store = KeyImageStoreBuilder::new(512, logger)
+            .soft_limit(2)
+            .build::<HeapORAMStorageCreator>();
+        store.add_record(&KeyImage::from(1), &data(1, 10)).unwrap();
+        store.add_record(&KeyImage::from(2), &data(2, 20)).unwrap();
+
+        store.freeze();
+        assert_eq!(
+            store.remove_record(&KeyImage::from(1)),
+            Err(AddRecordsError::ReadOnly)
+        );
+        assert_eq!(store.clear(), Err(AddRecordsError::ReadOnly));
+        assert_eq!(
+            store.take_record(&KeyImage::from(2)),
+            Err(AddRecordsError::ReadOnly)
+        );
+        store.unfreeze();
+
+        store.mark_degraded();
+        assert_eq!(
+            store.remove_record(&KeyImage::from(1)),
+            Err(AddRecordsError::Degraded)
+        );
+        store.clear_degraded();
+
+        store.pause_ingest();
+        assert_eq!(store.clear(), Err(AddRecordsError::IngestPaused));
+        store.resume_ingest();
+
+        assert_eq!(store.len(), 2);
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(1)).0,
+            data(1, 10)
+        );
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(2)).0,
+            data(2, 20)
+        );
+        assert_eq!(store.remove_record(&KeyImage::from(1)), Ok(true));
+        assert_eq!(
+            store.take_record(&KeyImage::from(2)).unwrap().0,
+            data(2, 20)
+        );
+        store.add_record(&KeyImage::from(3), &data(3, 30)).unwrap();
+        assert_eq!(store.clear(), Ok(()));
+        assert!(store.is_empty());
+    }
+
+    // Test that a degraded store refuses writes until the mark is cleared, and
+    // that being frozen takes precedence
+    #[test_with_logger]
+    fn test_write_blocked_degraded(logger: Logger) {
+        let mut store = test_store(512, logger);
+        store.mark_degraded();
+        assert!(store.is_degraded());
+        assert_eq!(store.why_write_blocked(), Some(WriteBlockReason::Degraded));
+        assert_eq!(
+            store.add_record(&KeyImage::from(1), &data(1, 10)),
+            Err(AddRecordsError::Degraded)
+        );
+
+        store.freeze();
+        assert_eq!(store.why_write_blocked(), Some(WriteBlockReason::Frozen));
+        store.unfreeze();
+
+        store.clear_degraded();
+        assert_eq!(store.why_write_blocked(), None);
+        store.add_record(&KeyImage::from(1), &data(1, 10)).unwrap();
+    }
+
+    // Test that a store seeded in the old value layout is read correctly
+    // while migrating, that read-repair upgrades the records it reads, and
+    // that finishing rewrites the rest
+    #[test_with_logger]
+    fn test_codec_migration(logger: Logger) {
+        let narrow = ValueCodec::new(BlockIndexWidth::U64);
+        let wide = ValueCodec::new(BlockIndexWidth::U128);
+        let raw_value = |store: &mut KeyImageStore<HeapORAMStorageCreator>, idx: u64| {
+            let key = store.omap_key(&KeyImage::from(idx));
+            let mut value = A8Bytes::<ValueSize>::default();
+            assert_eq!(store.omap.read(&key, &mut value), OMAP_FOUND);
+            value
+        };
+
+        for read_repair in [false, true].iter() {
+            let mut store = KeyImageStoreBuilder::new(512, logger.clone())
+                .maintenance_index()
+                .value_codec(narrow)
+                .build::<HeapORAMStorageCreator>();
+            for idx in 1..=10u64 {
+                store
+                    .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                    .unwrap();
+            }
+            store
+                .add_provisional_record(&KeyImage::from(11), &data(11, 110))
+                .unwrap();
+
+            store.begin_codec_migration(wide, *read_repair);
+            assert!(store.is_migrating_codec());
+            for idx in 1..=10u64 {
+                assert_eq!(
+                    store.find_record_unchecked(&KeyImage::from(idx)),
+                    (data(idx, idx * 10), KeyImageResultCode::Spent as u32)
+                );
+            }
+            assert_eq!(
+                store.find_record_unchecked(&KeyImage::from(11)).1,
+                KeyImageResultCode::ProvisionallySpent as u32
+            );
+            assert_eq!(
+                store.find_record_unchecked(&KeyImage::from(12)),
+                (KeyImageData::default(), KeyImageResultCode::NotSpent as u32)
+            );
+
+            // Only read-repair rewrites the records which were read
+            let value = raw_value(&mut store, 3);
+            assert_eq!(wide.decode(&value) == data(3, 30), *read_repair);
+            assert_eq!(narrow.decode(&value) == data(3, 30), !*read_repair);
+
+            // New writes use the new layout
+            store
+                .add_record(&KeyImage::from(12), &data(12, 120))
+                .unwrap();
+            assert_eq!(wide.decode(&raw_value(&mut store, 12)), data(12, 120));
+
+            assert_eq!(store.finish_codec_migration().unwrap(), 12);
+            assert!(!store.is_migrating_codec());
+            for idx in 1..=12u64 {
+                let value = raw_value(&mut store, idx);
+                assert_eq!(wide.decode(&value), data(idx, idx * 10));
+                assert_eq!(value[ValueSize::USIZE - 1], 0);
+            }
+            assert_eq!(store.finalize_block(11).unwrap(), 1);
+            assert_eq!(
+                store.find_record_unchecked(&KeyImage::from(11)),
+                (data(11, 110), KeyImageResultCode::Spent as u32)
+            );
+        }
+    }
+
+    // Test that records written before and after switching the write codec
+    // both read back correctly, each stored in the encoding it was written in
+    #[test_with_logger]
+    fn test_set_write_codec(logger: Logger) {
+        let narrow = ValueCodec::new(BlockIndexWidth::U64);
+        let wide = ValueCodec::new(BlockIndexWidth::U128);
+        let mut store = KeyImageStoreBuilder::new(512, logger)
+            .maintenance_index()
+            .value_codec(narrow)
+            .build::<HeapORAMStorageCreator>();
+        for idx in 1..=5u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+
+        store.set_write_codec(wide);
+        assert_eq!(store.value_codec(), &wide);
+        assert!(store.is_migrating_codec());
+        // Switching again to the same codec changes nothing
+        store.set_write_codec(wide);
+        for idx in 6..=10u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+
+        for idx in 1..=10u64 {
+            let key = store.omap_key(&KeyImage::from(idx));
+            let mut value = A8Bytes::<ValueSize>::default();
+            assert_eq!(store.omap.read(&key, &mut value), OMAP_FOUND);
+            let written_with = if idx <= 5 { narrow } else { wide };
+            assert_eq!(written_with.decode(&value), data(idx, idx * 10));
+
+            let (found, result_code) = store.find(&KeyImage::from(idx));
+            assert_eq!(Option::from(found), Some(data(idx, idx * 10)));
+            assert_eq!(result_code, KeyImageResultCode::Spent as u32);
+        }
+
+        assert_eq!(store.finish_codec_migration().unwrap(), 10);
+        for idx in 1..=10u64 {
+            let (found, _) = store.find(&KeyImage::from(idx));
+            assert_eq!(Option::from(found), Some(data(idx, idx * 10)));
+        }
+    }
+
+    // Test that ingest fails while paused, that reads continue, and that
+    // ingest succeeds again after resuming
+    #[test_with_logger]
+    fn test_pause_ingest(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+        store.add_record(&KeyImage::from(1), &data(1, 10)).unwrap();
+
+        store.pause_ingest();
+        assert!(store.is_ingest_paused());
+        assert!(!store.is_frozen());
+        assert_eq!(
+            store.why_write_blocked(),
+            Some(WriteBlockReason::IngestPaused)
+        );
+        assert_eq!(
+            store.add_record(&KeyImage::from(2), &data(2, 20)),
+            Err(AddRecordsError::IngestPaused)
+        );
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(1)).0,
+            data(1, 10)
+        );
+        let (snapshot, _) = store.snapshot().unwrap();
+
+        store.resume_ingest();
+        assert_eq!(store.why_write_blocked(), None);
+        store.add_record(&KeyImage::from(2), &data(2, 20)).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(!snapshot.is_empty());
+    }
+
+    // Test that writes are refused once the soft limit is reached
+    #[test_with_logger]
+    fn test_write_blocked_soft_limit(logger: Logger) {
+        let mut store = KeyImageStoreBuilder::new(512, logger)
+            .soft_limit(3)
+            .build::<HeapORAMStorageCreator>();
+        for idx in 1..=3u64 {
+            assert_eq!(store.why_write_blocked(), None);
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx))
+                .unwrap();
+        }
+
+        assert_eq!(
+            store.why_write_blocked(),
+            Some(WriteBlockReason::SoftLimitReached(3, 3))
+        );
+        assert_eq!(
+            store.add_record(&KeyImage::from(4), &data(4, 4)),
+            Err(AddRecordsError::SoftLimitReached(3, 3))
+        );
+
+        assert!(store.remove_record(&KeyImage::from(1)).unwrap());
+        assert_eq!(store.why_write_blocked(), None);
+        store.add_record(&KeyImage::from(4), &data(4, 4)).unwrap();
+    }
+
+    // Treats the first byte of a key image as a version prefix to be ignored
+    fn strip_version_prefix(key_image: &KeyImage) -> [u8; 32] {
+        let mut bytes = compressed_bytes(key_image);
+        bytes[0] = 0;
+        bytes
+    }
+
+    // Test that a custom canonicalizer is applied in both writes and lookups,
+    // so that key images with the same canonical bytes collide intentionally
+    #[test_with_logger]
+    fn test_canonicalizer(logger: Logger) {
+        let mut v1 = [5u8; 32];
+        v1[0] = 1;
+        let mut v2 = v1;
+        v2[0] = 2;
+        let mut other = v1;
+        other[1] = 6;
+        let (v1, v2, other) = (
+            KeyImage::from(v1),
+            KeyImage::from(v2),
+            KeyImage::from(other),
+        );
+
+        let mut store = KeyImageStoreBuilder::new(512, logger.clone())
+            .maintenance_index()
+            .canonicalizer(strip_version_prefix)
+            .build::<HeapORAMStorageCreator>();
+        store.add_record(&v1, &data(3, 30)).unwrap();
+        assert_eq!(
+            store.find_record_unchecked(&v2),
+            (data(3, 30), KeyImageResultCode::Spent as u32)
+        );
+        assert_eq!(
+            store.find_record_unchecked(&other).1,
+            KeyImageResultCode::NotSpent as u32
+        );
+
+        store.add_record(&v2, &data(4, 40)).unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.find_record_unchecked(&v1).0, data(4, 40));
+
+        // The aliases are tracked once, as v2, so maintenance operations see
+        // a single record
+        assert_eq!(store.tracked_keys.len(), 1);
+        assert_eq!(
+            store
+                .export_records(&crate::record_serde::SerialRecordSerde)
+                .unwrap()
+                .len(),
+            1
+        );
+        let (snapshot, _) = store.snapshot().unwrap();
+        let mut restored = test_store(512, logger.clone());
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored.find_record_unchecked(&v2).0, data(4, 40));
+        let mut single = KeyImageStoreBuilder::new(512, logger.clone())
+            .maintenance_index()
+            .canonicalizer(strip_version_prefix)
+            .build::<HeapORAMStorageCreator>();
+        single.add_record(&v2, &data(4, 40)).unwrap();
+        assert_eq!(store.commitment().unwrap(), single.commitment().unwrap());
+
+        assert!(store.remove_record(&v1).unwrap());
+        assert!(store.tracked_keys.is_empty());
+        assert!(store.keys_by_block.is_empty());
+
+        // The default canonicalizer keeps them apart
+        let mut store = indexed_test_store(512, logger);
+        store.add_record(&v1, &data(3, 30)).unwrap();
+        assert_eq!(
+            store.find_record_unchecked(&v2).1,
+            KeyImageResultCode::NotSpent as u32
+        );
+    }
+
+    // Test that after a rehash every record is still found, records written
+    // afterwards are found too, and every slot assignment has changed
+    #[test_with_logger]
+    fn test_rehash(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+
+        let key_images: Vec<KeyImage> = (1..=50u64).map(KeyImage::from).collect();
+        for (idx, key_image) in key_images.iter().enumerate() {
+            let idx = idx as u64;
+            store.add_record(key_image, &data(idx, idx * 10)).unwrap();
+        }
+
+        let old_keys: Vec<_> = key_images
+            .iter()
+            .map(|key_image| store.omap_key(key_image))
+            .collect();
+
+        store.rehash([7u8; 32]).unwrap();
+        assert_eq!(store.len(), 50);
+
+        for (idx, key_image) in key_images.iter().enumerate() {
+            let idx = idx as u64;
+            let (found, result_code) = store.find_record_unchecked(key_image);
+            assert_eq!(result_code, KeyImageResultCode::Spent as u32);
+            assert_eq!(found, data(idx, idx * 10));
+
+            let new_key = store.omap_key(key_image);
+            assert_ne!(new_key[..], old_keys[idx as usize][..]);
+        }
+
+        let (_, result_code) = store.find_record_unchecked(&KeyImage::from(51));
+        assert_eq!(result_code, KeyImageResultCode::NotSpent as u32);
+
+        store
+            .add_record(&KeyImage::from(51), &data(51, 510))
+            .unwrap();
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(51)).0,
+            data(51, 510)
+        );
+    }
+
+    // Test that a store using the wide block index layout works end to end
+    #[test_with_logger]
+    fn test_wide_block_index_store(logger: Logger) {
+        let codec = ValueCodec::new(BlockIndexWidth::U128);
+        let mut store: KeyImageStore<HeapORAMStorageCreator> =
+            KeyImageStoreBuilder::new(512, logger)
+                .value_codec(codec)
+                .build();
+        assert_eq!(store.value_codec(), &codec);
+
+        store
+            .add_record(&KeyImage::from(1), &data(u64::MAX, 99))
+            .unwrap();
+        assert_eq!(
+            store.find_record_unchecked(&KeyImage::from(1)).0,
+            data(u64::MAX, 99)
+        );
+    }
+
+    static FAKE_CLOCK_NANOS: AtomicU64 = AtomicU64::new(0);
+    static FAKE_CLOCK_STEP_NANOS: AtomicU64 = AtomicU64::new(1_000);
+
+    // A time source which moves forwards by the current step on every read,
+    // so each operation appears to take exactly one step
+    fn stepping_now() -> Duration {
+        let step = FAKE_CLOCK_STEP_NANOS.load(Ordering::SeqCst);
+        Duration::from_nanos(FAKE_CLOCK_NANOS.fetch_add(step, Ordering::SeqCst))
+    }
+
+    // Test that an artificially slow operation trips the threshold alert, and
+    // that the max observed latency reflects it
+    #[test_with_logger]
+    fn test_slow_operation_threshold(logger: Logger) {
+        let mut store: KeyImageStore<HeapORAMStorageCreator> =
+            KeyImageStoreBuilder::new(512, logger)
+                .time_source(stepping_now)
+                .slow_operation_threshold(Duration::from_millis(100))
+                .build();
+
+        store.add_record(&KeyImage::from(1), &data(1, 1)).unwrap();
+        store.find_record_unchecked(&KeyImage::from(1));
+        assert_eq!(store.metrics().slow_operations, 0);
+        assert_eq!(store.max_observed_latency(), Duration::from_micros(1));
+
+        FAKE_CLOCK_STEP_NANOS.store(2_000_000_000, Ordering::SeqCst);
+        store.find_record_unchecked(&KeyImage::from(2));
+        FAKE_CLOCK_STEP_NANOS.store(1_000, Ordering::SeqCst);
+
+        assert_eq!(store.metrics().slow_operations, 1);
+        assert_eq!(store.max_observed_latency(), Duration::from_secs(2));
+
+        store.find_record_unchecked(&KeyImage::from(1));
+        assert_eq!(store.metrics().slow_operations, 1);
+        assert_eq!(store.metrics().latency.count(), 4);
+        assert_eq!(store.metrics().queries, 3);
+        assert_eq!(store.metrics().records_added, 1);
+    }
+}
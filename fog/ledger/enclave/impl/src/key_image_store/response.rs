@@ -0,0 +1,178 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Serialization of batch lookup results straight into response bytes.
+//!
+//! The host would otherwise receive decoded results from the store and
+//! serialize them itself. Producing the wire bytes here means the decoded
+//! results never leave the enclave's lookup path.
+
+use super::{KeyImageData, KeyImageStore, StorageDataSize, StorageMetaSize};
+use crate::error::KeyImageStoreError;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use fog_types::ledger::{CheckKeyImagesResponse, KeyImageResult};
+use mc_oblivious_traits::ORAMStorageCreator;
+use mc_transaction_core::ring_signature::KeyImage;
+
+/// The size of the count which starts a compact response
+const COMPACT_HEADER_SIZE: usize = 4;
+
+/// The size of each result in a compact response: the 32 key image bytes,
+/// the little-endian block index and timestamp, and the little-endian result
+/// code
+pub const COMPACT_RESULT_SIZE: usize = 52;
+
+/// The encoding of a batch lookup response
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResponseFormat {
+    /// A protobuf CheckKeyImagesResponse, as sent to clients.
+    ///
+    /// Only the results are set. The host can append the num_blocks and
+    /// global_txo_count fields to the bytes, since concatenated protobuf
+    /// messages merge. The store knows nothing about timestamp availability,
+    /// so timestamp_result_code is left zero. Protobuf omits zero fields, so
+    /// the length of the response depends on the results.
+    Protobuf,
+    /// A little-endian u32 count of results, followed by that many results
+    /// of COMPACT_RESULT_SIZE bytes each. The length of the response only
+    /// depends on the number of key images.
+    Compact,
+}
+
+impl ResponseFormat {
+    /// Decode results from a response in this format, as (key image, data,
+    /// result code) triples in batch order
+    pub fn decode_results(
+        &self,
+        bytes: &[u8],
+    ) -> Result<Vec<(KeyImage, KeyImageData, u32)>, KeyImageStoreError> {
+        match self {
+            ResponseFormat::Protobuf => {
+                let response: CheckKeyImagesResponse = mc_util_serial::decode(bytes)?;
+                Ok(response
+                    .results
+                    .into_iter()
+                    .map(|result| {
+                        let data = KeyImageData {
+                            block_index: result.spent_at,
+                            timestamp: result.timestamp,
+                        };
+                        (result.key_image, data, result.key_image_result_code)
+                    })
+                    .collect())
+            }
+            ResponseFormat::Compact => {
+                if bytes.len() < COMPACT_HEADER_SIZE {
+                    return Err(KeyImageStoreError::Serialization);
+                }
+                let count =
+                    u32::from_le_bytes(bytes[0..COMPACT_HEADER_SIZE].try_into().unwrap()) as usize;
+                let body = &bytes[COMPACT_HEADER_SIZE..];
+                if body.len() != count * COMPACT_RESULT_SIZE {
+                    return Err(KeyImageStoreError::Serialization);
+                }
+                Ok(body
+                    .chunks_exact(COMPACT_RESULT_SIZE)
+                    .map(|chunk| {
+                        let key_image_bytes: [u8; 32] = chunk[0..32].try_into().unwrap();
+                        let data = KeyImageData {
+                            block_index: u64::from_le_bytes(chunk[32..40].try_into().unwrap()),
+                            timestamp: u64::from_le_bytes(chunk[40..48].try_into().unwrap()),
+                        };
+                        let result_code = u32::from_le_bytes(chunk[48..52].try_into().unwrap());
+                        (KeyImage::from(key_image_bytes), data, result_code)
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+impl<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> KeyImageStore<OSC> {
+    /// Look up a batch of key images as `find_records` does, and serialize
+    /// the results directly into response bytes in the given format.
+    ///
+    /// Results are written in batch order, one per key image. In the compact
+    /// format every result is written at a fixed offset as it is computed.
+    pub fn find_records_serialized(
+        &mut self,
+        key_images: &[KeyImage],
+        pad_to: usize,
+        format: ResponseFormat,
+    ) -> Vec<u8> {
+        match format {
+            ResponseFormat::Protobuf => {
+                let mut results = Vec::with_capacity(key_images.len());
+                self.find_records_streaming(key_images, pad_to, |idx, (data, result_code)| {
+                    results.push(KeyImageResult {
+                        key_image: key_images[idx],
+                        spent_at: data.block_index,
+                        timestamp: data.timestamp,
+                        timestamp_result_code: 0,
+                        key_image_result_code: result_code,
+                    })
+                });
+                mc_util_serial::encode(&CheckKeyImagesResponse {
+                    num_blocks: 0,
+                    global_txo_count: 0,
+                    results,
+                })
+            }
+            ResponseFormat::Compact => {
+                let mut bytes = Vec::with_capacity(
+                    COMPACT_HEADER_SIZE + key_images.len() * COMPACT_RESULT_SIZE,
+                );
+                bytes.extend_from_slice(&(key_images.len() as u32).to_le_bytes());
+                self.find_records_streaming(key_images, pad_to, |idx, (data, result_code)| {
+                    bytes.extend_from_slice(key_images[idx].as_ref());
+                    bytes.extend_from_slice(&data.block_index.to_le_bytes());
+                    bytes.extend_from_slice(&data.timestamp.to_le_bytes());
+                    bytes.extend_from_slice(&result_code.to_le_bytes());
+                });
+                bytes
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_image_store::test_utils::{data, test_store};
+    use mc_common::logger::{test_with_logger, Logger};
+
+    // Test that serialized responses decode back into the results which
+    // find_records gives, in both formats
+    #[test_with_logger]
+    fn test_find_records_serialized_round_trip(logger: Logger) {
+        let mut store = test_store(512, logger);
+        for idx in 1..=4 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+
+        let key_images: Vec<KeyImage> = (3..=6).map(KeyImage::from).collect();
+        let expected: Vec<(KeyImage, KeyImageData, u32)> = key_images
+            .iter()
+            .zip(store.find_records(&key_images, 8))
+            .map(|(key_image, (data, result_code))| (*key_image, data, result_code))
+            .collect();
+
+        for format in [ResponseFormat::Protobuf, ResponseFormat::Compact].iter() {
+            let bytes = store.find_records_serialized(&key_images, 8, *format);
+            assert_eq!(format.decode_results(&bytes).unwrap(), expected);
+        }
+
+        // The compact format is fixed-size, and rejects truncated input
+        let bytes = store.find_records_serialized(&key_images, 8, ResponseFormat::Compact);
+        assert_eq!(
+            bytes.len(),
+            COMPACT_HEADER_SIZE + key_images.len() * COMPACT_RESULT_SIZE
+        );
+        assert_eq!(
+            ResponseFormat::Compact.decode_results(&bytes[..bytes.len() - 1]),
+            Err(KeyImageStoreError::Serialization)
+        );
+    }
+}
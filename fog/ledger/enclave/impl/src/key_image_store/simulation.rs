@@ -0,0 +1,131 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Capacity planning by simulated fills.
+//!
+//! The point at which an oblivious map overflows depends on how evenly its
+//! cuckoo hashing spreads records, so it is below the nominal capacity and
+//! varies from map to map. `simulate_fill` measures it directly, by filling a
+//! fresh map of the given shape until a write overflows. This is a planning
+//! tool and never touches a serving store.
+
+use super::{KeyImageStore, StorageDataSize, StorageMetaSize};
+use fog_ledger_enclave_api::AddRecordsError;
+use mc_crypto_rand::McRng;
+use mc_oblivious_traits::{ORAMStorageCreator, ObliviousHashMap};
+use rand_core::RngCore;
+
+/// The key images inserted by a simulated fill
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InsertPattern {
+    /// Canonical key image bytes counting up from zero, like a test ledger
+    Sequential,
+    /// Uniformly random canonical key image bytes, like a real ledger
+    Random,
+}
+
+/// The outcome of a simulated fill
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SimReport {
+    /// The capacity of the simulated oblivious map
+    pub capacity: u64,
+    /// The number of inserts which succeeded before the first overflow, or
+    /// None if the map was filled to capacity without overflowing
+    pub overflow_after: Option<u64>,
+}
+
+impl SimReport {
+    /// The fraction of the capacity which was filled when the map overflowed
+    pub fn overflow_load_factor(&self) -> Option<f64> {
+        self.overflow_after
+            .map(|inserts| inserts as f64 / self.capacity as f64)
+    }
+}
+
+impl<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> KeyImageStore<OSC> {
+    /// Fill a fresh oblivious map with the given desired capacity and stash
+    /// size, and report how many inserts it took before one overflowed.
+    ///
+    /// Use a heap-backed OSC, since the map is only needed for the duration
+    /// of the simulation.
+    pub fn simulate_fill(
+        desired_capacity: u64,
+        stash_size: usize,
+        insert_pattern: InsertPattern,
+    ) -> SimReport {
+        let mut omap = Self::create_omap_with_stash(desired_capacity, stash_size);
+        let capacity = omap.capacity();
+        let mut rng = McRng::default();
+        let mut hash_seed = [0u8; 32];
+        rng.fill_bytes(&mut hash_seed);
+
+        let mut overflow_after = None;
+        let value = Default::default();
+        for idx in 0..capacity {
+            let mut canonical_key_image = [0u8; 32];
+            match insert_pattern {
+                InsertPattern::Sequential => {
+                    canonical_key_image[0..8].copy_from_slice(&idx.to_le_bytes())
+                }
+                InsertPattern::Random => rng.fill_bytes(&mut canonical_key_image),
+            }
+            let key = Self::derive_key(&hash_seed, &canonical_key_image);
+            match Self::write_value(&mut omap, &key, &value) {
+                Err(AddRecordsError::MapOverflow(_, _)) => {
+                    overflow_after = Some(idx);
+                    break;
+                }
+                result => result.expect("simulated insert failed"),
+            }
+        }
+
+        SimReport {
+            capacity,
+            overflow_after,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_image_store::{
+        test_utils::{test_store, TestStore},
+        KeyImageData, STASH_SIZE,
+    };
+    use mc_common::logger::{test_with_logger, Logger};
+    use mc_transaction_core::ring_signature::KeyImage;
+
+    // Test that the simulated overflow point is close to the point at which
+    // a real store of the same shape overflows
+    #[test_with_logger]
+    fn test_simulate_fill(logger: Logger) {
+        let mut store = test_store(4096, logger);
+        let mut actual = None;
+        for idx in 0..store.capacity() {
+            let data = KeyImageData {
+                block_index: idx,
+                timestamp: 0,
+            };
+            if let Err(err) = store.add_record(&KeyImage::from(idx + 1), &data) {
+                assert!(matches!(err, AddRecordsError::MapOverflow(_, _)));
+                actual = Some(idx);
+                break;
+            }
+        }
+        let actual = actual.expect("store should overflow before capacity");
+
+        let tolerance = store.capacity() / 8;
+        for pattern in &[InsertPattern::Sequential, InsertPattern::Random] {
+            let report = TestStore::simulate_fill(4096, STASH_SIZE, *pattern);
+            assert_eq!(report.capacity, store.capacity());
+            let simulated = report.overflow_after.expect("simulation should overflow");
+            assert!(
+                (simulated as i64 - actual as i64).abs() as u64 <= tolerance,
+                "simulated {} vs actual {}",
+                simulated,
+                actual
+            );
+            assert!(report.overflow_load_factor().unwrap() < 1.0);
+        }
+    }
+}
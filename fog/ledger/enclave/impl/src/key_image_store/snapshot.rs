@@ -0,0 +1,430 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Snapshots of a KeyImageStore, and deltas between them.
+//!
+//! A full snapshot captures every record in the store. A delta captures only
+//! the records written and removed since a prior snapshot, so that backups
+//! can be taken often without serializing the whole store each time.
+//!
+//! Deltas replay removals from tombstones, which the store keeps for as long
+//! as a snapshot they may be based on is outstanding. Release each snapshot
+//! with `release_snapshot` once no more deltas will be taken from it.
+//!
+//! Snapshots only contain public ledger data, but callers which move them out
+//! of the enclave are expected to seal them like any other enclave state.
+
+use super::{KeyImageData, KeyImageStore, StorageDataSize, StorageMetaSize};
+use crate::{error::KeyImageStoreError, metrics::KeyImageStoreMetrics};
+use alloc::vec::Vec;
+use mc_oblivious_traits::{ORAMStorageCreator, ObliviousHashMap};
+use mc_transaction_core::ring_signature::KeyImage;
+use serde::{Deserialize, Serialize};
+
+/// Identifies the state of a store at the time a snapshot was taken
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SnapshotInfo {
+    /// The store generation which the snapshot captures
+    pub generation: u64,
+}
+
+/// The serialized contents of a snapshot or a delta
+#[derive(Deserialize, Serialize)]
+struct SnapshotContents {
+    /// The generation a delta applies on top of, or None for a full snapshot
+    base_generation: Option<u64>,
+    /// The generation captured
+    generation: u64,
+    /// Records which were written, and whether each is provisional
+    records: Vec<(KeyImage, KeyImageData, bool)>,
+    /// Key images which were removed
+    removed: Vec<KeyImage>,
+    /// The store's metrics, if it was configured to snapshot them
+    metrics: Option<KeyImageStoreMetrics>,
+}
+
+impl<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> KeyImageStore<OSC> {
+    /// Describe the current state of the store, as a snapshot would
+    pub fn snapshot_info(&self) -> SnapshotInfo {
+        SnapshotInfo {
+            generation: self.generation,
+        }
+    }
+
+    /// Take a full snapshot of the store.
+    ///
+    /// The records are found through the maintenance index, so this fails
+    /// with MaintenanceIndexDisabled if the store was built without it. The
+    /// snapshot is outstanding until it is passed to `release_snapshot`.
+    pub fn snapshot(&mut self) -> Result<(Vec<u8>, SnapshotInfo), KeyImageStoreError> {
+        self.require_maintenance_index()?;
+        let contents = SnapshotContents {
+            base_generation: None,
+            generation: self.generation,
+            records: self.records_written_since(0),
+            removed: Vec::new(),
+            metrics: self.metrics_for_snapshot(),
+        };
+        let snapshot = mc_util_serial::serialize(&contents)?;
+        self.pin_generation(self.generation);
+        Ok((snapshot, self.snapshot_info()))
+    }
+
+    /// Take a snapshot of only the records written and removed since a prior
+    /// snapshot of this store. Fails with SnapshotBaseCompacted if the
+    /// tombstones needed since then have been dropped.
+    pub fn snapshot_delta(
+        &mut self,
+        since: &SnapshotInfo,
+    ) -> Result<(Vec<u8>, SnapshotInfo), KeyImageStoreError> {
+        self.require_maintenance_index()?;
+        if since.generation > self.generation {
+            return Err(KeyImageStoreError::SnapshotBaseMismatch(
+                since.generation,
+                self.generation,
+            ));
+        }
+        if since.generation < self.tombstone_floor {
+            return Err(KeyImageStoreError::SnapshotBaseCompacted(
+                since.generation,
+                self.tombstone_floor,
+            ));
+        }
+
+        let removed = self
+            .removed_keys
+            .values()
+            .filter(|(_, generation)| *generation > since.generation)
+            .map(|(key_image, _)| *key_image)
+            .collect();
+        let contents = SnapshotContents {
+            base_generation: Some(since.generation),
+            generation: self.generation,
+            records: self.records_written_since(since.generation),
+            removed,
+            metrics: self.metrics_for_snapshot(),
+        };
+        let delta = mc_util_serial::serialize(&contents)?;
+        self.pin_generation(self.generation);
+        Ok((delta, self.snapshot_info()))
+    }
+
+    /// Note that no more deltas will be taken from a snapshot or delta of
+    /// this store, so that the tombstones kept for it can be dropped.
+    ///
+    /// Release each SnapshotInfo returned by `snapshot` or `snapshot_delta`
+    /// exactly once. Releasing one which is not outstanding does nothing.
+    pub fn release_snapshot(&mut self, info: &SnapshotInfo) {
+        self.unpin_generation(info.generation);
+    }
+
+    /// Replace the contents of the store with a full snapshot.
+    ///
+    /// A new oblivious map is built and swapped in once it is complete, so
+    /// if this fails the store is left unchanged. Snapshots taken before the
+    /// restore are no longer outstanding, and need not be released.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<SnapshotInfo, KeyImageStoreError> {
+        let contents: SnapshotContents = mc_util_serial::deserialize(snapshot)?;
+        if contents.base_generation.is_some() {
+            return Err(KeyImageStoreError::WrongSnapshotKind);
+        }
+
+        let mut new_omap = Self::create_omap(self.desired_capacity);
+        for (key_image, data, provisional) in contents.records.iter() {
+            let key = self.omap_key(key_image);
+            Self::write_value(&mut new_omap, &key, &self.encode_value(data, *provisional))?;
+        }
+
+        if self.canary {
+            Self::write_canary(&mut new_omap, &self.hash_seed)?;
+        }
+        self.omap = new_omap;
+        self.canary_in_map = self.canary;
+        self.omap_epoch += 1;
+        self.tracked_keys.clear();
+        self.keys_by_block.clear();
+        self.removed_keys.clear();
+        self.pinned_generations.clear();
+        self.tombstone_floor = contents.generation;
+        self.provisional_keys.clear();
+        for (key_image, data, provisional) in contents.records.iter() {
+            self.track_key(key_image, data.block_index, contents.generation);
+            if *provisional {
+                self.track_provisional(key_image, data.block_index);
+            }
+        }
+        self.generation = contents.generation;
+        // Generations only advance when records change, which takes ingest
+        self.ingested = contents.generation > 0;
+        if let Some(metrics) = contents.metrics {
+            self.metrics = metrics;
+        }
+        Ok(self.snapshot_info())
+    }
+
+    /// Bring a store up to date by applying a delta on top of the snapshot
+    /// it was taken relative to.
+    ///
+    /// The store must be at exactly the generation the delta is based on. If
+    /// this fails partway through, the store should be restored again.
+    pub fn apply_delta(&mut self, delta: &[u8]) -> Result<SnapshotInfo, KeyImageStoreError> {
+        let contents: SnapshotContents = mc_util_serial::deserialize(delta)?;
+        let base_generation = contents
+            .base_generation
+            .ok_or(KeyImageStoreError::WrongSnapshotKind)?;
+        if base_generation != self.generation {
+            return Err(KeyImageStoreError::SnapshotBaseMismatch(
+                base_generation,
+                self.generation,
+            ));
+        }
+
+        for (key_image, data, provisional) in contents.records.iter() {
+            let key = self.omap_key(key_image);
+            let value = self.encode_value(data, *provisional);
+            Self::write_value(&mut self.omap, &key, &value)?;
+            self.track_key(key_image, data.block_index, contents.generation);
+            if *provisional {
+                self.track_provisional(key_image, data.block_index);
+            }
+        }
+        for key_image in contents.removed.iter() {
+            let key = self.omap_key(key_image);
+            self.omap.remove(&key);
+            self.untrack_key(key_image, contents.generation);
+        }
+
+        self.generation = contents.generation;
+        self.ingested |= contents.generation > 0;
+        if let Some(metrics) = contents.metrics {
+            self.metrics = metrics;
+        }
+        Ok(self.snapshot_info())
+    }
+
+    /// The metrics to include in a snapshot, if the store is configured to
+    fn metrics_for_snapshot(&self) -> Option<KeyImageStoreMetrics> {
+        if self.snapshot_metrics {
+            Some(self.metrics.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Read the records which were last written after a given generation
+    fn records_written_since(&mut self, generation: u64) -> Vec<(KeyImage, KeyImageData, bool)> {
+        let key_images: Vec<KeyImage> = self
+            .tracked_keys
+            .values()
+            .filter(|tracked| tracked.generation > generation)
+            .map(|tracked| tracked.key_image)
+            .collect();
+        key_images
+            .into_iter()
+            .filter_map(|key_image| {
+                self.read_tracked(&key_image).map(|value| {
+                    (
+                        key_image,
+                        self.codec.decode(&value),
+                        bool::from(self.codec.is_provisional(&value)),
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_image_store::{
+        test_utils::{data, indexed_test_store, test_store, TestStore},
+        KeyImageStoreBuilder,
+    };
+    use fog_ledger_enclave_api::KeyImageResultCode;
+    use mc_common::logger::{test_with_logger, Logger};
+
+    // Test that a full snapshot restores into a fresh store
+    #[test_with_logger]
+    fn test_snapshot_restore(logger: Logger) {
+        let mut store = indexed_test_store(512, logger.clone());
+        for idx in 1..=20u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        store
+            .add_provisional_record(&KeyImage::from(21), &data(21, 210))
+            .unwrap();
+        let (snapshot, info) = store.snapshot().unwrap();
+
+        let mut restored = indexed_test_store(512, logger);
+        assert_eq!(restored.restore(&snapshot).unwrap(), info);
+        assert_eq!(restored.len(), 21);
+        for idx in 1..=20u64 {
+            assert_eq!(
+                restored.find_record_unchecked(&KeyImage::from(idx)),
+                (data(idx, idx * 10), KeyImageResultCode::Spent as u32)
+            );
+        }
+
+        // Provisional records stay provisional, and can still be finalized
+        assert_eq!(
+            restored.find_record_unchecked(&KeyImage::from(21)),
+            (data(21, 210), KeyImageResultCode::ProvisionallySpent as u32)
+        );
+        assert_eq!(restored.finalize_block(21).unwrap(), 1);
+        assert_eq!(
+            restored.find_record_unchecked(&KeyImage::from(21)).1,
+            KeyImageResultCode::Spent as u32
+        );
+    }
+
+    // Test that restoring the base snapshot and applying a delta reproduces
+    // the full contents of the store, including removals
+    #[test_with_logger]
+    fn test_snapshot_delta(logger: Logger) {
+        let mut store = indexed_test_store(512, logger.clone());
+        for idx in 1..=10u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        let (base, base_info) = store.snapshot().unwrap();
+
+        for idx in 11..=15u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        assert!(store.remove_record(&KeyImage::from(3)).unwrap());
+        assert!(store.remove_record(&KeyImage::from(12)).unwrap());
+        let (delta, delta_info) = store.snapshot_delta(&base_info).unwrap();
+
+        let mut restored = test_store(512, logger);
+        restored.restore(&base).unwrap();
+        assert_eq!(restored.apply_delta(&delta).unwrap(), delta_info);
+
+        assert_eq!(restored.len(), store.len());
+        assert_eq!(restored.len(), 13);
+        for idx in 1..=15u64 {
+            let expected = store.find_record_unchecked(&KeyImage::from(idx));
+            assert_eq!(
+                restored.find_record_unchecked(&KeyImage::from(idx)),
+                expected
+            );
+        }
+        assert_eq!(
+            restored.find_record_unchecked(&KeyImage::from(3)).1,
+            KeyImageResultCode::NotSpent as u32
+        );
+    }
+
+    // Test that metrics survive a snapshot and restore when enabled, and are
+    // otherwise left as they were
+    #[test_with_logger]
+    fn test_snapshot_metrics(logger: Logger) {
+        let mut store: TestStore = KeyImageStoreBuilder::new(512, logger.clone())
+            .maintenance_index()
+            .snapshot_metrics()
+            .build();
+        for idx in 1..=5u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        store.find_records(&[KeyImage::from(1), KeyImage::from(9)], 4);
+        let (snapshot, _) = store.snapshot().unwrap();
+
+        let mut restored = test_store(512, logger.clone());
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.metrics(), store.metrics());
+        assert_eq!(restored.metrics().records_added, 5);
+        assert_eq!(restored.metrics().queries, 2);
+
+        // Counting carries on from the restored totals
+        restored.find_record_unchecked(&KeyImage::from(2));
+        assert_eq!(restored.metrics().queries, 3);
+
+        // Without the option, restoring leaves the metrics alone
+        let mut plain = indexed_test_store(512, logger.clone());
+        plain.add_record(&KeyImage::from(1), &data(1, 10)).unwrap();
+        let (snapshot, _) = plain.snapshot().unwrap();
+        let mut restored = test_store(512, logger);
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.metrics().records_added, 0);
+    }
+
+    // Test that a delta can only be taken from a released snapshot until a
+    // removal drops the tombstones it would need
+    #[test_with_logger]
+    fn test_snapshot_delta_after_release(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+        for idx in 1..=5u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        let (_, base_info) = store.snapshot().unwrap();
+        store.release_snapshot(&base_info);
+        store.add_record(&KeyImage::from(6), &data(6, 60)).unwrap();
+        let (_, delta_info) = store.snapshot_delta(&base_info).unwrap();
+        store.release_snapshot(&delta_info);
+
+        assert!(store.remove_record(&KeyImage::from(2)).unwrap());
+        assert!(store.removed_keys.is_empty());
+        assert_eq!(
+            store.snapshot_delta(&base_info).err(),
+            Some(KeyImageStoreError::SnapshotBaseCompacted(5, 7))
+        );
+        let info = store.snapshot_info();
+        assert!(store.snapshot_delta(&info).is_ok());
+    }
+
+    // Test that restoring drops the snapshots outstanding before it, so that
+    // later removals keep no tombstones for them
+    #[test_with_logger]
+    fn test_restore_unpins_snapshots(logger: Logger) {
+        let mut store = indexed_test_store(512, logger);
+        for idx in 1..=5u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+        let (snapshot, _) = store.snapshot().unwrap();
+        let (_, _unreleased) = store.snapshot().unwrap();
+        assert!(!store.pinned_generations.is_empty());
+
+        store.restore(&snapshot).unwrap();
+        assert!(store.pinned_generations.is_empty());
+        assert!(store.remove_record(&KeyImage::from(2)).unwrap());
+        assert!(store.removed_keys.is_empty());
+    }
+
+    // Test that a delta is rejected by a store which is not at its base
+    #[test_with_logger]
+    fn test_snapshot_delta_base_mismatch(logger: Logger) {
+        let mut store = indexed_test_store(512, logger.clone());
+        store.add_record(&KeyImage::from(1), &data(1, 10)).unwrap();
+        let (base, base_info) = store.snapshot().unwrap();
+        store.add_record(&KeyImage::from(2), &data(2, 20)).unwrap();
+        let (delta, _) = store.snapshot_delta(&base_info).unwrap();
+
+        let mut fresh = test_store(512, logger.clone());
+        assert_eq!(
+            fresh.apply_delta(&delta),
+            Err(KeyImageStoreError::SnapshotBaseMismatch(1, 0))
+        );
+        assert_eq!(
+            fresh.restore(&delta),
+            Err(KeyImageStoreError::WrongSnapshotKind)
+        );
+
+        let mut restored = test_store(512, logger);
+        restored.restore(&base).unwrap();
+        restored.apply_delta(&delta).unwrap();
+        assert_eq!(
+            restored.apply_delta(&delta),
+            Err(KeyImageStoreError::SnapshotBaseMismatch(1, 2))
+        );
+    }
+}
@@ -0,0 +1,32 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Helpers shared by the KeyImageStore tests
+
+use super::{KeyImageData, KeyImageStore, KeyImageStoreBuilder};
+use mc_common::logger::Logger;
+use mc_oblivious_traits::HeapORAMStorageCreator;
+use mc_transaction_core::BlockIndex;
+
+/// The store the tests run against
+pub type TestStore = KeyImageStore<HeapORAMStorageCreator>;
+
+/// A store with the default configuration
+pub fn test_store(desired_capacity: u64, logger: Logger) -> TestStore {
+    KeyImageStoreBuilder::new(desired_capacity, logger).build()
+}
+
+/// A store which keeps the maintenance index, for tests of the operations
+/// which need it
+pub fn indexed_test_store(desired_capacity: u64, logger: Logger) -> TestStore {
+    KeyImageStoreBuilder::new(desired_capacity, logger)
+        .maintenance_index()
+        .build()
+}
+
+/// The data of a record spent in a block, with a timestamp
+pub fn data(block_index: BlockIndex, timestamp: u64) -> KeyImageData {
+    KeyImageData {
+        block_index,
+        timestamp,
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! A summary of the spends among all of a wallet's key images, computed in a
+//! single padded batch.
+
+use super::{KeyImageStore, StorageDataSize, StorageMetaSize};
+use crate::ct::ct_select_u64;
+use aligned_cmov::subtle::{Choice, ConstantTimeEq, ConstantTimeGreater};
+use alloc::{vec, vec::Vec};
+use fog_ledger_enclave_api::KeyImageResultCode;
+use mc_oblivious_traits::ORAMStorageCreator;
+use mc_transaction_core::{ring_signature::KeyImage, BlockIndex};
+
+/// The spends among a wallet's key images
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WalletSpendSummary {
+    /// The number of key images which are spent or provisionally spent
+    pub num_spent: u64,
+    /// The highest spent-at block index among the spent key images, and the
+    /// timestamp of that block, or None if none of them are spent
+    pub latest_spend: Option<(BlockIndex, u64)>,
+    /// Whether each key image is spent, packed 64 to a word as by
+    /// `spent_bitmask`
+    pub spent: Vec<u64>,
+}
+
+impl WalletSpendSummary {
+    /// Whether the key image at the given index in the wallet's batch is
+    /// spent
+    pub fn is_spent(&self, idx: usize) -> bool {
+        (self.spent[idx / 64] >> (idx % 64)) & 1 == 1
+    }
+}
+
+impl<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> KeyImageStore<OSC> {
+    /// Look up all of a wallet's key images in one batch, padded to pad_to
+    /// lookups as `find_records` is, and summarize their spends branchlessly.
+    pub fn wallet_spend_summary(
+        &mut self,
+        key_images: &[KeyImage],
+        pad_to: usize,
+    ) -> WalletSpendSummary {
+        let mut spent = vec![0u64; (key_images.len() + 63) / 64];
+        let mut num_spent = 0u64;
+        let mut latest_block_index = 0u64;
+        let mut latest_timestamp = 0u64;
+        let mut any_found = Choice::from(0);
+
+        self.find_records_streaming(key_images, pad_to, |idx, (data, result_code)| {
+            let found = result_code.ct_eq(&(KeyImageResultCode::Spent as u32))
+                | result_code.ct_eq(&(KeyImageResultCode::ProvisionallySpent as u32));
+            spent[idx / 64] |= (found.unwrap_u8() as u64) << (idx % 64);
+            num_spent += found.unwrap_u8() as u64;
+
+            // Take this record if it is present and either it is the first
+            // present record, or it is later than the best seen so far.
+            let take = found & (!any_found | data.block_index.ct_gt(&latest_block_index));
+            latest_block_index = ct_select_u64(take, data.block_index, latest_block_index);
+            latest_timestamp = ct_select_u64(take, data.timestamp, latest_timestamp);
+            any_found |= found;
+        });
+
+        WalletSpendSummary {
+            num_spent,
+            latest_spend: if bool::from(any_found) {
+                Some((latest_block_index, latest_timestamp))
+            } else {
+                None
+            },
+            spent,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_image_store::test_utils::{data, indexed_test_store};
+    use mc_common::logger::{test_with_logger, Logger};
+
+    // Test the summary of a wallet with a hundred key images, a few of them
+    // spent, one provisionally, among other records in the ledger
+    #[test_with_logger]
+    fn test_wallet_spend_summary(logger: Logger) {
+        let mut store = indexed_test_store(4096, logger);
+        for idx in 1000..1200u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, idx * 10))
+                .unwrap();
+        }
+
+        // The wallet's key images, of which those at 3, 64, 70 and 99 are in
+        // the ledger
+        let wallet: Vec<KeyImage> = (1..=100u64).map(KeyImage::from).collect();
+        for (position, block_index) in [(3usize, 40u64), (64, 95), (70, 120)].iter() {
+            store
+                .add_record(&wallet[*position], &data(*block_index, *block_index * 10))
+                .unwrap();
+        }
+        store
+            .add_provisional_record(&wallet[99], &data(130, 1300))
+            .unwrap();
+
+        let summary = store.wallet_spend_summary(&wallet, 128);
+        assert_eq!(summary.num_spent, 4);
+        assert_eq!(summary.latest_spend, Some((130, 1300)));
+        assert_eq!(summary.spent.len(), 2);
+        for idx in 0..wallet.len() {
+            assert_eq!(
+                summary.is_spent(idx),
+                [3, 64, 70, 99].contains(&idx),
+                "key image {}",
+                idx
+            );
+        }
+        assert_eq!(store.last_batch_padding(), 28);
+        assert_eq!(summary.spent, store.spent_bitmask(&wallet));
+
+        // A wallet with nothing spent
+        let fresh: Vec<KeyImage> = (2000..2010u64).map(KeyImage::from).collect();
+        assert_eq!(
+            store.wallet_spend_summary(&fresh, 0),
+            WalletSpendSummary {
+                num_spent: 0,
+                latest_spend: None,
+                spent: vec![0],
+            }
+        );
+    }
+}
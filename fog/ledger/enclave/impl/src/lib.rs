@@ -12,6 +12,58 @@
 
 extern crate alloc;
 
+mod audit;
+pub use audit::{AuditOp, AuditOutcome, AuditRecord, AuditSink};
+
+pub mod ct;
+
+mod clock;
+pub use clock::{Clock, MockClock, NoClock};
+
+mod error;
+pub use error::{KeyImageStoreError, WriteBlockReason};
+
+mod failover_store;
+pub use failover_store::{FailoverMetrics, FailoverStore};
+
+mod key_image_store;
+pub use key_image_store::{
+    BatchAdmission, BatchProof, CanaryStatus, Canonicalizer, CommittedRecord, DuplicateResolution,
+    GrowStrategy, InsertPattern, KeyImageData, KeyImageStore, KeyImageStoreBuilder, PendingGrow,
+    ResponseFormat, SimReport, SnapshotInfo, StorageDataSize, StorageMetaSize, StoreOp,
+    StoreOpResult, TimeSource, TimestampAnomaly, TimestampAnomalyKind, TimestampOverwritePolicy,
+    WalletSpendSummary, COMPACT_RESULT_SIZE, DEFAULT_OVERFLOW_LOG_INTERVAL,
+};
+
+mod lookup;
+pub use lookup::{logical_differences, KeyImageLookup};
+
+mod metrics;
+pub use metrics::{
+    AccessStats, BlockBucketMetrics, KeyImageStoreMetrics, LatencyByOutcome, LatencyHistogram,
+    DEFAULT_BLOCK_BUCKET_SIZE, NUM_BATCH_SIZE_BUCKETS, NUM_LATENCY_BUCKETS,
+};
+
+mod overflow;
+pub use overflow::{OverflowAction, OverflowHandler, OverflowInfo};
+
+mod record_serde;
+pub use record_serde::{FixedRecordSerde, RecordSerde, SerialRecordSerde, FIXED_RECORD_SIZE};
+
+mod shadow_store;
+pub use shadow_store::{ShadowMetrics, ShadowStore};
+
+mod shared_store;
+pub use shared_store::SharedStore;
+
+mod store_group;
+pub use store_group::StoreGroup;
+
+mod value_codec;
+pub use value_codec::{
+    BlockIndexWidth, ValueCodec, ValueSize, WideBlockIndex, REQUIRED_VALUE_BYTES,
+};
+
 use alloc::vec::Vec;
 use fog_ledger_enclave_api::{KeyImageContext, LedgerEnclave, OutputContext, Result};
 use fog_types::ledger::{
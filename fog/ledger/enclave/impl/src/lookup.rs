@@ -0,0 +1,128 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! A common interface for answering key image queries, so that wrappers like
+//! StoreGroup can combine stores of different types.
+
+use crate::{
+    failover_store::FailoverStore,
+    key_image_store::{KeyImageData, KeyImageStore, StorageDataSize, StorageMetaSize},
+    shadow_store::ShadowStore,
+};
+use alloc::vec::Vec;
+use fog_ledger_enclave_api::KeyImageResultCode;
+use mc_oblivious_traits::ORAMStorageCreator;
+use mc_transaction_core::ring_signature::KeyImage;
+
+/// Something which can look up key images obliviously
+pub trait KeyImageLookup {
+    /// Look up a key image, returning its data and a KeyImageResultCode as a
+    /// u32, as `KeyImageStore::find_record` does
+    fn find_record(&mut self, key_image: &KeyImage) -> (KeyImageData, u32);
+}
+
+/// Look up each of a set of key images in two stores, and return those which
+/// they disagree about.
+///
+/// The stores may be configured differently, e.g. with different value
+/// layouts or ORAM tuning, since only decoded results are compared. They
+/// agree about a key image if they return the same result code, and, if
+/// it was found, the same data. This is a maintenance check, e.g. to
+/// validate a migration, and branches on its results.
+pub fn logical_differences(
+    first: &mut dyn KeyImageLookup,
+    second: &mut dyn KeyImageLookup,
+    key_images: &[KeyImage],
+) -> Vec<KeyImage> {
+    key_images
+        .iter()
+        .filter(|key_image| {
+            let (first_data, first_code) = first.find_record(key_image);
+            let (second_data, second_code) = second.find_record(key_image);
+            let found = first_code == KeyImageResultCode::Spent as u32
+                || first_code == KeyImageResultCode::ProvisionallySpent as u32;
+            first_code != second_code || (found && first_data != second_data)
+        })
+        .cloned()
+        .collect()
+}
+
+impl<OSC> KeyImageLookup for KeyImageStore<OSC>
+where
+    OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>,
+{
+    fn find_record(&mut self, key_image: &KeyImage) -> (KeyImageData, u32) {
+        KeyImageStore::find_record_unchecked(self, key_image)
+    }
+}
+
+impl<P: KeyImageLookup, R: KeyImageLookup> KeyImageLookup for FailoverStore<P, R> {
+    fn find_record(&mut self, key_image: &KeyImage) -> (KeyImageData, u32) {
+        FailoverStore::find_record(self, key_image)
+    }
+}
+
+impl<P, S> KeyImageLookup for ShadowStore<P, S>
+where
+    P: ORAMStorageCreator<StorageDataSize, StorageMetaSize>,
+    S: ORAMStorageCreator<StorageDataSize, StorageMetaSize>,
+{
+    fn find_record(&mut self, key_image: &KeyImage) -> (KeyImageData, u32) {
+        ShadowStore::find_record(self, key_image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        key_image_store::KeyImageStoreBuilder,
+        record_serde::SerialRecordSerde,
+        value_codec::{BlockIndexWidth, ValueCodec},
+    };
+    use alloc::vec;
+    use mc_common::logger::{test_with_logger, Logger};
+    use mc_oblivious_traits::HeapORAMStorageCreator;
+
+    type TestStore = KeyImageStore<HeapORAMStorageCreator>;
+
+    fn data(block_index: u64) -> KeyImageData {
+        KeyImageData {
+            block_index,
+            timestamp: block_index * 10,
+        }
+    }
+
+    // Test that records migrated to a wider value layout compare equal, and
+    // that a divergence afterwards is reported
+    #[test_with_logger]
+    fn test_logical_differences(logger: Logger) {
+        let mut narrow = KeyImageStoreBuilder::new(512, logger.clone())
+            .maintenance_index()
+            .build::<HeapORAMStorageCreator>();
+        for idx in 1..=20u64 {
+            narrow.add_record(&KeyImage::from(idx), &data(idx)).unwrap();
+        }
+
+        let mut wide: TestStore = KeyImageStoreBuilder::new(1024, logger)
+            .maintenance_index()
+            .value_codec(ValueCodec::new(BlockIndexWidth::U128))
+            .miss_sentinel(KeyImageData {
+                block_index: u64::MAX,
+                timestamp: u64::MAX,
+            })
+            .build();
+        let records = narrow.export_records(&SerialRecordSerde).unwrap();
+        wide.import_records(&SerialRecordSerde, &records).unwrap();
+
+        let key_images: Vec<KeyImage> = (1..=25u64).map(KeyImage::from).collect();
+        assert!(logical_differences(&mut narrow, &mut wide, &key_images).is_empty());
+
+        wide.update_block_index(&KeyImage::from(3), 300).unwrap();
+        wide.remove_record(&KeyImage::from(7)).unwrap();
+        wide.add_record(&KeyImage::from(22), &data(22)).unwrap();
+        assert_eq!(
+            logical_differences(&mut narrow, &mut wide, &key_images),
+            vec![KeyImage::from(3), KeyImage::from(7), KeyImage::from(22)]
+        );
+    }
+}
@@ -0,0 +1,331 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Operational metrics for the KeyImageStore.
+//!
+//! Nothing here may depend on the contents of a query, with one exception.
+//! Counters are bumped once per operation regardless of outcome, and
+//! latencies are measured around whole operations, after the oblivious work
+//! has finished. The exception is the split of single key lookup latencies
+//! into hits and misses: each sample is placed in constant time, but the
+//! histograms together reveal how many lookups hit to whoever reads them, so
+//! they must stay with the operator.
+//!
+//! Per-block counters are only kept for records added by ingest, since the
+//! block index in the answer to a query is exactly what it must not reveal.
+
+use crate::{
+    audit::{AuditOp, AuditOutcome},
+    ct::{ct_select_u32, ct_select_u64},
+};
+use aligned_cmov::subtle::Choice;
+use alloc::collections::BTreeMap;
+use core::time::Duration;
+use mc_transaction_core::BlockIndex;
+use serde::{Deserialize, Serialize};
+
+/// The number of buckets in a LatencyHistogram
+pub const NUM_LATENCY_BUCKETS: usize = 32;
+
+/// A histogram of operation latencies, with power-of-two microsecond buckets.
+///
+/// Bucket 0 counts latencies under 1us, and bucket i > 0 counts latencies in
+/// [2^(i-1), 2^i) us. The last bucket also counts everything larger.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LatencyHistogram {
+    /// Sample counts per bucket
+    buckets: [u64; NUM_LATENCY_BUCKETS],
+    /// The total number of samples
+    count: u64,
+    /// The largest sample seen
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    /// Record one latency sample
+    pub fn record(&mut self, latency: Duration) {
+        self.buckets[Self::bucket_for(latency)] += 1;
+        self.count += 1;
+        if latency > self.max {
+            self.max = latency;
+        }
+    }
+
+    /// Record one latency sample if condition is set, and otherwise leave
+    /// the histogram as it was, without branching on condition
+    pub fn record_if(&mut self, condition: Choice, latency: Duration) {
+        let sample = condition.unwrap_u8() as u64;
+        self.buckets[Self::bucket_for(latency)] += sample;
+        self.count += sample;
+        let larger = condition & Choice::from((latency > self.max) as u8);
+        self.max = Duration::new(
+            ct_select_u64(larger, latency.as_secs(), self.max.as_secs()),
+            ct_select_u32(larger, latency.subsec_nanos(), self.max.subsec_nanos()),
+        );
+    }
+
+    /// The sample counts per bucket
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets[..]
+    }
+
+    /// The total number of samples
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The largest sample seen, or zero if there are none
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// The bucket which a latency sample falls in
+    pub fn bucket_for(latency: Duration) -> usize {
+        let micros = latency.as_micros();
+        let bucket = (128 - micros.leading_zeros()) as usize;
+        bucket.min(NUM_LATENCY_BUCKETS - 1)
+    }
+}
+
+/// Latency histograms for each category of operation outcome, with single
+/// key lookups also split into hits and misses
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LatencyByOutcome {
+    /// Lookups, whether or not the key image was found
+    answered: LatencyHistogram,
+    /// Single key lookups which found the key image
+    hits: LatencyHistogram,
+    /// Single key lookups which did not find the key image
+    misses: LatencyHistogram,
+    /// Writes which were made
+    written: LatencyHistogram,
+    /// Writes which were refused, whether by the store before reaching the
+    /// oblivious map, or by the map itself
+    refused: LatencyHistogram,
+}
+
+impl LatencyByOutcome {
+    /// Record one latency sample for an operation with the given outcome
+    pub fn record(&mut self, outcome: AuditOutcome, latency: Duration) {
+        self.get_mut(outcome).record(latency);
+    }
+
+    /// Record one latency sample for a single key lookup, as a hit if found
+    /// is set and as a miss otherwise.
+    ///
+    /// Both histograms are updated, one of them with no sample, so this does
+    /// not branch on found. The sample is recorded as answered separately.
+    pub fn record_hit_or_miss(&mut self, found: Choice, latency: Duration) {
+        self.hits.record_if(found, latency);
+        self.misses.record_if(!found, latency);
+    }
+
+    /// The histogram of single key lookups which found their key image
+    pub fn hits(&self) -> &LatencyHistogram {
+        &self.hits
+    }
+
+    /// The histogram of single key lookups which did not find their key
+    /// image
+    pub fn misses(&self) -> &LatencyHistogram {
+        &self.misses
+    }
+
+    /// The histogram of operations with the given outcome
+    pub fn get(&self, outcome: AuditOutcome) -> &LatencyHistogram {
+        match outcome {
+            AuditOutcome::Answered => &self.answered,
+            AuditOutcome::Written => &self.written,
+            AuditOutcome::Refused => &self.refused,
+        }
+    }
+
+    fn get_mut(&mut self, outcome: AuditOutcome) -> &mut LatencyHistogram {
+        match outcome {
+            AuditOutcome::Answered => &mut self.answered,
+            AuditOutcome::Written => &mut self.written,
+            AuditOutcome::Refused => &mut self.refused,
+        }
+    }
+}
+
+/// The default number of blocks in each bucket of the per-block metrics
+pub const DEFAULT_BLOCK_BUCKET_SIZE: u64 = 10_000;
+
+/// Counters for the records of one range of block indices
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct BlockBucketMetrics {
+    /// The number of records added with a block index in this range
+    pub records_added: u64,
+}
+
+/// The number of buckets in the batch size distribution of AccessStats
+pub const NUM_BATCH_SIZE_BUCKETS: usize = 16;
+
+/// Aggregate statistics about the store's observable access pattern.
+///
+/// Everything here is determined by the kinds of operations the host asked
+/// for, their batch sizes and their pad_to, none of which depend on which key
+/// images were queried or whether they were found. An auditor can compare
+/// these against the oblivious map's traffic to check that the store does no
+/// more and no less than that.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AccessStats {
+    /// The number of single lookups
+    pub lookups: u64,
+    /// The number of batch lookups
+    pub batch_lookups: u64,
+    /// The number of lookups which also removed the record
+    pub takes: u64,
+    /// The number of records added by ingest, including refused adds
+    pub adds: u64,
+    /// The number of records removed by ingest
+    pub removes: u64,
+    /// The number of records corrected by ingest, including refused updates
+    pub updates: u64,
+    /// The number of key images looked up, singly or in batches
+    pub keys_looked_up: u64,
+    /// The number of dummy lookups done to pad batches
+    pub padding_lookups: u64,
+    /// Batch lookups by padded size. Bucket 0 counts empty batches, and
+    /// bucket i > 0 counts batches of [2^(i-1), 2^i) lookups. The last bucket
+    /// also counts everything larger.
+    pub batch_size_buckets: [u64; NUM_BATCH_SIZE_BUCKETS],
+}
+
+impl AccessStats {
+    /// Count one operation of the given kind. Single lookups and takes also
+    /// count the key image they looked up.
+    pub fn record_op(&mut self, op: AuditOp) {
+        match op {
+            AuditOp::Lookup => {
+                self.lookups += 1;
+                self.keys_looked_up += 1;
+            }
+            AuditOp::BatchLookup => self.batch_lookups += 1,
+            AuditOp::Take => {
+                self.takes += 1;
+                self.keys_looked_up += 1;
+            }
+            AuditOp::Add => self.adds += 1,
+            AuditOp::Remove => self.removes += 1,
+            AuditOp::Update => self.updates += 1,
+        }
+    }
+
+    /// Count the lookups of a batch of num_keys key images padded to pad_to
+    pub fn record_batch(&mut self, num_keys: usize, pad_to: usize) {
+        let num_lookups = num_keys.max(pad_to);
+        self.keys_looked_up += num_keys as u64;
+        self.padding_lookups += (num_lookups - num_keys) as u64;
+        self.batch_size_buckets[Self::bucket_for(num_lookups)] += 1;
+    }
+
+    /// The number of oblivious map lookups done for queries, padding
+    /// included
+    pub fn total_lookups(&self) -> u64 {
+        self.keys_looked_up + self.padding_lookups
+    }
+
+    /// The fraction of query lookups which were padding, or zero if there
+    /// were none
+    pub fn padding_ratio(&self) -> f64 {
+        match self.total_lookups() {
+            0 => 0.0,
+            total => self.padding_lookups as f64 / total as f64,
+        }
+    }
+
+    /// The bucket which a batch of the given padded size falls in
+    pub fn bucket_for(num_lookups: usize) -> usize {
+        let bucket = (64 - (num_lookups as u64).leading_zeros()) as usize;
+        bucket.min(NUM_BATCH_SIZE_BUCKETS - 1)
+    }
+}
+
+/// Counters describing the operation of a KeyImageStore
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct KeyImageStoreMetrics {
+    /// The number of successful add_record calls
+    pub records_added: u64,
+    /// The number of lookups performed
+    pub queries: u64,
+    /// Latencies of individual add and lookup operations
+    pub latency: LatencyHistogram,
+    /// The same latencies, split by the category of their outcome
+    pub latency_by_outcome: LatencyByOutcome,
+    /// The number of operations which exceeded the slow operation threshold
+    pub slow_operations: u64,
+    /// The number of warnings logged about overflowing writes
+    pub overflow_warnings_logged: u64,
+    /// The number of warnings about overflowing writes which were suppressed
+    /// by rate limiting
+    pub overflow_warnings_suppressed: u64,
+    /// Counters per range of block indices, keyed by the first block index of
+    /// each range
+    pub by_block_bucket: BTreeMap<BlockIndex, BlockBucketMetrics>,
+    /// Aggregate access pattern statistics
+    pub access: AccessStats,
+}
+
+impl KeyImageStoreMetrics {
+    /// Count a record added in a block, with the given bucket size
+    pub fn record_added(&mut self, block_index: BlockIndex, block_bucket_size: u64) {
+        self.records_added += 1;
+        let bucket_start = block_index - block_index % block_bucket_size;
+        self.by_block_bucket
+            .entry(bucket_start)
+            .or_default()
+            .records_added += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that samples land in the expected buckets
+    #[test]
+    fn test_latency_buckets() {
+        assert_eq!(LatencyHistogram::bucket_for(Duration::from_nanos(999)), 0);
+        assert_eq!(LatencyHistogram::bucket_for(Duration::from_micros(1)), 1);
+        assert_eq!(LatencyHistogram::bucket_for(Duration::from_micros(3)), 2);
+        assert_eq!(LatencyHistogram::bucket_for(Duration::from_micros(4)), 3);
+        assert_eq!(LatencyHistogram::bucket_for(Duration::from_millis(1)), 10);
+        assert_eq!(
+            LatencyHistogram::bucket_for(Duration::from_secs(1_000_000)),
+            NUM_LATENCY_BUCKETS - 1
+        );
+    }
+
+    // Test that the histogram tracks its count and maximum
+    #[test]
+    fn test_latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.max(), Duration::default());
+
+        histogram.record(Duration::from_micros(5));
+        histogram.record(Duration::from_millis(2));
+        histogram.record(Duration::from_micros(6));
+
+        assert_eq!(histogram.count(), 3);
+        assert_eq!(histogram.max(), Duration::from_millis(2));
+        assert_eq!(histogram.buckets()[3], 2);
+        assert_eq!(histogram.buckets()[11], 1);
+        assert_eq!(histogram.buckets().iter().sum::<u64>(), 3);
+    }
+
+    // Test that a conditional sample only lands when its condition is set
+    #[test]
+    fn test_latency_histogram_record_if() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.record_if(Choice::from(0), Duration::from_millis(2));
+        assert_eq!(histogram, LatencyHistogram::default());
+
+        histogram.record_if(Choice::from(1), Duration::from_micros(5));
+        histogram.record_if(Choice::from(0), Duration::from_millis(2));
+        assert_eq!(histogram.count(), 1);
+        assert_eq!(histogram.max(), Duration::from_micros(5));
+        assert_eq!(histogram.buckets()[3], 1);
+        assert_eq!(histogram.buckets().iter().sum::<u64>(), 1);
+    }
+}
@@ -0,0 +1,38 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Pluggable handling of a KeyImageStore running out of capacity.
+//!
+//! When a write overflows the oblivious map, the store asks an overflow
+//! handler what to do. Overflows only happen on ingest writes, which are
+//! public, so the handler may branch freely.
+
+use alloc::boxed::Box;
+
+/// The state of a store when a write overflowed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OverflowInfo {
+    /// The number of records in the oblivious map
+    pub len: u64,
+    /// The real capacity of the oblivious map
+    pub capacity: u64,
+    /// The capacity the oblivious map was created with
+    pub desired_capacity: u64,
+}
+
+/// What a store should do about a write which overflowed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverflowAction {
+    /// Fail the write with AddRecordsError::MapOverflow
+    Reject,
+    /// Mark the store degraded, so that subsequent writes are refused, and
+    /// fail the write
+    Degrade,
+    /// Grow the store to the given desired capacity, then retry the write
+    Grow(u64),
+    /// Grow the store to the desired capacity chosen by its grow strategy,
+    /// then retry the write
+    GrowByStrategy,
+}
+
+/// A callback which decides what to do when a write overflows
+pub type OverflowHandler = Box<dyn Fn(&OverflowInfo) -> OverflowAction + Send + Sync>;
@@ -0,0 +1,120 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! Serialization of individual records, for exporting records from a store
+//! and importing them into another.
+//!
+//! Exports are an interchange format, so the encoding is pluggable: operators
+//! can pick a compact one for moving data, or a fixed, easily inspected one
+//! for debugging.
+
+use crate::{error::KeyImageStoreError, key_image_store::KeyImageData};
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use mc_transaction_core::ring_signature::KeyImage;
+
+/// An encoding of a single (KeyImage, KeyImageData) record
+pub trait RecordSerde {
+    /// Encode a record
+    fn serialize(
+        &self,
+        key_image: &KeyImage,
+        data: &KeyImageData,
+    ) -> Result<Vec<u8>, KeyImageStoreError>;
+
+    /// Decode a record encoded by `serialize`
+    fn deserialize(&self, bytes: &[u8]) -> Result<(KeyImage, KeyImageData), KeyImageStoreError>;
+}
+
+/// Encodes records with mc_util_serial, the encoding used by snapshots
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SerialRecordSerde;
+
+impl RecordSerde for SerialRecordSerde {
+    fn serialize(
+        &self,
+        key_image: &KeyImage,
+        data: &KeyImageData,
+    ) -> Result<Vec<u8>, KeyImageStoreError> {
+        Ok(mc_util_serial::serialize(&(key_image, data))?)
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<(KeyImage, KeyImageData), KeyImageStoreError> {
+        Ok(mc_util_serial::deserialize(bytes)?)
+    }
+}
+
+/// The size of a record encoded by FixedRecordSerde
+pub const FIXED_RECORD_SIZE: usize = 48;
+
+/// Encodes records in a fixed 48-byte layout: the 32 key image bytes, then
+/// the little-endian block index and timestamp. This is easy to inspect in a
+/// hex dump.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FixedRecordSerde;
+
+impl RecordSerde for FixedRecordSerde {
+    fn serialize(
+        &self,
+        key_image: &KeyImage,
+        data: &KeyImageData,
+    ) -> Result<Vec<u8>, KeyImageStoreError> {
+        let mut bytes = Vec::with_capacity(FIXED_RECORD_SIZE);
+        bytes.extend_from_slice(key_image.as_ref());
+        bytes.extend_from_slice(&data.block_index.to_le_bytes());
+        bytes.extend_from_slice(&data.timestamp.to_le_bytes());
+        Ok(bytes)
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<(KeyImage, KeyImageData), KeyImageStoreError> {
+        if bytes.len() != FIXED_RECORD_SIZE {
+            return Err(KeyImageStoreError::Serialization);
+        }
+        let key_image_bytes: [u8; 32] = bytes[0..32].try_into().unwrap();
+        let data = KeyImageData {
+            block_index: u64::from_le_bytes(bytes[32..40].try_into().unwrap()),
+            timestamp: u64::from_le_bytes(bytes[40..48].try_into().unwrap()),
+        };
+        Ok((KeyImage::from(key_image_bytes), data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<S: RecordSerde>(serde: &S) {
+        let key_image = KeyImage::from(77);
+        let data = KeyImageData {
+            block_index: 0x0102_0304_0506_0708,
+            timestamp: 1_600_000_000,
+        };
+        let bytes = serde.serialize(&key_image, &data).unwrap();
+        assert_eq!(serde.deserialize(&bytes).unwrap(), (key_image, data));
+        assert_eq!(
+            serde.deserialize(&[]),
+            Err(KeyImageStoreError::Serialization)
+        );
+    }
+
+    // Test that records round-trip through each encoding
+    #[test]
+    fn test_round_trip() {
+        round_trip(&SerialRecordSerde);
+        round_trip(&FixedRecordSerde);
+    }
+
+    // Test the fixed layout
+    #[test]
+    fn test_fixed_layout() {
+        let key_image = KeyImage::from(5);
+        let data = KeyImageData {
+            block_index: 3,
+            timestamp: 4,
+        };
+        let bytes = FixedRecordSerde.serialize(&key_image, &data).unwrap();
+        assert_eq!(bytes.len(), FIXED_RECORD_SIZE);
+        assert_eq!(&bytes[0..32], key_image.as_ref());
+        assert_eq!(bytes[32], 3);
+        assert_eq!(bytes[40], 4);
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! A wrapper which runs a second KeyImageStore in verification-only shadow
+//! mode alongside the live one.
+//!
+//! This is meant for migrations between store backends or configurations:
+//! the shadow receives the same writes, removals and other ingest operations
+//! as the primary, and every read is answered by both, but only the
+//! primary's answer is ever returned. Any disagreement is counted in the
+//! shadow metrics.
+//!
+//! Each store auto-prunes by its own configuration, so the two only stay in
+//! step if they are configured to prune alike. Maintenance operations which
+//! rebuild a store, such as grow, rehash or restore, are not forwarded: they
+//! are for the caller to run on each store, via `into_inner`.
+
+use crate::key_image_store::{KeyImageData, KeyImageStore, StorageDataSize, StorageMetaSize};
+use aligned_cmov::subtle::ConstantTimeEq;
+use fog_ledger_enclave_api::AddRecordsError;
+use mc_oblivious_traits::ORAMStorageCreator;
+use mc_transaction_core::{ring_signature::KeyImage, BlockIndex};
+
+/// Counters describing how the shadow store compares to the primary
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ShadowMetrics {
+    /// The number of reads which were compared
+    pub reads: u64,
+    /// The number of reads where the shadow disagreed with the primary
+    pub mismatches: u64,
+    /// The number of writes which succeeded on the primary but failed on the
+    /// shadow
+    pub shadow_write_errors: u64,
+}
+
+/// Forwards writes to both a primary and a shadow store, and compares reads
+pub struct ShadowStore<P, S>
+where
+    P: ORAMStorageCreator<StorageDataSize, StorageMetaSize>,
+    S: ORAMStorageCreator<StorageDataSize, StorageMetaSize>,
+{
+    /// The store whose answers are served
+    primary: KeyImageStore<P>,
+    /// The store being verified
+    shadow: KeyImageStore<S>,
+    /// Comparison counters
+    metrics: ShadowMetrics,
+}
+
+impl<P, S> ShadowStore<P, S>
+where
+    P: ORAMStorageCreator<StorageDataSize, StorageMetaSize>,
+    S: ORAMStorageCreator<StorageDataSize, StorageMetaSize>,
+{
+    /// Pair a live store with a shadow store
+    pub fn new(primary: KeyImageStore<P>, shadow: KeyImageStore<S>) -> Self {
+        Self {
+            primary,
+            shadow,
+            metrics: Default::default(),
+        }
+    }
+
+    /// Get the comparison counters
+    pub fn metrics(&self) -> &ShadowMetrics {
+        &self.metrics
+    }
+
+    /// Get the primary store
+    pub fn primary(&self) -> &KeyImageStore<P> {
+        &self.primary
+    }
+
+    /// Get the shadow store
+    pub fn shadow(&self) -> &KeyImageStore<S> {
+        &self.shadow
+    }
+
+    /// Stop shadowing, returning the primary and shadow stores
+    pub fn into_inner(self) -> (KeyImageStore<P>, KeyImageStore<S>) {
+        (self.primary, self.shadow)
+    }
+
+    /// Record that a key image was spent, in both stores.
+    ///
+    /// The primary's result is returned. A failure on the shadow alone is
+    /// counted but does not fail the write.
+    pub fn add_record(
+        &mut self,
+        key_image: &KeyImage,
+        data: &KeyImageData,
+    ) -> Result<(), AddRecordsError> {
+        self.primary.add_record(key_image, data)?;
+        let shadow_result = self.shadow.add_record(key_image, data);
+        self.count_shadow_write(shadow_result);
+        Ok(())
+    }
+
+    /// Record that a key image was spent in a block which is not yet final,
+    /// in both stores, as `add_record` does
+    pub fn add_provisional_record(
+        &mut self,
+        key_image: &KeyImage,
+        data: &KeyImageData,
+    ) -> Result<(), AddRecordsError> {
+        self.primary.add_provisional_record(key_image, data)?;
+        let shadow_result = self.shadow.add_provisional_record(key_image, data);
+        self.count_shadow_write(shadow_result);
+        Ok(())
+    }
+
+    /// Mark every provisional record of a block as final, in both stores,
+    /// returning the number finalized in the primary
+    pub fn finalize_block(&mut self, block_index: BlockIndex) -> Result<usize, AddRecordsError> {
+        let num_finalized = self.primary.finalize_block(block_index)?;
+        let shadow_result = self.shadow.finalize_block(block_index);
+        self.count_shadow_write(shadow_result);
+        Ok(num_finalized)
+    }
+
+    /// Set the timestamp of every record in a block, in both stores,
+    /// returning the number updated in the primary
+    pub fn backfill_timestamps(
+        &mut self,
+        block_index: BlockIndex,
+        timestamp: u64,
+    ) -> Result<usize, AddRecordsError> {
+        let num_updated = self.primary.backfill_timestamps(block_index, timestamp)?;
+        let shadow_result = self.shadow.backfill_timestamps(block_index, timestamp);
+        self.count_shadow_write(shadow_result);
+        Ok(num_updated)
+    }
+
+    /// Move the record for a key image to a different block, in both stores,
+    /// returning whether it was present in the primary
+    pub fn update_block_index(
+        &mut self,
+        key_image: &KeyImage,
+        new_block_index: BlockIndex,
+    ) -> Result<bool, AddRecordsError> {
+        let present = self
+            .primary
+            .update_block_index(key_image, new_block_index)?;
+        let shadow_result = self.shadow.update_block_index(key_image, new_block_index);
+        self.count_shadow_write(shadow_result);
+        Ok(present)
+    }
+
+    /// Remove the record for a key image from both stores, returning whether
+    /// it was present in the primary
+    pub fn remove_record(&mut self, key_image: &KeyImage) -> Result<bool, AddRecordsError> {
+        let present = self.primary.remove_record(key_image)?;
+        let shadow_result = self.shadow.remove_record(key_image);
+        self.count_shadow_write(shadow_result);
+        Ok(present)
+    }
+
+    /// Look up a key image and remove its record, in both stores, returning
+    /// the primary's answer.
+    ///
+    /// The answers are compared as in `find_record`.
+    pub fn take_record(
+        &mut self,
+        key_image: &KeyImage,
+    ) -> Result<(KeyImageData, u32), AddRecordsError> {
+        let primary = self.primary.take_record(key_image)?;
+        let shadow_result = self.shadow.take_record(key_image);
+        if let Ok(shadow) = shadow_result.as_ref() {
+            self.compare(&primary, shadow);
+        }
+        self.count_shadow_write(shadow_result);
+        Ok(primary)
+    }
+
+    /// Look up a key image in both stores, returning the primary's answer.
+    ///
+    /// The comparison and the mismatch counter update are branchless, so
+    /// this has the same access pattern whether or not the stores agree.
+    pub fn find_record(&mut self, key_image: &KeyImage) -> (KeyImageData, u32) {
+        let primary = self.primary.find_record_unchecked(key_image);
+        let shadow = self.shadow.find_record_unchecked(key_image);
+        self.compare(&primary, &shadow);
+        primary
+    }
+
+    /// Find the most recent spend among a set of key images, from the primary.
+    ///
+    /// Each key image is compared between the stores as in `find_record`.
+    pub fn latest_spend(&mut self, key_images: &[KeyImage]) -> Option<(BlockIndex, u64)> {
+        for key_image in key_images {
+            self.find_record(key_image);
+        }
+        self.primary.latest_spend(key_images)
+    }
+
+    /// Count a read, and whether the stores' answers to it disagree,
+    /// branchlessly
+    fn compare(&mut self, primary: &(KeyImageData, u32), shadow: &(KeyImageData, u32)) {
+        let agree = primary.0.ct_eq(&shadow.0) & primary.1.ct_eq(&shadow.1);
+        self.metrics.reads += 1;
+        self.metrics.mismatches += (!agree).unwrap_u8() as u64;
+    }
+
+    /// Count a write which succeeded on the primary, if it failed on the
+    /// shadow
+    fn count_shadow_write<T>(&mut self, shadow_result: Result<T, AddRecordsError>) {
+        if shadow_result.is_err() {
+            self.metrics.shadow_write_errors += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_image_store::KeyImageStoreBuilder;
+    use fog_ledger_enclave_api::KeyImageResultCode;
+    use mc_common::logger::{test_with_logger, Logger};
+    use mc_oblivious_traits::HeapORAMStorageCreator;
+
+    type TestShadowStore = ShadowStore<HeapORAMStorageCreator, HeapORAMStorageCreator>;
+
+    fn data(block_index: BlockIndex, timestamp: u64) -> KeyImageData {
+        KeyImageData {
+            block_index,
+            timestamp,
+        }
+    }
+
+    // Test that matching stores record no mismatches, and writes reach both
+    #[test_with_logger]
+    fn test_shadow_agrees(logger: Logger) {
+        let mut store = TestShadowStore::new(
+            KeyImageStore::new(512, logger.clone()),
+            KeyImageStore::new(512, logger),
+        );
+
+        store.add_record(&KeyImage::from(1), &data(3, 30)).unwrap();
+        store.add_record(&KeyImage::from(2), &data(4, 40)).unwrap();
+
+        assert_eq!(store.find_record(&KeyImage::from(1)).0, data(3, 30));
+        assert_eq!(store.find_record(&KeyImage::from(2)).0, data(4, 40));
+        store.find_record(&KeyImage::from(3));
+
+        assert_eq!(store.metrics().reads, 3);
+        assert_eq!(store.metrics().mismatches, 0);
+        assert_eq!(store.shadow().len(), 2);
+    }
+
+    // Test that a shadow which has diverged from the primary is detected, and
+    // that the primary's answer is still the one served
+    #[test_with_logger]
+    fn test_shadow_mismatch(logger: Logger) {
+        let mut primary = KeyImageStore::new(512, logger.clone());
+        let mut shadow = KeyImageStore::new(512, logger);
+
+        // Diverge the shadow: it has a different block for key 1, and is
+        // missing key 2 entirely.
+        primary
+            .add_record(&KeyImage::from(1), &data(5, 50))
+            .unwrap();
+        shadow.add_record(&KeyImage::from(1), &data(6, 60)).unwrap();
+        primary
+            .add_record(&KeyImage::from(2), &data(7, 70))
+            .unwrap();
+
+        let mut store = TestShadowStore::new(primary, shadow);
+        store.add_record(&KeyImage::from(3), &data(8, 80)).unwrap();
+
+        assert_eq!(store.find_record(&KeyImage::from(1)).0, data(5, 50));
+        assert_eq!(store.metrics().mismatches, 1);
+
+        assert_eq!(store.find_record(&KeyImage::from(2)).0, data(7, 70));
+        assert_eq!(store.metrics().mismatches, 2);
+
+        assert_eq!(store.find_record(&KeyImage::from(3)).0, data(8, 80));
+        assert_eq!(store.metrics().mismatches, 2);
+        assert_eq!(store.metrics().reads, 3);
+    }
+
+    // Test that every kind of ingest operation reaches the shadow, so that a
+    // mixed workload leaves the two stores agreeing on every key image
+    #[test_with_logger]
+    fn test_shadow_forwards_ingest(logger: Logger) {
+        let mut store = TestShadowStore::new(
+            KeyImageStoreBuilder::new(512, logger.clone())
+                .maintenance_index()
+                .build(),
+            KeyImageStoreBuilder::new(512, logger)
+                .maintenance_index()
+                .build(),
+        );
+
+        for idx in 1..=6u64 {
+            store
+                .add_record(&KeyImage::from(idx), &data(idx, 0))
+                .unwrap();
+        }
+        store
+            .add_provisional_record(&KeyImage::from(7), &data(7, 70))
+            .unwrap();
+        store
+            .add_provisional_record(&KeyImage::from(8), &data(8, 80))
+            .unwrap();
+        assert_eq!(store.backfill_timestamps(2, 25).unwrap(), 1);
+        assert_eq!(store.finalize_block(7).unwrap(), 1);
+        assert!(store.update_block_index(&KeyImage::from(3), 9).unwrap());
+        assert!(store.remove_record(&KeyImage::from(4)).unwrap());
+        assert_eq!(store.take_record(&KeyImage::from(5)).unwrap().0, data(5, 0));
+
+        assert_eq!(store.metrics().shadow_write_errors, 0);
+        assert_eq!(store.shadow().len(), store.primary().len());
+        for idx in 1..=9u64 {
+            store.find_record(&KeyImage::from(idx));
+        }
+        assert_eq!(store.metrics().reads, 10);
+        assert_eq!(store.metrics().mismatches, 0);
+
+        assert_eq!(store.find_record(&KeyImage::from(2)).0, data(2, 25));
+        assert_eq!(store.find_record(&KeyImage::from(3)).0, data(9, 0));
+        assert_eq!(
+            store.find_record(&KeyImage::from(8)).1,
+            KeyImageResultCode::ProvisionallySpent as u32
+        );
+        for idx in [4u64, 5].iter() {
+            assert_eq!(
+                store.find_record(&KeyImage::from(*idx)).1,
+                KeyImageResultCode::NotSpent as u32
+            );
+        }
+        assert_eq!(store.metrics().mismatches, 0);
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! A KeyImageStore shared between threads, which keeps answering lookups
+//! while it grows.
+//!
+//! Every operation locks the store. `grow` holds the lock only to read the
+//! records out of the store and, later, to commit the grow: the larger map
+//! is filled in between with the lock released, so lookups made meanwhile
+//! are answered by the old map. The commit swaps the new map in under the
+//! lock, so each lookup is answered entirely by the old map or entirely by
+//! the new one, which by then holds the same records.
+
+use crate::{
+    error::KeyImageStoreError,
+    key_image_store::{KeyImageData, KeyImageStore, StorageDataSize, StorageMetaSize},
+};
+use aligned_cmov::subtle::CtOption;
+use mc_oblivious_traits::ORAMStorageCreator;
+use mc_sgx_compat::sync::Mutex;
+use mc_transaction_core::ring_signature::KeyImage;
+
+/// A KeyImageStore behind a lock, for use from several threads
+pub struct SharedStore<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> {
+    /// The store
+    store: Mutex<KeyImageStore<OSC>>,
+}
+
+impl<OSC: ORAMStorageCreator<StorageDataSize, StorageMetaSize>> SharedStore<OSC> {
+    /// Share a store
+    pub fn new(store: KeyImageStore<OSC>) -> Self {
+        Self {
+            store: Mutex::new(store),
+        }
+    }
+
+    /// Take the store back
+    pub fn into_inner(self) -> Result<KeyImageStore<OSC>, KeyImageStoreError> {
+        Ok(self.store.into_inner()?)
+    }
+
+    /// Look up a key image, as `KeyImageStore::find` does
+    pub fn find(
+        &self,
+        key_image: &KeyImage,
+    ) -> Result<(CtOption<KeyImageData>, u32), KeyImageStoreError> {
+        Ok(self.store.lock()?.find(key_image))
+    }
+
+    /// Record that a key image was spent, as `KeyImageStore::add_record` does
+    pub fn add_record(
+        &self,
+        key_image: &KeyImage,
+        data: &KeyImageData,
+    ) -> Result<(), KeyImageStoreError> {
+        Ok(self.store.lock()?.add_record(key_image, data)?)
+    }
+
+    /// Run any other operation on the store, holding the lock throughout
+    pub fn with_store<T>(
+        &self,
+        f: impl FnOnce(&mut KeyImageStore<OSC>) -> T,
+    ) -> Result<T, KeyImageStoreError> {
+        Ok(f(&mut self.store.lock()?))
+    }
+
+    /// Move the store into a new oblivious map with the given capacity,
+    /// serving other threads from the old map while the new one is filled.
+    ///
+    /// Fails as `KeyImageStore::prepare_grow` and `commit_grow` do, leaving
+    /// the store unchanged.
+    pub fn grow(&self, desired_capacity: u64) -> Result<(), KeyImageStoreError> {
+        let mut pending = self.store.lock()?.start_grow(desired_capacity)?;
+        if let Err(err) = pending.fill() {
+            self.store.lock()?.abandon_grow(pending);
+            return Err(err);
+        }
+        self.store.lock()?.commit_grow(pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::key_image_store::KeyImageStoreBuilder;
+    use alloc::{sync::Arc, vec::Vec};
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use fog_ledger_enclave_api::KeyImageResultCode;
+    use mc_common::logger::{test_with_logger, Logger};
+    use mc_oblivious_traits::HeapORAMStorageCreator;
+    use std::thread;
+
+    fn data(block_index: u64) -> KeyImageData {
+        KeyImageData {
+            block_index,
+            timestamp: block_index * 10,
+        }
+    }
+
+    // Test that lookups from other threads while a grow runs always see every
+    // record, and that writes made meanwhile survive the swap
+    #[test_with_logger]
+    fn test_reads_during_grow(logger: Logger) {
+        let mut store = KeyImageStoreBuilder::new(512, logger)
+            .maintenance_index()
+            .build::<HeapORAMStorageCreator>();
+        for idx in 1..=200u64 {
+            store.add_record(&KeyImage::from(idx), &data(idx)).unwrap();
+        }
+        let old_capacity = store.capacity();
+        let shared = Arc::new(SharedStore::new(store));
+        let growing = Arc::new(AtomicBool::new(true));
+
+        let readers: Vec<_> = (0..4u64)
+            .map(|reader| {
+                let shared = shared.clone();
+                let growing = growing.clone();
+                thread::spawn(move || {
+                    let mut num_reads = 0u64;
+                    while growing.load(Ordering::SeqCst) || num_reads < 200 {
+                        let idx = (reader * 50 + num_reads) % 200 + 1;
+                        let (found, result_code) = shared.find(&KeyImage::from(idx)).unwrap();
+                        assert_eq!(result_code, KeyImageResultCode::Spent as u32);
+                        assert_eq!(Option::<KeyImageData>::from(found), Some(data(idx)));
+                        num_reads += 1;
+                    }
+                })
+            })
+            .collect();
+        let writer = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for idx in 201..=220u64 {
+                    shared.add_record(&KeyImage::from(idx), &data(idx)).unwrap();
+                }
+            })
+        };
+
+        shared.grow(8192).unwrap();
+        growing.store(false, Ordering::SeqCst);
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        let mut store = Arc::try_unwrap(shared)
+            .ok()
+            .expect("every thread is joined")
+            .into_inner()
+            .unwrap();
+        assert!(store.capacity() > old_capacity);
+        assert_eq!(store.len(), 220);
+        for idx in 1..=220u64 {
+            assert_eq!(
+                store.find_record_unchecked(&KeyImage::from(idx)),
+                (data(idx), KeyImageResultCode::Spent as u32)
+            );
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! A group of key image stores which are queried as one, e.g. the shards or
+//! generations of a larger store.
+
+use crate::{
+    ct::{ct_select_u32, ct_select_u64},
+    key_image_store::KeyImageData,
+    lookup::KeyImageLookup,
+};
+use aligned_cmov::subtle::{Choice, ConstantTimeEq};
+use alloc::vec::Vec;
+use fog_ledger_enclave_api::KeyImageResultCode;
+use mc_transaction_core::ring_signature::KeyImage;
+
+/// Queries several stores as if they were one.
+///
+/// Every query is sent to every member of the group, and the answer is
+/// selected branchlessly, so the access pattern does not reveal which member
+/// held the key image, or whether any did.
+pub struct StoreGroup<'a> {
+    /// The member stores, in priority order
+    stores: Vec<&'a mut dyn KeyImageLookup>,
+}
+
+impl<'a> StoreGroup<'a> {
+    /// Group some stores. When more than one holds a key image, the first
+    /// one's record is returned.
+    pub fn new(stores: Vec<&'a mut dyn KeyImageLookup>) -> Self {
+        Self { stores }
+    }
+
+    /// The number of stores in the group
+    pub fn len(&self) -> usize {
+        self.stores.len()
+    }
+
+    /// Whether the group has no stores
+    pub fn is_empty(&self) -> bool {
+        self.stores.is_empty()
+    }
+
+    /// Look up a key image in every store, returning the first hit.
+    ///
+    /// If no store holds the key image, the result is NotSpent, unless a
+    /// store reported an error, in which case it is KeyImageError.
+    pub fn find_record(&mut self, key_image: &KeyImage) -> (KeyImageData, u32) {
+        let mut result = KeyImageData::default();
+        let mut result_code = KeyImageResultCode::NotSpent as u32;
+        let mut any_found = Choice::from(0);
+        let mut any_error = Choice::from(0);
+
+        for store in self.stores.iter_mut() {
+            let (data, code) = store.find_record(key_image);
+            let found = code.ct_eq(&(KeyImageResultCode::Spent as u32))
+                | code.ct_eq(&(KeyImageResultCode::ProvisionallySpent as u32));
+            let take = found & !any_found;
+            result.block_index = ct_select_u64(take, data.block_index, result.block_index);
+            result.timestamp = ct_select_u64(take, data.timestamp, result.timestamp);
+            result_code = ct_select_u32(take, code, result_code);
+            any_found |= found;
+            any_error |= code.ct_eq(&(KeyImageResultCode::KeyImageError as u32));
+        }
+
+        result_code = ct_select_u32(
+            !any_found & any_error,
+            KeyImageResultCode::KeyImageError as u32,
+            result_code,
+        );
+        (result, result_code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_image_store::KeyImageStore;
+    use alloc::vec;
+    use mc_common::logger::{test_with_logger, Logger};
+    use mc_oblivious_traits::HeapORAMStorageCreator;
+
+    fn data(block_index: u64) -> KeyImageData {
+        KeyImageData {
+            block_index,
+            timestamp: block_index * 10,
+        }
+    }
+
+    // Test that a key present in any member store is found via the group,
+    // with the first member taking priority
+    #[test_with_logger]
+    fn test_store_group(logger: Logger) {
+        let mut first = KeyImageStore::<HeapORAMStorageCreator>::new(512, logger.clone());
+        let mut second = KeyImageStore::<HeapORAMStorageCreator>::new(512, logger.clone());
+        let mut third = KeyImageStore::<HeapORAMStorageCreator>::new(512, logger);
+
+        first.add_record(&KeyImage::from(1), &data(1)).unwrap();
+        second.add_record(&KeyImage::from(2), &data(2)).unwrap();
+        third.add_record(&KeyImage::from(3), &data(3)).unwrap();
+        second.add_record(&KeyImage::from(4), &data(4)).unwrap();
+        third.add_record(&KeyImage::from(4), &data(40)).unwrap();
+
+        let mut group = StoreGroup::new(vec![
+            &mut first as &mut dyn KeyImageLookup,
+            &mut second,
+            &mut third,
+        ]);
+        assert_eq!(group.len(), 3);
+
+        let spent = KeyImageResultCode::Spent as u32;
+        for idx in 1..=3u64 {
+            assert_eq!(group.find_record(&KeyImage::from(idx)), (data(idx), spent));
+        }
+        assert_eq!(group.find_record(&KeyImage::from(4)), (data(4), spent));
+        assert_eq!(
+            group.find_record(&KeyImage::from(5)),
+            (KeyImageData::default(), KeyImageResultCode::NotSpent as u32)
+        );
+        drop(group);
+
+        // Every query went to every member
+        assert_eq!(first.metrics().queries, 5);
+        assert_eq!(third.metrics().queries, 5);
+    }
+}
@@ -0,0 +1,390 @@
+// Copyright (c) 2018-2021 The MobileCoin Foundation
+
+//! The encoding of KeyImageData into the value bytes of the oblivious map.
+//!
+//! The block index is stored little-endian at the start of the value, in a
+//! field whose width is configurable, followed by the little-endian
+//! timestamp, and then a flag byte which is 1 if the record is provisional,
+//! i.e. its block is not yet final. ValueSize reserves enough bytes for the
+//! widest supported block index, so a store can move to a wider block index
+//! without changing the size of its ORAM blocks.
+//!
+//! While a store migrates between layouts, the last byte of each value is 1
+//! if it has been rewritten in the new layout. No layout reaches that byte,
+//! so values written outside a migration always have it clear.
+//!
+//! There is a single layout, which only varies in the width of the block
+//! index; no cargo feature adds or removes a field. REQUIRED_VALUE_BYTES adds
+//! up the fields of the widest layout, and the build fails if they do not fit
+//! in ValueSize, so a field added to KeyImageData cannot silently run into the
+//! migration mark. New fields must be counted there, and a field which is
+//! only present under a feature must be counted under that feature.
+
+use crate::{ct::ct_copy_bytes, key_image_store::KeyImageData};
+use aligned_cmov::{
+    subtle::{Choice, ConstantTimeEq},
+    typenum::{Unsigned, U32},
+    A8Bytes,
+};
+use core::convert::TryInto;
+use mc_transaction_core::BlockIndex;
+
+/// The size of the value recorded in the oblivious map for each key image
+pub type ValueSize = U32;
+
+/// The offset of the byte which marks a value as migrated to a new layout
+const MIGRATED_OFFSET: usize = ValueSize::USIZE - 1;
+
+/// The number of value bytes used by the timestamp
+const TIMESTAMP_BYTES: usize = 8;
+
+/// The number of value bytes used by the provisional flag
+const PROVISIONAL_FLAG_BYTES: usize = 1;
+
+/// The number of value bytes used by the migration mark
+const MIGRATED_MARK_BYTES: usize = 1;
+
+/// The number of value bytes needed by the widest layout, with the 128-bit
+/// block index.
+///
+/// The build-time check below accepts ValueSize:
+///
+/// ```
+/// use aligned_cmov::typenum::Unsigned;
+/// use fog_ledger_enclave_impl::{ValueSize, REQUIRED_VALUE_BYTES};
+///
+/// const _: [(); 0 - (REQUIRED_VALUE_BYTES > ValueSize::USIZE) as usize] = [];
+/// ```
+///
+/// and fails the build for a value too small for the layout:
+///
+/// ```compile_fail
+/// use aligned_cmov::typenum::{Unsigned, U16};
+/// use fog_ledger_enclave_impl::REQUIRED_VALUE_BYTES;
+///
+/// const _: [(); 0 - (REQUIRED_VALUE_BYTES > U16::USIZE) as usize] = [];
+/// ```
+pub const REQUIRED_VALUE_BYTES: usize = BlockIndexWidth::U128.num_bytes()
+    + TIMESTAMP_BYTES
+    + PROVISIONAL_FLAG_BYTES
+    + MIGRATED_MARK_BYTES;
+
+// Fails to build, with an array length overflow, if the widest layout does
+// not fit in ValueSize
+const _: [(); 0 - (REQUIRED_VALUE_BYTES > ValueSize::USIZE) as usize] = [];
+
+/// A block index which may be wider than the BlockIndex used by the chain
+/// today
+pub type WideBlockIndex = u128;
+
+/// The width of the block index field in the value layout
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BlockIndexWidth {
+    /// 8-byte block index, enough for any u64 block height
+    U64,
+    /// 16-byte block index, for chains which may outgrow u64 block heights
+    U128,
+}
+
+impl BlockIndexWidth {
+    /// The number of value bytes used by a block index of this width
+    pub const fn num_bytes(self) -> usize {
+        match self {
+            BlockIndexWidth::U64 => 8,
+            BlockIndexWidth::U128 => 16,
+        }
+    }
+}
+
+impl Default for BlockIndexWidth {
+    fn default() -> Self {
+        BlockIndexWidth::U64
+    }
+}
+
+/// Translates between KeyImageData and the value bytes of the oblivious map
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ValueCodec {
+    /// The width of the block index field
+    block_index_width: BlockIndexWidth,
+}
+
+impl ValueCodec {
+    /// Make a codec which uses the given block index width
+    pub fn new(block_index_width: BlockIndexWidth) -> Self {
+        Self { block_index_width }
+    }
+
+    /// The width of the block index field
+    pub fn block_index_width(&self) -> BlockIndexWidth {
+        self.block_index_width
+    }
+
+    /// Encode KeyImageData as value bytes.
+    ///
+    /// Any BlockIndex fits in every supported width, so this cannot fail.
+    pub fn encode(&self, data: &KeyImageData) -> A8Bytes<ValueSize> {
+        self.encode_wide(data.block_index as WideBlockIndex, data.timestamp)
+            .expect("every supported block index width holds a BlockIndex")
+    }
+
+    /// Decode KeyImageData from value bytes.
+    ///
+    /// Values written by `encode` always decode exactly. A wide block index
+    /// which does not fit in a BlockIndex is truncated, use `decode_wide` to
+    /// read such values.
+    pub fn decode(&self, value: &A8Bytes<ValueSize>) -> KeyImageData {
+        let (block_index, timestamp) = self.decode_wide(value);
+        KeyImageData {
+            block_index: block_index as u64,
+            timestamp,
+        }
+    }
+
+    /// Encode a block index of up to 128 bits and a timestamp as value bytes.
+    ///
+    /// Returns None if the block index does not fit in the configured width.
+    pub fn encode_wide(
+        &self,
+        block_index: WideBlockIndex,
+        timestamp: u64,
+    ) -> Option<A8Bytes<ValueSize>> {
+        let width = self.block_index_width.num_bytes();
+        let block_index_bytes = block_index.to_le_bytes();
+        if block_index_bytes[width..].iter().any(|byte| *byte != 0) {
+            return None;
+        }
+
+        let mut value = A8Bytes::<ValueSize>::default();
+        value[0..width].copy_from_slice(&block_index_bytes[0..width]);
+        value[width..width + 8].copy_from_slice(&timestamp.to_le_bytes());
+        Some(value)
+    }
+
+    /// Overwrite the block index of value bytes, leaving the timestamp and
+    /// provisional flag as they are.
+    ///
+    /// This writes the same bytes whatever the value holds, so it is
+    /// constant-time.
+    pub fn set_block_index(&self, value: &mut A8Bytes<ValueSize>, block_index: BlockIndex) {
+        let width = self.block_index_width.num_bytes();
+        let block_index_bytes = (block_index as WideBlockIndex).to_le_bytes();
+        value[0..width].copy_from_slice(&block_index_bytes[0..width]);
+    }
+
+    /// Overwrite the timestamp of value bytes, leaving the block index and
+    /// provisional flag as they are
+    pub fn set_timestamp(&self, value: &mut A8Bytes<ValueSize>, timestamp: u64) {
+        let width = self.block_index_width.num_bytes();
+        value[width..width + 8].copy_from_slice(&timestamp.to_le_bytes());
+    }
+
+    /// Mark value bytes as provisional, or as final
+    pub fn set_provisional(&self, value: &mut A8Bytes<ValueSize>, provisional: bool) {
+        value[self.provisional_offset()] = provisional as u8;
+    }
+
+    /// Whether value bytes are marked provisional.
+    ///
+    /// This is constant-time, so it may be used on the query path.
+    pub fn is_provisional(&self, value: &A8Bytes<ValueSize>) -> Choice {
+        value[self.provisional_offset()].ct_eq(&1)
+    }
+
+    /// Mark value bytes as migrated to this codec's layout, or clear the mark
+    pub fn set_migrated(&self, value: &mut A8Bytes<ValueSize>, migrated: bool) {
+        value[MIGRATED_OFFSET] = migrated as u8;
+    }
+
+    /// Rewrite value bytes in `from`'s layout into this codec's layout, and
+    /// mark them migrated, unless they are marked migrated already.
+    ///
+    /// Only fixed byte ranges are read and written, and the result is
+    /// selected branchlessly, so this is constant-time and may be used on the
+    /// query path. A block index too wide for this codec's layout is
+    /// truncated, so migrations should only widen the block index.
+    pub fn migrate(&self, from: &ValueCodec, value: &mut A8Bytes<ValueSize>) {
+        let (block_index, timestamp) = from.decode_wide(value);
+        let width = self.block_index_width.num_bytes();
+        let mut migrated = A8Bytes::<ValueSize>::default();
+        migrated[0..width].copy_from_slice(&block_index.to_le_bytes()[0..width]);
+        migrated[width..width + 8].copy_from_slice(&timestamp.to_le_bytes());
+        migrated[self.provisional_offset()] = value[from.provisional_offset()];
+        self.set_migrated(&mut migrated, true);
+
+        let already_migrated = value[MIGRATED_OFFSET].ct_eq(&1);
+        ct_copy_bytes(!already_migrated, value, &migrated);
+    }
+
+    /// The offset of the provisional flag, which follows the timestamp
+    fn provisional_offset(&self) -> usize {
+        self.block_index_width.num_bytes() + TIMESTAMP_BYTES
+    }
+
+    /// Decode a block index of up to 128 bits and a timestamp from value
+    /// bytes
+    pub fn decode_wide(&self, value: &A8Bytes<ValueSize>) -> (WideBlockIndex, u64) {
+        let width = self.block_index_width.num_bytes();
+        let mut block_index_bytes = [0u8; 16];
+        block_index_bytes[0..width].copy_from_slice(&value[0..width]);
+        (
+            WideBlockIndex::from_le_bytes(block_index_bytes),
+            u64::from_le_bytes(value[width..width + 8].try_into().unwrap()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that a u64 block index round-trips under the default width
+    #[test]
+    fn test_u64_round_trip() {
+        let codec = ValueCodec::default();
+        assert_eq!(codec.block_index_width(), BlockIndexWidth::U64);
+
+        for block_index in &[0u64, 1, 0x0102_0304_0506_0708, u64::MAX] {
+            let data = KeyImageData {
+                block_index: *block_index,
+                timestamp: 1_600_000_000,
+            };
+            assert_eq!(codec.decode(&codec.encode(&data)), data);
+        }
+
+        // The default layout only uses the first 16 bytes
+        let value = codec.encode(&KeyImageData {
+            block_index: u64::MAX,
+            timestamp: u64::MAX,
+        });
+        assert!(value[16..].iter().all(|byte| *byte == 0));
+
+        // A block index wider than u64 cannot be encoded at this width
+        assert!(codec
+            .encode_wide(u64::MAX as WideBlockIndex + 1, 5)
+            .is_none());
+    }
+
+    // Test that a block index wider than u64 round-trips under the 128-bit
+    // width, and that BlockIndex values still do too
+    #[test]
+    fn test_u128_round_trip() {
+        let codec = ValueCodec::new(BlockIndexWidth::U128);
+
+        for block_index in &[
+            0 as WideBlockIndex,
+            u64::MAX as WideBlockIndex,
+            u64::MAX as WideBlockIndex + 1,
+            0x0102_0304_0506_0708_090a_0b0c_0d0e_0f10,
+            WideBlockIndex::MAX,
+        ] {
+            let value = codec.encode_wide(*block_index, 77).unwrap();
+            assert_eq!(codec.decode_wide(&value), (*block_index, 77));
+        }
+
+        let data = KeyImageData {
+            block_index: 12345,
+            timestamp: 1_600_000_000,
+        };
+        assert_eq!(codec.decode(&codec.encode(&data)), data);
+    }
+
+    // Test that values are final unless marked provisional, at either width,
+    // and that the flag does not disturb the rest of the value
+    #[test]
+    fn test_provisional_flag() {
+        let data = KeyImageData {
+            block_index: u64::MAX,
+            timestamp: u64::MAX,
+        };
+        for width in &[BlockIndexWidth::U64, BlockIndexWidth::U128] {
+            let codec = ValueCodec::new(*width);
+            let mut value = codec.encode(&data);
+            assert!(!bool::from(codec.is_provisional(&value)));
+
+            codec.set_provisional(&mut value, true);
+            assert!(bool::from(codec.is_provisional(&value)));
+            assert_eq!(codec.decode(&value), data);
+
+            codec.set_provisional(&mut value, false);
+            assert!(!bool::from(codec.is_provisional(&value)));
+            assert_eq!(value[..], codec.encode(&data)[..]);
+
+            // Changing the block index or the timestamp keeps the other
+            // fields and the flag
+            codec.set_provisional(&mut value, true);
+            codec.set_block_index(&mut value, 7);
+            assert!(bool::from(codec.is_provisional(&value)));
+            assert_eq!(
+                codec.decode(&value),
+                KeyImageData {
+                    block_index: 7,
+                    timestamp: u64::MAX,
+                }
+            );
+            codec.set_timestamp(&mut value, 9);
+            assert!(bool::from(codec.is_provisional(&value)));
+            assert_eq!(
+                codec.decode(&value),
+                KeyImageData {
+                    block_index: 7,
+                    timestamp: 9,
+                }
+            );
+        }
+    }
+
+    // Test that migrating a value rewrites it in the new layout, keeping every
+    // field and the provisional flag, and leaves migrated values alone
+    #[test]
+    fn test_migrate() {
+        let narrow = ValueCodec::new(BlockIndexWidth::U64);
+        let wide = ValueCodec::new(BlockIndexWidth::U128);
+        let data = KeyImageData {
+            block_index: 3,
+            timestamp: 4,
+        };
+
+        let mut value = narrow.encode(&data);
+        narrow.set_provisional(&mut value, true);
+        wide.migrate(&narrow, &mut value);
+        assert_eq!(wide.decode(&value), data);
+        assert!(bool::from(wide.is_provisional(&value)));
+        assert_eq!(value[MIGRATED_OFFSET], 1);
+
+        // Migrating again is a no-op
+        let migrated = value.clone();
+        wide.migrate(&narrow, &mut value);
+        assert_eq!(value, migrated);
+
+        wide.set_migrated(&mut value, false);
+        wide.set_provisional(&mut value, false);
+        assert_eq!(value, wide.encode(&data));
+    }
+
+    // Test that REQUIRED_VALUE_BYTES covers every field of the widest layout,
+    // so that the build-time check guards the real layout
+    #[test]
+    fn test_required_value_bytes() {
+        let wide = ValueCodec::new(BlockIndexWidth::U128);
+        assert_eq!(
+            wide.provisional_offset() + PROVISIONAL_FLAG_BYTES + MIGRATED_MARK_BYTES,
+            REQUIRED_VALUE_BYTES
+        );
+        assert!(wide.provisional_offset() < MIGRATED_OFFSET);
+    }
+
+    // Test that the two widths really do lay the timestamp out differently
+    #[test]
+    fn test_layouts_differ() {
+        let data = KeyImageData {
+            block_index: 3,
+            timestamp: 4,
+        };
+        let narrow = ValueCodec::new(BlockIndexWidth::U64).encode(&data);
+        let wide = ValueCodec::new(BlockIndexWidth::U128).encode(&data);
+        assert_eq!(narrow[8], 4);
+        assert_eq!(wide[8], 0);
+        assert_eq!(wide[16], 4);
+    }
+}